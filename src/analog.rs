@@ -0,0 +1,156 @@
+// Copyright 2021, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Types related to the `vrpn_Analog` device class
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult,
+    },
+    data_types::{
+        message::TypedMessageBody, name_types::StaticMessageTypeName, MessageTypeIdentifier,
+    },
+};
+use bytes::{Buf, BufMut};
+
+/// Maximum number of channels in a report, matching `vrpn_CHANNEL_MAX` in mainline.
+pub const MAX_CHANNELS: usize = 128;
+
+/// Readings for a number of analog channels.
+///
+/// The number and meaning of the channels depends on the sender.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AnalogReport {
+    pub channels: Vec<f64>,
+}
+
+impl AnalogReport {
+    pub fn new(channels: Vec<f64>) -> AnalogReport {
+        AnalogReport { channels }
+    }
+}
+
+impl TypedMessageBody for AnalogReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Analog Channel"));
+}
+
+impl BufferSize for AnalogReport {
+    fn buffer_size(&self) -> usize {
+        // The count is transmitted as a double too.
+        (self.channels.len() + 1) * f64::constant_buffer_size()
+    }
+}
+
+impl BufferTo for AnalogReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        let count = check_channel_count(self.channels.len())?;
+        check_buffer_remaining(buf, self.buffer_size())?;
+        (count as f64).buffer_to(buf)?;
+        for channel in &self.channels {
+            channel.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for AnalogReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let count = f64::unbuffer_from(buf)?;
+        if !(count >= 0.0 && count.fract() == 0.0) {
+            return Err(BufferUnbufferError::ParseError {
+                parsing_kind: "analog channel count".to_string(),
+                s: format!("{} is not a valid count", count),
+            });
+        }
+        let count = if count > MAX_CHANNELS as f64 {
+            // Don't try converting absurdly large values to usize.
+            return Err(BufferUnbufferError::CountExceedsMaximum {
+                count: count.min(usize::MAX as f64) as usize,
+                max: MAX_CHANNELS,
+            });
+        } else {
+            count as usize
+        };
+        check_unbuffer_remaining(buf, count * f64::constant_buffer_size())?;
+        let channels = (0..count)
+            .map(|_| f64::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<_>>>()?;
+        Ok(AnalogReport { channels })
+    }
+}
+
+fn check_channel_count(count: usize) -> Result<usize, BufferUnbufferError> {
+    if count > MAX_CHANNELS {
+        Err(BufferUnbufferError::CountExceedsMaximum {
+            count,
+            max: MAX_CHANNELS,
+        })
+    } else {
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BytesMutExtras;
+    use bytes::{Bytes, BytesMut};
+    use proptest::prelude::*;
+
+    #[test]
+    fn fixture() {
+        let mut buf = Bytes::from_static(&hex!(
+            "40 00 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 bf e0 00 00 00 00 00 00"
+        ));
+        let report = AnalogReport::unbuffer_from(&mut buf).unwrap();
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(report.channels, vec![1.0, -0.5]);
+    }
+
+    #[test]
+    fn too_many_channels() {
+        let report = AnalogReport::new(vec![0.0; MAX_CHANNELS + 1]);
+        assert_eq!(
+            BytesMut::allocate_and_buffer(report).unwrap_err(),
+            BufferUnbufferError::CountExceedsMaximum {
+                count: MAX_CHANNELS + 1,
+                max: MAX_CHANNELS
+            }
+        );
+
+        // 129.0
+        let mut buf = Bytes::from_static(&hex!("40 60 20 00 00 00 00 00"));
+        assert_eq!(
+            AnalogReport::unbuffer_from(&mut buf).unwrap_err(),
+            BufferUnbufferError::CountExceedsMaximum {
+                count: MAX_CHANNELS + 1,
+                max: MAX_CHANNELS
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_count() {
+        // -1.0
+        let mut buf = Bytes::from_static(&hex!("bf f0 00 00 00 00 00 00"));
+        assert!(AnalogReport::unbuffer_from(&mut buf).is_err());
+        // 1.5
+        let mut buf = Bytes::from_static(&hex!("3f f8 00 00 00 00 00 00"));
+        assert!(AnalogReport::unbuffer_from(&mut buf).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(channels in prop::collection::vec(-1.0e6f64..1.0e6, 0..=MAX_CHANNELS)) {
+            let report = AnalogReport::new(channels);
+            let mut buf = BytesMut::allocate_and_buffer(report.clone()).unwrap().freeze();
+            prop_assert_eq!(buf.len(), report.buffer_size());
+            let decoded = AnalogReport::unbuffer_from(&mut buf).unwrap();
+            prop_assert_eq!(buf.remaining(), 0);
+            prop_assert_eq!(decoded, report);
+        }
+    }
+}
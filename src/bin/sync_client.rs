@@ -9,16 +9,16 @@
 //! However, this doesn't use any Connection structs - just an endpoint and a type dispatcher.
 //! In normal usage, this would be bundled into a Connection.
 
-extern crate bytes;
 extern crate vrpn;
 
-use bytes::Bytes;
-use std::net::{SocketAddr, TcpStream};
+use std::{
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
 use vrpn::{
-    buffer_unbuffer::UnbufferFrom,
-    data_types::{cookie::check_ver_nonfile_compatible, CookieData, TypedMessage},
+    data_types::TypedMessage,
     handler::{HandlerCode, TypedHandler},
-    sync_io::{read_cookie, write_cookie, EndpointSyncTcp},
+    sync_io::EndpointSyncTcp,
     tracker::PoseReport,
     Result, TypeDispatcher,
 };
@@ -35,23 +35,16 @@ impl TypedHandler for TrackerHandler {
 
 fn main() -> Result<()> {
     let addr: SocketAddr = "127.0.0.1:3883".parse().unwrap();
-    let mut stream = TcpStream::connect(addr)?;
-    stream.set_nodelay(true)?;
-
-    // We first write our cookie, then read and check the server's cookie, before the loop.
-    write_cookie(&mut stream, CookieData::make_cookie())?;
-    let cookie_buf = read_cookie(&mut stream)?;
-    let mut cookie_buf = Bytes::copy_from_slice(&cookie_buf[..]);
-
-    let msg = CookieData::unbuffer_from(&mut cookie_buf)?;
-    check_ver_nonfile_compatible(msg.version)?;
+    let stream = TcpStream::connect(addr)?;
 
-    let mut endpoint = EndpointSyncTcp::new(stream);
+    // The endpoint exchanges cookies with the server as it is polled.
+    let mut endpoint = EndpointSyncTcp::new(stream)?;
     let mut dispatcher = TypeDispatcher::new();
     let _ = dispatcher.add_typed_handler(Box::new(TrackerHandler {}), None)?;
 
-    loop {
-        endpoint.poll_endpoint(&mut dispatcher)?;
-        // Every time we get here, tehre is no more messages buffered for us.
+    while endpoint.poll_endpoint(&mut dispatcher)? {
+        // Every time we get here, there are no more messages buffered for us.
+        std::thread::sleep(Duration::from_millis(1));
     }
+    Ok(())
 }
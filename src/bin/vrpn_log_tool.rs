@@ -0,0 +1,121 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Filter, trim and merge log files.
+//!
+//! Usage: vrpn_log_tool [options] <output log> <input log>...
+//!
+//! Options:
+//!
+//! - `-start <seconds>`: drop messages recorded before this long after the first one.
+//! - `-end <seconds>`: drop messages recorded after this long after the first one.
+//! - `-drop-sender <name>`: drop messages from this sender. May be repeated.
+//! - `-drop-type <name>`: drop messages of this type. May be repeated.
+//! - `-rebase <seconds>`: shift times so the first message kept was recorded
+//!   this long after the UNIX epoch.
+//!
+//! With more than one input, the logs are merged into one in time order,
+//! with times measured from the first message in any of them.
+//! The output may not be one of the inputs.
+
+extern crate bytes;
+extern crate vrpn;
+
+use bytes::Bytes;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use vrpn::{
+    data_types::{MessageTypeName, SenderName},
+    log_file::{LogReader, LogWriter, MergeOptions},
+    Result, VrpnError,
+};
+
+fn usage() -> VrpnError {
+    VrpnError::OtherMessage(
+        "usage: vrpn_log_tool [-start <seconds>] [-end <seconds>] [-drop-sender <name>]... \
+         [-drop-type <name>]... [-rebase <seconds>] <output log> <input log>..."
+            .to_owned(),
+    )
+}
+
+fn parse_seconds(arg: Option<String>) -> Result<Duration> {
+    let seconds: f64 = arg.ok_or_else(usage)?.parse().map_err(|_| usage())?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(usage());
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    merge: MergeOptions,
+    output: String,
+    inputs: Vec<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut merge = MergeOptions::default();
+        let mut paths = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-start" => merge.start = Some(parse_seconds(args.next())?),
+                "-end" => merge.end = Some(parse_seconds(args.next())?),
+                "-drop-sender" => {
+                    let name = args.next().ok_or_else(usage)?;
+                    merge.drop_senders.push(SenderName(Bytes::from(name)));
+                }
+                "-drop-type" => {
+                    let name = args.next().ok_or_else(usage)?;
+                    merge.drop_types.push(MessageTypeName(Bytes::from(name)));
+                }
+                "-rebase" => {
+                    merge.rebase = Some(SystemTime::UNIX_EPOCH + parse_seconds(args.next())?)
+                }
+                _ if arg.starts_with('-') => return Err(usage()),
+                _ => paths.push(arg),
+            }
+        }
+        if paths.len() < 2 {
+            return Err(usage());
+        }
+        let output = paths.remove(0);
+        Ok(Options {
+            merge,
+            output,
+            inputs: paths,
+        })
+    }
+}
+
+fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+
+    // Open every input before creating the output, which truncates it.
+    let inputs = options
+        .inputs
+        .iter()
+        .map(|path| LogReader::new(BufReader::new(File::open(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    if let Ok(output) = Path::new(&options.output).canonicalize() {
+        for path in &options.inputs {
+            if Path::new(path).canonicalize()? == output {
+                return Err(VrpnError::OtherMessage(format!(
+                    "Refusing to overwrite input log {} with the output",
+                    path
+                )));
+            }
+        }
+    }
+
+    let kept = options
+        .merge
+        .merge(inputs, LogWriter::create(&options.output)?)?;
+    eprintln!("Wrote {} messages to {}", kept, options.output);
+    Ok(())
+}
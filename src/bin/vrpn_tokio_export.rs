@@ -0,0 +1,60 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Export the messages in a log file, or received from a server,
+//! to standard output as CSV or (with -json) JSON lines.
+//!
+//! Usage: vrpn_tokio_export [-json] (-file <log file> | <server>)
+
+extern crate tokio;
+extern crate vrpn;
+
+use std::sync::Arc;
+use vrpn::{
+    export::{ExportFormat, ExportHandler, Exporter},
+    log_file::LogReader,
+    vrpn_tokio::{ConnectionIp, ConnectionIpStream, StreamExtras},
+    Result, ServerInfo, VrpnError,
+};
+
+fn usage() -> VrpnError {
+    VrpnError::OtherMessage(
+        "usage: vrpn_tokio_export [-json] (-file <log file> | <server>)".to_owned(),
+    )
+}
+
+/// Export everything recorded in a log file.
+fn export_file(path: &str, format: ExportFormat) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut exporter = Exporter::new(stdout.lock(), format)?;
+    for entry in LogReader::open(path)? {
+        exporter.export_entry(&entry?)?;
+    }
+    exporter.flush()
+}
+
+/// Export everything received from a server, until it disconnects.
+async fn export_server(server: ServerInfo, format: ExportFormat) -> Result<()> {
+    let connection = ConnectionIp::new_client(server, None, None)?;
+    let _ = ExportHandler::add(&connection, Exporter::new(std::io::stdout(), format)?)?;
+    ConnectionIpStream::new(Arc::clone(&connection))
+        .drain()
+        .await
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let format = if args.first().map(String::as_str) == Some("-json") {
+        let _ = args.remove(0);
+        ExportFormat::JsonLines
+    } else {
+        ExportFormat::Csv
+    };
+    match &args[..] {
+        [flag, path] if flag == "-file" => export_file(path, format),
+        [server] => export_server(server.parse()?, format).await,
+        _ => Err(usage()),
+    }
+}
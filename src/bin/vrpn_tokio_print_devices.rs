@@ -4,52 +4,64 @@
 
 // Rough port of the vrpn_print_devices client from the
 // mainline C++ VRPN repo
+//
+// Usage: vrpn_tokio_print_devices [-file <log file>]
 
 extern crate futures;
 extern crate tokio;
 extern crate vrpn;
 
+use futures::stream;
 use std::sync::Arc;
 use vrpn::{
+    data_types::{StaticSenderName, TypedMessage},
     handler::{HandlerCode, TypedHandler},
-    prelude::*,
     tracker::PoseReport,
-    vrpn_tokio::{ping, ConnectionIp, ConnectionIpStream, StreamExtras},
-    Message, Result, ServerInfo, StaticSenderName,
+    vrpn_tokio::{
+        ping, ConnectionFile, ConnectionFileStream, ConnectionIp, ConnectionIpStream, PlaybackMode,
+        StreamExtras,
+    },
+    Connection, Result, ServerInfo,
 };
 
 #[derive(Debug)]
 struct TrackerHandler {}
 impl TypedHandler for TrackerHandler {
     type Item = PoseReport;
-    fn handle_typed(&mut self, msg: &Message<PoseReport>) -> Result<HandlerCode> {
+    fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
         println!("{:?}\n   {:?}", msg.header, msg.body);
         Ok(HandlerCode::ContinueProcessing)
     }
 }
 
-// type Selected = Drain<stream::Select<ConnectionIpStream, ping::Client<ConnectionIp>>>;
-
-fn main() {
-    let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>().unwrap();
-
-    let connection =
-        ConnectionIp::new_client(server, None, None).expect("should be able to create client");
-    let sender = connection
-        .register_sender(StaticSenderName(b"Tracker0"))
-        .expect("should be able to register sender");
-    let _ = connection
-        .add_typed_handler(Box::new(TrackerHandler {}), Some(sender))
-        .expect("should be able to add handler");
-    let ping_client = ping::Client::new(sender, Arc::clone(&connection))
-        .expect("should be able to create ping client");
-
-    let selected = ConnectionIpStream::new(Arc::clone(&connection))
-        .select(ping_client)
-        .map_err(|e| {
-            eprintln!("error: {}", e);
-        })
-        .drain();
-
-    tokio::run(selected);
+/// Print the reports recorded in a log file, at the pace they were recorded.
+async fn print_file(path: &str) -> Result<()> {
+    let connection = ConnectionFile::open(path, PlaybackMode::OriginalTiming).await?;
+    let sender = connection.register_sender(StaticSenderName(b"Tracker0"))?;
+    let _ = connection.add_typed_handler(Box::new(TrackerHandler {}), Some(sender))?;
+    ConnectionFileStream::new(connection).drain().await
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, flag, path] = &args[..] {
+        if flag == "-file" {
+            return print_file(path).await;
+        }
+    }
+
+    let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>()?;
+
+    let connection = ConnectionIp::new_client(server, None, None)?;
+    let sender = connection.register_sender(StaticSenderName(b"Tracker0"))?;
+    let _ = connection.add_typed_handler(Box::new(TrackerHandler {}), Some(sender))?;
+    let ping_client = ping::Client::new(sender, Arc::clone(&connection))?;
+
+    stream::select(
+        ConnectionIpStream::new(Arc::clone(&connection)),
+        ping_client,
+    )
+    .drain()
+    .await
 }
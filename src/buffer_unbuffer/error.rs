@@ -37,6 +37,8 @@ pub enum BufferUnbufferError {
     ParseError { parsing_kind: String, s: String },
     #[error("{}", .0)]
     MessageSizeInvalid(MessageSizeInvalid),
+    #[error("count of {count} exceeds the maximum of {max}")]
+    CountExceedsMaximum { count: usize, max: usize },
 }
 
 impl From<SizeRequirement> for BufferUnbufferError {
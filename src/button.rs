@@ -0,0 +1,332 @@
+// Copyright 2021, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Types related to the `vrpn_Button` device class
+
+use std::convert::TryFrom;
+
+use crate::{
+    buffer_unbuffer::{
+        check_buffer_remaining, check_unbuffer_remaining, BufferResult, BufferSize, BufferTo,
+        BufferUnbufferError, ConstantBufferSize, UnbufferFrom, UnbufferResult, WrappedConstantSize,
+    },
+    data_types::{
+        id_types::ButtonId, message::TypedMessageBody, name_types::StaticMessageTypeName,
+        MessageTypeIdentifier,
+    },
+};
+use bytes::{Buf, BufMut};
+
+/// Maximum number of buttons on a device, matching `vrpn_BUTTON_MAX_BUTTONS` in mainline.
+pub const MAX_BUTTONS: usize = 256;
+
+/// The state of a single button.
+///
+/// On the wire this is an `i32`: zero is released, anything else is pressed.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ButtonState {
+    #[default]
+    Released,
+    Pressed,
+}
+
+impl From<bool> for ButtonState {
+    fn from(pressed: bool) -> Self {
+        if pressed {
+            ButtonState::Pressed
+        } else {
+            ButtonState::Released
+        }
+    }
+}
+
+impl From<ButtonState> for bool {
+    fn from(state: ButtonState) -> Self {
+        state == ButtonState::Pressed
+    }
+}
+
+impl WrappedConstantSize for ButtonState {
+    type WrappedType = i32;
+    fn get(&self) -> Self::WrappedType {
+        match self {
+            ButtonState::Released => 0,
+            ButtonState::Pressed => 1,
+        }
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        ButtonState::from(v != 0)
+    }
+}
+
+/// The new state of one button, as carried in a `ButtonChangeReport`.
+///
+/// Button ids range from 0 up to, but not including, `MAX_BUTTONS`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ButtonChange {
+    /// Button id: the position of this button in a `ButtonStatesReport`
+    pub button: ButtonId,
+    /// New state
+    pub state: ButtonState,
+}
+
+impl ButtonChange {
+    pub fn new(button: ButtonId, state: ButtonState) -> ButtonChange {
+        ButtonChange { button, state }
+    }
+}
+
+impl ConstantBufferSize for ButtonChange {
+    fn constant_buffer_size() -> usize {
+        ButtonId::constant_buffer_size() + ButtonState::constant_buffer_size()
+    }
+}
+
+impl BufferTo for ButtonChange {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, Self::constant_buffer_size())?;
+        check_button_id(self.button)?;
+        self.button.buffer_to(buf)?;
+        self.state.buffer_to(buf)
+    }
+}
+
+impl UnbufferFrom for ButtonChange {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let button = ButtonId::unbuffer_from(buf)?;
+        check_button_id(button)?;
+        let state = ButtonState::unbuffer_from(buf)?;
+        Ok(ButtonChange { button, state })
+    }
+}
+
+/// New button states for a subset of the buttons on the sender.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct ButtonChangeReport {
+    pub changes: Vec<ButtonChange>,
+}
+
+impl TypedMessageBody for ButtonChangeReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Button Change"));
+}
+
+impl BufferSize for ButtonChangeReport {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() + self.changes.len() * ButtonChange::constant_buffer_size()
+    }
+}
+
+impl BufferTo for ButtonChangeReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        buffer_count(self.changes.len(), buf)?;
+        for change in &self.changes {
+            change.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for ButtonChangeReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let count = unbuffer_count(buf)?;
+        check_unbuffer_remaining(buf, count * ButtonChange::constant_buffer_size())?;
+        let changes = (0..count)
+            .map(|_| ButtonChange::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<_>>>()?;
+        Ok(ButtonChangeReport { changes })
+    }
+}
+
+/// Current button states for all buttons on the sender.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct ButtonStatesReport {
+    /// States, indexed by button id.
+    pub states: Vec<ButtonState>,
+}
+
+impl TypedMessageBody for ButtonStatesReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Button States"));
+}
+
+impl BufferSize for ButtonStatesReport {
+    fn buffer_size(&self) -> usize {
+        i32::constant_buffer_size() + self.states.len() * ButtonState::constant_buffer_size()
+    }
+}
+
+impl BufferTo for ButtonStatesReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        check_buffer_remaining(buf, self.buffer_size())?;
+        buffer_count(self.states.len(), buf)?;
+        for state in &self.states {
+            state.buffer_to(buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for ButtonStatesReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        let count = unbuffer_count(buf)?;
+        check_unbuffer_remaining(buf, count * ButtonState::constant_buffer_size())?;
+        let states = (0..count)
+            .map(|_| ButtonState::unbuffer_from(buf))
+            .collect::<UnbufferResult<Vec<_>>>()?;
+        Ok(ButtonStatesReport { states })
+    }
+}
+
+fn check_button_count(count: usize) -> Result<usize, BufferUnbufferError> {
+    if count > MAX_BUTTONS {
+        Err(BufferUnbufferError::CountExceedsMaximum {
+            count,
+            max: MAX_BUTTONS,
+        })
+    } else {
+        Ok(count)
+    }
+}
+
+fn check_button_id(button: ButtonId) -> Result<(), BufferUnbufferError> {
+    match usize::try_from(button.0) {
+        Ok(index) if index < MAX_BUTTONS => Ok(()),
+        _ => Err(BufferUnbufferError::ParseError {
+            parsing_kind: "button id".to_string(),
+            s: format!("{} is not a valid button id", button.0),
+        }),
+    }
+}
+
+fn buffer_count<T: BufMut>(count: usize, buf: &mut T) -> BufferResult {
+    // At most MAX_BUTTONS, so fits.
+    (check_button_count(count)? as i32).buffer_to(buf)
+}
+
+fn unbuffer_count<T: Buf>(buf: &mut T) -> UnbufferResult<usize> {
+    let count = i32::unbuffer_from(buf)?;
+    let count = usize::try_from(count).map_err(|_| BufferUnbufferError::ParseError {
+        parsing_kind: "button count".to_string(),
+        s: format!("negative count {}", count),
+    })?;
+    check_button_count(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::BytesMutExtras;
+    use bytes::{Bytes, BytesMut};
+    use proptest::prelude::*;
+
+    #[test]
+    fn change_fixture() {
+        let mut buf = Bytes::from_static(&hex!(
+            "00 00 00 02 00 00 00 03 00 00 00 01 00 00 00 00 00 00 00 00"
+        ));
+        let report = ButtonChangeReport::unbuffer_from(&mut buf).unwrap();
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(
+            report.changes,
+            vec![
+                ButtonChange::new(ButtonId(3), ButtonState::Pressed),
+                ButtonChange::new(ButtonId(0), ButtonState::Released)
+            ]
+        );
+    }
+
+    #[test]
+    fn states_fixture() {
+        let mut buf = Bytes::from_static(&hex!("00 00 00 03 00 00 00 00 00 00 00 01 00 00 00 00"));
+        let report = ButtonStatesReport::unbuffer_from(&mut buf).unwrap();
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(
+            report.states,
+            vec![
+                ButtonState::Released,
+                ButtonState::Pressed,
+                ButtonState::Released
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_count() {
+        let mut buf = Bytes::from_static(&hex!("ff ff ff ff"));
+        assert!(ButtonStatesReport::unbuffer_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn too_many_buttons() {
+        let report = ButtonStatesReport {
+            states: vec![ButtonState::Released; MAX_BUTTONS + 1],
+        };
+        assert_eq!(
+            BytesMut::allocate_and_buffer(report).unwrap_err(),
+            BufferUnbufferError::CountExceedsMaximum {
+                count: MAX_BUTTONS + 1,
+                max: MAX_BUTTONS
+            }
+        );
+
+        // 257 changes
+        let mut buf = Bytes::from_static(&hex!("00 00 01 01"));
+        assert_eq!(
+            ButtonChangeReport::unbuffer_from(&mut buf).unwrap_err(),
+            BufferUnbufferError::CountExceedsMaximum {
+                count: MAX_BUTTONS + 1,
+                max: MAX_BUTTONS
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_button_id() {
+        // Button 256, pressed
+        let mut buf = Bytes::from_static(&hex!("00 00 00 01 00 00 01 00 00 00 00 01"));
+        assert!(ButtonChangeReport::unbuffer_from(&mut buf).is_err());
+        let report = ButtonChangeReport {
+            changes: vec![ButtonChange::new(ButtonId(-1), ButtonState::Pressed)],
+        };
+        assert!(BytesMut::allocate_and_buffer(report).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = Bytes::from_static(&hex!("00 00 00 02 00 00 00 03 00 00 00 01"));
+        assert!(ButtonChangeReport::unbuffer_from(&mut buf).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn change_roundtrip(changes in prop::collection::vec((0..MAX_BUTTONS as i32, any::<bool>()), 0..64)) {
+            let report = ButtonChangeReport {
+                changes: changes
+                    .into_iter()
+                    .map(|(id, pressed)| ButtonChange::new(ButtonId(id), pressed.into()))
+                    .collect(),
+            };
+            let mut buf = BytesMut::allocate_and_buffer(report.clone()).unwrap().freeze();
+            prop_assert_eq!(buf.len(), report.buffer_size());
+            let decoded = ButtonChangeReport::unbuffer_from(&mut buf).unwrap();
+            prop_assert_eq!(buf.remaining(), 0);
+            prop_assert_eq!(decoded, report);
+        }
+
+        #[test]
+        fn states_roundtrip(states in prop::collection::vec(any::<bool>(), 0..64)) {
+            let report = ButtonStatesReport {
+                states: states.into_iter().map(ButtonState::from).collect(),
+            };
+            let mut buf = BytesMut::allocate_and_buffer(report.clone()).unwrap().freeze();
+            prop_assert_eq!(buf.len(), report.buffer_size());
+            let decoded = ButtonStatesReport::unbuffer_from(&mut buf).unwrap();
+            prop_assert_eq!(buf.remaining(), 0);
+            prop_assert_eq!(decoded, report);
+        }
+    }
+}
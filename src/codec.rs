@@ -2,14 +2,18 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use bytes::Buf;
+use std::collections::VecDeque;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::{
-    buffer_unbuffer::{BufferUnbufferError, UnbufferResult},
-    data_types::SequencedGenericMessage,
+    buffer_unbuffer::{BufferResult, BufferSize, BufferUnbufferError, UnbufferResult},
+    constants::UDP_BUFLEN,
+    data_types::{id_types::SequenceNumber, GenericMessage, MessageSize, SequencedGenericMessage},
 };
 
 /// Decode at most 1 message. Returns Ok(None) if we don't have enough data.
+#[cfg(any(test, feature = "async-tokio"))]
 pub(crate) fn maybe_decode_one<T: Buf + Clone>(
     buf: &mut T,
 ) -> UnbufferResult<Option<SequencedGenericMessage>> {
@@ -21,6 +25,66 @@ pub(crate) fn maybe_decode_one<T: Buf + Clone>(
     }
 }
 
+/// Split a received datagram into the messages packed in it.
+///
+/// Messages never span datagrams, so leftover partial data is an error.
+pub(crate) fn decode_datagram(mut buf: Bytes) -> UnbufferResult<Vec<SequencedGenericMessage>> {
+    let mut messages = Vec::new();
+    while buf.has_remaining() {
+        messages.push(SequencedGenericMessage::try_read_from_buf(&mut buf)?);
+    }
+    Ok(messages)
+}
+
+/// Whether a message is small enough to be sent in a datagram.
+pub(crate) fn fits_in_datagram(msg: &GenericMessage) -> bool {
+    MessageSize::from_unpadded_body_size(msg.body.buffer_size()).padded_message_size() <= UDP_BUFLEN
+}
+
+/// Sequences messages and packs them into datagrams of at most `UDP_BUFLEN` bytes,
+/// as many messages per datagram as will fit.
+#[derive(Debug, Default)]
+pub(crate) struct DatagramPacker {
+    current: BytesMut,
+    complete: VecDeque<Bytes>,
+    seq: u32,
+}
+
+impl DatagramPacker {
+    pub(crate) fn new() -> DatagramPacker {
+        Default::default()
+    }
+
+    /// Sequence a message and add it to the datagram being filled,
+    /// starting a new datagram first if it doesn't fit.
+    ///
+    /// Fails if the message is too big to fit in a datagram at all.
+    pub(crate) fn push(&mut self, msg: GenericMessage) -> BufferResult {
+        if !fits_in_datagram(&msg) {
+            return Err(BufferUnbufferError::OutOfBuffer);
+        }
+        let msg = msg.into_sequenced_message(SequenceNumber(self.seq));
+        if self.current.len() + msg.buffer_size() > UDP_BUFLEN {
+            self.finish();
+        }
+        self.current.put(msg.try_into_buf()?);
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Close off the datagram being filled, if it has anything in it.
+    pub(crate) fn finish(&mut self) {
+        if !self.current.is_empty() {
+            self.complete.push_back(self.current.split().freeze());
+        }
+    }
+
+    /// Take the oldest complete datagram.
+    pub(crate) fn pop(&mut self) -> Option<Bytes> {
+        self.complete.pop_front()
+    }
+}
+
 // pub(crate) fn decode_one_mut(buf: &mut BytesMut) -> Result<Option<SequencedGenericMessage>> {
 //     let initial_len = buf.len();
 //     if let Some(combined_size) = peek_u32_bytes_mut(buf)? {
@@ -50,6 +114,68 @@ mod tests {
     use bytes::Bytes;
 
     use super::*;
+    use crate::data_types::{
+        id_types::{MessageTypeId, SenderId},
+        GenericBody, MessageHeader, TimeVal,
+    };
+
+    fn make_message(body_len: usize) -> GenericMessage {
+        GenericMessage {
+            header: MessageHeader::new(Some(TimeVal::default()), MessageTypeId(3), SenderId(1)),
+            body: GenericBody::new(Bytes::from(vec![0xaa; body_len])),
+        }
+    }
+
+    #[test]
+    fn pack_datagrams() {
+        let mut packer = DatagramPacker::new();
+        // 24-byte header plus 400-byte body: three fit in one datagram.
+        for _ in 0..4 {
+            packer.push(make_message(400)).unwrap();
+        }
+        packer.finish();
+        let first = packer.pop().unwrap();
+        let second = packer.pop().unwrap();
+        assert!(packer.pop().is_none());
+        assert_eq!(first.len(), 3 * 424);
+        assert_eq!(second.len(), 424);
+
+        // Each datagram splits back into its messages, sequenced in order.
+        let messages = decode_datagram(first).unwrap();
+        let seqs: Vec<_> = messages.iter().map(|m| m.sequence_number).collect();
+        assert_eq!(
+            seqs,
+            vec![SequenceNumber(0), SequenceNumber(1), SequenceNumber(2)]
+        );
+        assert_eq!(messages[0].message(), &make_message(400));
+        let messages = decode_datagram(second).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sequence_number, SequenceNumber(3));
+    }
+
+    #[test]
+    fn decode_truncated_datagram() {
+        let mut packer = DatagramPacker::new();
+        packer.push(make_message(8)).unwrap();
+        packer.push(make_message(8)).unwrap();
+        packer.finish();
+        let datagram = packer.pop().unwrap();
+        assert!(decode_datagram(datagram.slice(..datagram.len() - 4)).is_err());
+        assert!(decode_datagram(Bytes::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pack_too_big() {
+        let mut packer = DatagramPacker::new();
+        assert_eq!(
+            packer.push(make_message(UDP_BUFLEN)),
+            Err(BufferUnbufferError::OutOfBuffer)
+        );
+        packer.finish();
+        assert!(packer.pop().is_none());
+        assert!(fits_in_datagram(&make_message(UDP_BUFLEN - 24)));
+        assert!(!fits_in_datagram(&make_message(UDP_BUFLEN - 23)));
+    }
 
     #[test]
     fn individual_decode_one() {
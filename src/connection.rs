@@ -4,19 +4,25 @@
 
 use std::{
     convert::TryFrom,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use crate::{
-    buffer_unbuffer::BufferTo,
+    buffer_unbuffer::{BufferTo, EmptyMessage},
     data_types::{
+        constants,
         id_types::*,
         name_types::{MessageTypeIdentifier, NameIntoBytes},
         ClassOfService, GenericMessage, LogFileNames, MessageTypeId, MessageTypeName, SenderName,
         TimeVal, TypedMessage, TypedMessageBody,
     },
     type_dispatcher::HandlerHandle,
-    Endpoint, EndpointGeneric, Handler, RegisterMapping, Result, TypeDispatcher, TypedHandler,
+    Endpoint, EndpointGeneric, Handler, RegisterMapping, Result, ServerInfo, TypeDispatcher,
+    TypedHandler,
 };
 
 pub type EndpointVec<EP> = Vec<Option<EP>>;
@@ -30,6 +36,100 @@ pub enum ConnectionStatus {
     ClientConnected,
     /// This is a server connection, the number of connected endpoints is provided
     Server(usize),
+    /// This connection has been shut down, and is closing or closed.
+    Closed,
+}
+
+/// Sent by the "VRPN Control" sender to local handlers when an endpoint connects
+/// and there were none before, just ahead of `GotConnection`.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct GotFirstConnection;
+
+impl EmptyMessage for GotFirstConnection {}
+impl TypedMessageBody for GotFirstConnection {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(constants::GOT_FIRST_CONNECTION);
+}
+
+/// Sent by the "VRPN Control" sender to local handlers when an endpoint connects.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct GotConnection;
+
+impl EmptyMessage for GotConnection {}
+impl TypedMessageBody for GotConnection {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(constants::GOT_CONNECTION);
+}
+
+/// Sent by the "VRPN Control" sender to local handlers when an endpoint disconnects.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct DroppedConnection;
+
+impl EmptyMessage for DroppedConnection {}
+impl TypedMessageBody for DroppedConnection {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(constants::DROPPED_CONNECTION);
+}
+
+/// Sent by the "VRPN Control" sender to local handlers when the last endpoint disconnects,
+/// just after `DroppedConnection`.
+///
+/// Servers typically use this to reset device state once nobody is listening.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct DroppedLastConnection;
+
+impl EmptyMessage for DroppedLastConnection {}
+impl TypedMessageBody for DroppedLastConnection {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(constants::DROPPED_LAST_CONNECTION);
+}
+
+/// Make a connection event message, to dispatch to local handlers only.
+fn connection_event<T>(dispatcher: &mut TypeDispatcher, body: T) -> Result<GenericMessage>
+where
+    T: TypedMessageBody + BufferTo,
+{
+    let message_type = match T::MESSAGE_IDENTIFIER {
+        MessageTypeIdentifier::UserMessageName(name) => dispatcher.register_type(name)?,
+        MessageTypeIdentifier::SystemMessageId(id) => RegisterMapping::Found(LocalId(id)),
+    };
+    let sender = dispatcher.register_sender(constants::CONTROL)?;
+    let msg = TypedMessage::new(None, message_type.into_inner(), sender.into_inner(), body);
+    Ok(GenericMessage::try_from(msg)?)
+}
+
+/// Queue the events for an endpoint connecting, `first` if there were none before.
+pub(crate) fn got_connection_events(
+    dispatcher: &mut TypeDispatcher,
+    first: bool,
+    events: &mut Vec<GenericMessage>,
+) -> Result<()> {
+    if first {
+        events.push(connection_event(dispatcher, GotFirstConnection)?);
+    }
+    events.push(connection_event(dispatcher, GotConnection)?);
+    Ok(())
+}
+
+/// Queue the events for an endpoint disconnecting, `last` if there are none left.
+pub(crate) fn dropped_connection_events(
+    dispatcher: &mut TypeDispatcher,
+    last: bool,
+    events: &mut Vec<GenericMessage>,
+) -> Result<()> {
+    events.push(connection_event(dispatcher, DroppedConnection)?);
+    if last {
+        events.push(connection_event(dispatcher, DroppedLastConnection)?);
+    }
+    Ok(())
 }
 
 pub trait Connection: Send + Sync {
@@ -195,6 +295,21 @@ pub trait Connection: Send + Sync {
         Ok(())
     }
 
+    /// Shut down this connection: tell the peer of every endpoint that we are disconnecting,
+    /// and stop accepting or reconnecting.
+    ///
+    /// Each endpoint closes once everything queued for it has been sent,
+    /// so keep polling the connection until it finishes.
+    fn shutdown(&self) -> Result<()> {
+        let core = self.connection_core();
+        core.shut_down.store(true, Ordering::SeqCst);
+        let mut endpoints = core.endpoints.lock()?;
+        for ep in endpoints.iter_mut().flatten() {
+            ep.disconnect()?;
+        }
+        Ok(())
+    }
+
     /// Gets a reference-counted handle to the mutex-protected endpoint vector.
     fn endpoints(&self) -> SharedEndpointVec<Self::SpecificEndpoint> {
         Arc::clone(&self.connection_core().endpoints)
@@ -206,6 +321,98 @@ pub trait Connection: Send + Sync {
     }
 }
 
+/// A connection type that can be created as a client of a given server.
+///
+/// Lets generic code, like the remote device objects, create its own connection.
+pub trait ClientConnection: Connection + Sized {
+    /// Create a new client connection to the given server.
+    ///
+    /// The connection will typically still need to be polled to actually connect.
+    fn new_client_connection(server: ServerInfo) -> Result<Arc<Self>>;
+}
+
+/// How a client connection tries to get back in touch with its server
+/// after losing (or failing to make) its connection.
+///
+/// The delay before each attempt grows by `backoff_factor` up to `max_delay`,
+/// and starts over once connected again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt.
+    pub initial_delay: Duration,
+    /// Upper limit on the delay between attempts.
+    pub max_delay: Duration,
+    /// What each delay is multiplied by to get the next.
+    pub backoff_factor: u32,
+    /// Give up after this many attempts in a row fail, or keep trying forever if `None`.
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// Get the delay before an attempt, given how many attempts in a row came before it,
+    /// or `None` if we should give up instead.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if matches!(self.max_attempts, Some(max) if attempt >= max) {
+            return None;
+        }
+        let factor = self.backoff_factor.checked_pow(attempt).unwrap_or(u32::MAX);
+        Some(
+            self.initial_delay
+                .checked_mul(factor)
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay)),
+        )
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            backoff_factor: 2,
+            max_attempts: None,
+        }
+    }
+}
+
+/// Keeps track of a client connection's attempts to reconnect to its server.
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+#[derive(Debug)]
+pub(crate) struct Reconnect {
+    server: ServerInfo,
+    policy: Option<ReconnectPolicy>,
+    attempts: u32,
+}
+
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+impl Reconnect {
+    /// Start out not reconnecting at all: a lost connection stays lost.
+    pub(crate) fn new(server: ServerInfo) -> Reconnect {
+        Reconnect {
+            server,
+            policy: None,
+            attempts: 0,
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Note that we are connected, so the next attempt starts at the initial delay.
+    pub(crate) fn connected(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Get the server to contact and how long to wait first,
+    /// or `None` if the policy says not to try again.
+    pub(crate) fn next_attempt(&mut self) -> Option<(ServerInfo, Duration)> {
+        let delay = self.policy.as_ref()?.delay(self.attempts)?;
+        self.attempts += 1;
+        Some((self.server.clone(), delay))
+    }
+}
+
 #[derive(Debug)]
 pub struct ConnectionCore<EP>
 where
@@ -213,8 +420,12 @@ where
 {
     pub(crate) endpoints: SharedEndpointVec<EP>,
     pub(crate) type_dispatcher: Arc<Mutex<TypeDispatcher>>,
+    // Only the IP connections keep logs.
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
     remote_log_names: LogFileNames,
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
     local_log_names: LogFileNames,
+    shut_down: AtomicBool,
 }
 impl<EP> ConnectionCore<EP>
 where
@@ -225,11 +436,82 @@ where
         local_log_names: Option<LogFileNames>,
         remote_log_names: Option<LogFileNames>,
     ) -> ConnectionCore<EP> {
+        // Ignored unless an IP connection could use them.
+        #[cfg(not(any(feature = "async-tokio", feature = "vrpn-async-std")))]
+        let _ = (local_log_names, remote_log_names);
         ConnectionCore {
             endpoints: Arc::new(Mutex::new(endpoints)),
             type_dispatcher: Arc::new(Mutex::new(TypeDispatcher::new())),
+            #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
             remote_log_names: LogFileNames::from(remote_log_names),
+            #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
             local_log_names: LogFileNames::from(local_log_names),
+            shut_down: AtomicBool::new(false),
         }
     }
+
+    /// Whether `Connection::shutdown()` has been called.
+    pub(crate) fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// The logs each endpoint should keep on our side.
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    pub(crate) fn local_log_names(&self) -> &LogFileNames {
+        &self.local_log_names
+    }
+
+    /// The logs a client asks the server to keep on its behalf.
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    pub(crate) fn remote_log_names(&self) -> &LogFileNames {
+        &self.remote_log_names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            backoff_factor: 3,
+            max_attempts: Some(5),
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(300)),
+                Some(Duration::from_millis(900)),
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(1)),
+                None
+            ]
+        );
+        // Big attempt counts must not overflow.
+        let forever = ReconnectPolicy::default();
+        assert_eq!(forever.delay(1000), Some(forever.max_delay));
+    }
+
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    #[test]
+    fn reconnect_attempts() {
+        let server: ServerInfo = "tcp://127.0.0.1:3883".parse().unwrap();
+        let mut reconnect = Reconnect::new(server.clone());
+        assert_eq!(reconnect.next_attempt(), None);
+
+        reconnect.set_policy(Some(ReconnectPolicy::default()));
+        let initial = ReconnectPolicy::default().initial_delay;
+        assert_eq!(reconnect.next_attempt(), Some((server.clone(), initial)));
+        assert_eq!(
+            reconnect.next_attempt(),
+            Some((server.clone(), initial * 2))
+        );
+        reconnect.connected();
+        assert_eq!(reconnect.next_attempt(), Some((server, initial)));
+    }
 }
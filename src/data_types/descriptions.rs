@@ -11,7 +11,8 @@ use std::{
 };
 
 use crate::buffer_unbuffer::{
-    check_buffer_remaining, BufferResult, BufferSize, BufferTo, UnbufferFrom, UnbufferResult,
+    check_buffer_remaining, BufferResult, BufferSize, BufferTo, EmptyMessage, SizeRequirement,
+    UnbufferFrom, UnbufferResult,
 };
 
 use super::{
@@ -170,12 +171,13 @@ impl<I: IdWithNameAndDescription> UnbufferFrom for InnerDescription<I> {
 impl UnbufferFrom for UdpInnerDescription {
     fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
         let mut ip_buf: Vec<u8> = Vec::default();
-        // ok to unwrap: a buf reader is infallible. The reader also prevents us from modifying the buffer state.
-        let length = buf.reader().read_until(0, &mut ip_buf).unwrap();
-        // let ip_buf: Vec<u8> = buf.into_iter().take_while(|b| **b != 0).cloned().collect();
+        // ok to unwrap: a buf reader is infallible. Reading advances the buffer, null terminator included.
+        let _ = buf.reader().read_until(0, &mut ip_buf).unwrap();
+        if ip_buf.pop() != Some(0) {
+            return Err(SizeRequirement::Unknown.into());
+        }
         let ip_str = String::from_utf8_lossy(&ip_buf);
         let addr: IpAddr = ip_str.parse()?;
-        buf.advance(length);
 
         Ok(UdpInnerDescription::new(addr))
     }
@@ -196,3 +198,42 @@ impl BufferTo for UdpInnerDescription {
         Ok(())
     }
 }
+
+/// TypedMessageBody-implementing structure for DISCONNECT_MESSAGE system messages,
+/// telling the peer we are closing the connection.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Disconnect;
+
+impl EmptyMessage for Disconnect {}
+impl TypedMessageBody for Disconnect {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::SystemMessageId(constants::DISCONNECT_MESSAGE);
+}
+
+impl From<Disconnect> for TypedMessage<Disconnect> {
+    fn from(v: Disconnect) -> TypedMessage<Disconnect> {
+        TypedMessage::new(None, constants::DISCONNECT_MESSAGE, SenderId(0), v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::GenericMessage, endpoint::*};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn udp_description_roundtrip() {
+        let desc = UdpDescription::new("192.168.1.20:51234".parse().unwrap());
+        let msg = GenericMessage::try_from(TypedMessage::from(desc.clone())).unwrap();
+        assert_eq!(&msg.body.clone().into_inner()[..], &b"192.168.1.20\0"[..]);
+        match parse_system_message(msg).unwrap() {
+            SystemCommand::Extended(ExtendedSystemCommand::UdpDescription(parsed)) => {
+                assert_eq!(parsed, desc)
+            }
+            other => panic!("unexpected system command {:?}", other),
+        }
+    }
+}
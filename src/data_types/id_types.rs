@@ -195,6 +195,20 @@ impl WrappedConstantSize for Sensor {
     }
 }
 
+/// Button ID (index) for buttons.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ButtonId(pub i32);
+
+impl WrappedConstantSize for ButtonId {
+    type WrappedType = i32;
+    fn get(&self) -> Self::WrappedType {
+        self.0
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        ButtonId(v)
+    }
+}
+
 pub(crate) enum CategorizedId {
     BelowZero(IdType),
     InArray(IdTypeUnsigned),
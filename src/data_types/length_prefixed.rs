@@ -43,19 +43,20 @@ pub fn buffer_string<T: BufMut>(
     termination: NullTermination,
     null_in_len: LengthBehavior,
 ) -> buffer::BufferResult {
-    let mut buf_size = buffer_size(s, termination);
-
-    buffer::check_buffer_remaining(buf, buf_size)?;
-    if termination == NullTermination::AddTrailingNull && null_in_len == LengthBehavior::ExcludeNull
+    buffer::check_buffer_remaining(buf, buffer_size(s, termination))?;
+    // The length prefix doesn't count itself, and only counts the null terminator if asked.
+    let mut transmitted_len = s.len();
+    if termination == NullTermination::AddTrailingNull && null_in_len == LengthBehavior::IncludeNull
     {
-        // Decrement the length that we transmit if we're adding a null terminator but not including it in the length.
-        buf_size -= 1;
+        transmitted_len += 1;
     }
-    let buf_size = buf_size as u32;
-    buf_size.buffer_to(buf)?;
+    let transmitted_len = transmitted_len as u32;
+    transmitted_len.buffer_to(buf)?;
 
     buf.put(s);
-    buf.put_u8(0);
+    if termination == NullTermination::AddTrailingNull {
+        buf.put_u8(0);
+    }
     Ok(())
 }
 
@@ -78,3 +79,32 @@ pub fn unbuffer_string<T: Buf>(buf: &mut T) -> unbuffer::UnbufferResult<Bytes> {
     unbuffer::consume_expected(buf, b"\0")?;
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn roundtrip_with_null() {
+        let mut buf = BytesMut::new();
+        buffer_string(
+            b"VRPN Control",
+            &mut buf,
+            NullTermination::AddTrailingNull,
+            LengthBehavior::IncludeNull,
+        )
+        .unwrap();
+        assert_eq!(
+            buf.len(),
+            buffer_size(b"VRPN Control", NullTermination::AddTrailingNull)
+        );
+        assert_eq!(
+            &buf[..],
+            &hex!("00 00 00 0d 56 52 50 4e 20 43 6f 6e 74 72 6f 6c 00")[..]
+        );
+        let mut buf = buf.freeze();
+        assert_eq!(unbuffer_string(&mut buf).unwrap(), &b"VRPN Control"[..]);
+        assert!(buf.is_empty());
+    }
+}
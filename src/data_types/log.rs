@@ -7,7 +7,10 @@ use crate::buffer_unbuffer::{
 };
 use bytes::{Buf, BufMut, Bytes};
 
-use super::{constants, name_types::MessageTypeIdentifier, TypedMessageBody};
+use super::{
+    constants, id_types::SenderId, name_types::MessageTypeIdentifier, TypedMessage,
+    TypedMessageBody,
+};
 
 bitflags! {
     pub struct LogMode: u8  {
@@ -173,6 +176,18 @@ impl TypedMessageBody for LogFileNames {
         MessageTypeIdentifier::SystemMessageId(constants::LOG_DESCRIPTION);
 }
 
+/// Like mainline, the sender field of a log description carries the requested log mode.
+impl From<LogFileNames> for TypedMessage<LogFileNames> {
+    fn from(v: LogFileNames) -> TypedMessage<LogFileNames> {
+        TypedMessage::new(
+            None,
+            constants::LOG_DESCRIPTION,
+            SenderId(v.log_mode().bits() as i32),
+            v,
+        )
+    }
+}
+
 fn unbuffer_logname<T: Buf>(len: usize, buf: &mut T) -> unbuffer::UnbufferResult<Option<Bytes>> {
     let name = if len > 0 {
         Some(buf.copy_to_bytes(len))
@@ -305,8 +305,8 @@ impl SequencedGenericMessage {
         let header = MessageHeader::unbuffer_from(&mut local_buf)?;
         assert_ne!(local_buf.remaining(), 0);
 
+        // Nothing may follow this: empty bodies are allowed.
         let sequence_number = SequenceNumber::unbuffer_from(&mut local_buf)?;
-        assert_ne!(local_buf.remaining(), 0);
 
         // Assert that handling the sequence number meant we're now aligned again.
         assert_eq!(
@@ -535,6 +535,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn empty_body_roundtrip() {
+        let msg = SequencedGenericMessage {
+            message: GenericMessage::from_header_and_body(
+                MessageHeader::new(None, MessageTypeId(-5), SenderId(0)),
+                GenericBody::default(),
+            ),
+            sequence_number: SequenceNumber(3),
+        };
+        let mut buf = msg.clone().try_into_buf().unwrap();
+        assert_eq!(buf.len(), MINIMUM_SIZE_FIELD as usize);
+        assert_eq!(
+            SequencedGenericMessage::try_read_from_buf(&mut buf).unwrap(),
+            msg
+        );
+    }
+
     #[test]
     fn invalid_msg_size() {
         assert!(MessageSize::try_from_length_field(20).is_err())
@@ -21,9 +21,9 @@ pub mod math_cgmath;
 #[doc(inline)]
 pub use crate::data_types::{
     cookie::{CookieData, Version},
-    descriptions::{Description, UdpDescription},
+    descriptions::{Description, Disconnect, UdpDescription},
     math::{Quat, Vec3},
-    time::TimeVal,
+    time::{Microseconds, Seconds, TimeVal},
 };
 pub use crate::data_types::{
     id_types::MessageTypeId,
@@ -37,7 +37,7 @@ pub use crate::data_types::{
     },
 };
 
-pub(crate) use crate::data_types::log::{LogFileNames, LogMode};
+pub use crate::data_types::log::{LogFileNames, LogMode};
 
 bitflags! {
     /// Class of service flags matching those in the original vrpn
@@ -9,9 +9,9 @@ use bytes::Bytes;
 use crate::{
     buffer_unbuffer::BufferTo,
     data_types::{
-        constants, id_types::*, message::Message, ClassOfService, Description, GenericMessage,
-        IdWithNameAndDescription, LogFileNames, MessageHeader, MessageTypeId, MessageTypeName,
-        SenderName, TypedMessage, TypedMessageBody, UdpDescription,
+        constants, id_types::*, message::Message, ClassOfService, Description, Disconnect,
+        GenericMessage, IdWithNameAndDescription, LogFileNames, MessageHeader, MessageTypeId,
+        MessageTypeName, SenderName, TypedMessage, TypedMessageBody, UdpDescription,
     },
     translation_table::{TranslationTable, TranslationTableExt},
     type_dispatcher::TryIntoDescriptionMessage,
@@ -83,9 +83,7 @@ pub fn handle_system_command(
 ) -> Result<Option<ExtendedSystemCommand>> {
     match system_command {
         SystemCommand::SenderDescription(desc) => {
-            let local_id = dispatcher
-                .register_sender(SenderName(desc.name.clone()))?
-                .into_inner();
+            let local_id = dispatcher.register_remote_sender(SenderName(desc.name.clone()))?;
             eprintln!(
                 "Registering sender {:?}: local {:?} = remote {:?}",
                 desc.name, local_id, desc.which
@@ -95,9 +93,7 @@ pub fn handle_system_command(
             Ok(None)
         }
         SystemCommand::TypeDescription(desc) => {
-            let local_id = dispatcher
-                .register_type(MessageTypeName(desc.name.clone()))?
-                .into_inner();
+            let local_id = dispatcher.register_remote_type(MessageTypeName(desc.name.clone()))?;
             eprintln!(
                 "Registering type {:?}: local {:?} = remote {:?}",
                 desc.name, local_id, desc.which
@@ -128,6 +124,22 @@ pub trait Endpoint /* : AsMut<TranslationTables> + AsRef<TranslationTables> */ {
     /// Queue up a generic message for sending.
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()>;
 
+    /// Record a message just received from the peer, before it is mapped to local IDs.
+    ///
+    /// Only endpoints that can keep an incoming log need to implement this.
+    fn log_incoming(&mut self, _msg: &GenericMessage) -> Result<()> {
+        Ok(())
+    }
+
+    /// Tell the peer we are disconnecting, and close once everything queued has been sent.
+    ///
+    /// The default just queues the disconnect message,
+    /// for endpoints with nothing of their own to close.
+    fn disconnect(&mut self) -> Result<()> {
+        let msg = GenericMessage::try_from(TypedMessage::from(Disconnect))?;
+        self.buffer_generic_message(msg, ClassOfService::RELIABLE)
+    }
+
     /// Pack all descriptions from the dispatcher and send them.
     fn send_all_descriptions(&mut self, dispatcher: &TypeDispatcher) -> Result<()> {
         for msg in dispatcher.pack_all_descriptions()? {
@@ -37,6 +37,8 @@ pub enum VrpnError {
     UnrecognizedSystemMessage(IdType),
     #[error("endpoint is closed or closing")]
     EndpointClosed,
+    #[error("handler was dropped before a reply arrived")]
+    ReplyCanceled,
     #[error("{0}")]
     MessageSizeInvalid(MessageSizeInvalid),
     #[error("{0}")]
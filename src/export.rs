@@ -0,0 +1,459 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Exporting messages from logs or live connections as CSV or JSON lines, for offline analysis.
+//!
+//! Tracker pose, button and analog reports are decoded into their fields:
+//! other messages are exported with their body in hex.
+
+use crate::{
+    analog::AnalogReport,
+    button::{ButtonChangeReport, ButtonStatesReport},
+    data_types::{
+        constants, GenericMessage, MessageTypeIdentifier, MessageTypeName, SenderName,
+        StaticMessageTypeName, TimeVal, TypedMessage, TypedMessageBody,
+    },
+    handler::{Handler, HandlerCode, HandlerHandle},
+    log_file::LogEntry,
+    tracker::PoseReport,
+    Connection, Endpoint, Result,
+};
+use bytes::Bytes;
+use std::{
+    convert::TryFrom,
+    fmt::Write as _,
+    io::Write,
+    sync::{Arc, Weak},
+    time::SystemTime,
+};
+
+/// How exported messages are written.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// One row per message: time, sender, type, then the fields.
+    ///
+    /// Rows have as many fields as their message: a header row names the common columns.
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// The contents of a message, decoded if it is of a kind we know.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportedBody {
+    Pose(PoseReport),
+    ButtonChange(ButtonChangeReport),
+    ButtonStates(ButtonStatesReport),
+    Analog(AnalogReport),
+    /// Any other message, or one that failed to decode.
+    Unknown(Bytes),
+}
+
+fn is_type<T: TypedMessageBody>(message_type: &MessageTypeName) -> bool {
+    match T::MESSAGE_IDENTIFIER {
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(name)) => {
+            message_type.0 == name
+        }
+        MessageTypeIdentifier::SystemMessageId(_) => false,
+    }
+}
+
+impl ExportedBody {
+    /// Decode a message, given the name of its type.
+    pub fn decode(msg: &GenericMessage, message_type: &MessageTypeName) -> ExportedBody {
+        fn typed<T>(msg: &GenericMessage) -> Option<T>
+        where
+            T: TypedMessageBody + crate::buffer_unbuffer::UnbufferFrom,
+        {
+            TypedMessage::<T>::try_from(msg).ok().map(|msg| msg.body)
+        }
+        let decoded = if is_type::<PoseReport>(message_type) {
+            typed(msg).map(ExportedBody::Pose)
+        } else if is_type::<ButtonChangeReport>(message_type) {
+            typed(msg).map(ExportedBody::ButtonChange)
+        } else if is_type::<ButtonStatesReport>(message_type) {
+            typed(msg).map(ExportedBody::ButtonStates)
+        } else if is_type::<AnalogReport>(message_type) {
+            typed(msg).map(ExportedBody::Analog)
+        } else {
+            None
+        };
+        decoded.unwrap_or_else(|| ExportedBody::Unknown(msg.body.clone().into_inner()))
+    }
+}
+
+/// Format a time as seconds since the UNIX epoch, to the microsecond.
+fn format_time(time: TimeVal) -> String {
+    let since_epoch = SystemTime::from(time)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.{:06}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros()
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON has no infinities or NaN.
+fn json_number(v: f64) -> String {
+    if v.is_finite() {
+        v.to_string()
+    } else {
+        "null".to_owned()
+    }
+}
+
+fn json_array(values: impl Iterator<Item = String>) -> String {
+    format!("[{}]", values.collect::<Vec<_>>().join(","))
+}
+
+/// The fields of a body, in the order they appear in a CSV row.
+fn csv_fields(body: &ExportedBody) -> Vec<String> {
+    match body {
+        ExportedBody::Pose(pose) => vec![
+            pose.sensor.0.to_string(),
+            pose.pos.x.to_string(),
+            pose.pos.y.to_string(),
+            pose.pos.z.to_string(),
+            pose.quat.v.x.to_string(),
+            pose.quat.v.y.to_string(),
+            pose.quat.v.z.to_string(),
+            pose.quat.s.to_string(),
+        ],
+        ExportedBody::ButtonChange(report) => report
+            .changes
+            .iter()
+            .flat_map(|change| {
+                let pressed: bool = change.state.into();
+                vec![change.button.0.to_string(), (pressed as i32).to_string()]
+            })
+            .collect(),
+        ExportedBody::ButtonStates(report) => report
+            .states
+            .iter()
+            .map(|&state| (bool::from(state) as i32).to_string())
+            .collect(),
+        ExportedBody::Analog(report) => report.channels.iter().map(f64::to_string).collect(),
+        ExportedBody::Unknown(body) => vec![hex(body)],
+    }
+}
+
+/// The fields of a body, as members of a JSON object.
+fn json_fields(body: &ExportedBody) -> String {
+    match body {
+        ExportedBody::Pose(pose) => format!(
+            "\"sensor\":{},\"pos\":{},\"quat\":{}",
+            pose.sensor.0,
+            json_array(
+                [pose.pos.x, pose.pos.y, pose.pos.z]
+                    .iter()
+                    .map(|&v| json_number(v))
+            ),
+            json_array(
+                [pose.quat.v.x, pose.quat.v.y, pose.quat.v.z, pose.quat.s]
+                    .iter()
+                    .map(|&v| json_number(v))
+            ),
+        ),
+        ExportedBody::ButtonChange(report) => format!(
+            "\"changes\":{}",
+            json_array(report.changes.iter().map(|change| format!(
+                "{{\"button\":{},\"pressed\":{}}}",
+                change.button.0,
+                bool::from(change.state)
+            )))
+        ),
+        ExportedBody::ButtonStates(report) => format!(
+            "\"pressed\":{}",
+            json_array(
+                report
+                    .states
+                    .iter()
+                    .map(|&state| bool::from(state).to_string())
+            )
+        ),
+        ExportedBody::Analog(report) => format!(
+            "\"channels\":{}",
+            json_array(report.channels.iter().map(|&v| json_number(v)))
+        ),
+        ExportedBody::Unknown(body) => format!("\"body\":\"{}\"", hex(body)),
+    }
+}
+
+/// Writes messages to a sink in one of the export formats.
+#[derive(Debug)]
+pub struct Exporter<W: Write> {
+    sink: W,
+    format: ExportFormat,
+}
+
+impl<W: Write> Exporter<W> {
+    /// Start exporting to `sink`, writing the header row for CSV.
+    pub fn new(mut sink: W, format: ExportFormat) -> Result<Exporter<W>> {
+        if format == ExportFormat::Csv {
+            writeln!(sink, "time,sender,type,fields")?;
+        }
+        Ok(Exporter { sink, format })
+    }
+
+    /// Write a message that has already been decoded.
+    pub fn write(
+        &mut self,
+        time: TimeVal,
+        sender: &SenderName,
+        message_type: &MessageTypeName,
+        body: &ExportedBody,
+    ) -> Result<()> {
+        let sender = String::from_utf8_lossy(&sender.0);
+        let message_type = String::from_utf8_lossy(&message_type.0);
+        match self.format {
+            ExportFormat::Csv => {
+                let mut row = vec![
+                    format_time(time),
+                    csv_field(&sender),
+                    csv_field(&message_type),
+                ];
+                row.extend(csv_fields(body));
+                writeln!(self.sink, "{}", row.join(","))?;
+            }
+            ExportFormat::JsonLines => writeln!(
+                self.sink,
+                "{{\"time\":{},\"sender\":{},\"type\":{},{}}}",
+                format_time(time),
+                json_string(&sender),
+                json_string(&message_type),
+                json_fields(body)
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Decode and write a message, given the names of its sender and type.
+    pub fn export_message(
+        &mut self,
+        msg: &GenericMessage,
+        sender: &SenderName,
+        message_type: &MessageTypeName,
+    ) -> Result<()> {
+        let body = ExportedBody::decode(msg, message_type);
+        self.write(msg.header.time, sender, message_type, &body)
+    }
+
+    /// Decode and write a message read from a log.
+    ///
+    /// System messages, and messages with IDs not described in the log, are skipped.
+    pub fn export_entry(&mut self, entry: &LogEntry) -> Result<()> {
+        match (&entry.sender, &entry.message_type) {
+            (Some(sender), Some(message_type)) => {
+                self.export_message(&entry.message, sender, message_type)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure everything exported so far has reached the sink.
+    pub fn flush(&mut self) -> Result<()> {
+        self.sink.flush()?;
+        Ok(())
+    }
+}
+
+/// Exports every message a connection receives, named as its peer described them.
+///
+/// Messages the connection sends itself are not exported.
+#[derive(Debug)]
+pub struct ExportHandler<C: Connection + 'static, W: Write + Send + Sync + 'static> {
+    connection: Weak<C>,
+    exporter: Exporter<W>,
+}
+
+impl<C: Connection + 'static, W: Write + Send + Sync + 'static> ExportHandler<C, W> {
+    /// Add a handler to `connection` that exports everything it receives.
+    pub fn add(connection: &Arc<C>, exporter: Exporter<W>) -> Result<HandlerHandle> {
+        connection.add_handler(
+            Box::new(ExportHandler {
+                connection: Arc::downgrade(connection),
+                exporter,
+            }),
+            None,
+            None,
+        )
+    }
+}
+
+impl<C: Connection + 'static, W: Write + Send + Sync + 'static> Handler for ExportHandler<C, W> {
+    fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+        let connection = match self.connection.upgrade() {
+            Some(connection) => connection,
+            None => return Ok(HandlerCode::RemoveThisHandler),
+        };
+        // Handlers run with the endpoints unlocked.
+        let names = {
+            let endpoints = connection.endpoints();
+            let endpoints = endpoints.lock()?;
+            endpoints
+                .iter()
+                .flatten()
+                .find_map(|endpoint| endpoint.translation_tables().find_names(&msg.header))
+        };
+        // Skip our own connection events: they were not received from anyone.
+        let names = names.filter(|(sender, _)| sender.0 != constants::CONTROL.0);
+        if let Some((sender, message_type)) = names {
+            self.exporter.export_message(msg, &sender, &message_type)?;
+            self.exporter.flush()?;
+        }
+        Ok(HandlerCode::ContinueProcessing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        button::{ButtonChange, ButtonState},
+        data_types::{
+            id_types::{ButtonId, LocalId, MessageTypeId, SenderId, Sensor},
+            GenericBody, MessageHeader, Quat, Vec3,
+        },
+    };
+    use std::time::Duration;
+
+    fn export(format: ExportFormat, message_type: &'static [u8], body: ExportedBody) -> String {
+        let mut exporter = Exporter::new(Vec::new(), format).unwrap();
+        let time = TimeVal::from(SystemTime::UNIX_EPOCH + Duration::from_micros(1_500_000));
+        exporter
+            .write(
+                time,
+                &SenderName(Bytes::from_static(b"Tracker0")),
+                &MessageTypeName(Bytes::from_static(message_type)),
+                &body,
+            )
+            .unwrap();
+        String::from_utf8(exporter.sink).unwrap()
+    }
+
+    fn pose() -> ExportedBody {
+        ExportedBody::Pose(PoseReport {
+            sensor: Sensor(1),
+            pos: Vec3::new(1.0, 2.0, 3.5),
+            quat: Quat::from_sv(1.0, Vec3::new(0.0, 0.0, 0.0)),
+        })
+    }
+
+    #[test]
+    fn pose_csv() {
+        assert_eq!(
+            export(ExportFormat::Csv, b"vrpn_Tracker Pos_Quat", pose()),
+            "time,sender,type,fields\n\
+             1.500000,Tracker0,vrpn_Tracker Pos_Quat,1,1,2,3.5,0,0,0,1\n"
+        );
+    }
+
+    #[test]
+    fn pose_json() {
+        assert_eq!(
+            export(ExportFormat::JsonLines, b"vrpn_Tracker Pos_Quat", pose()),
+            "{\"time\":1.500000,\"sender\":\"Tracker0\",\"type\":\"vrpn_Tracker Pos_Quat\",\
+             \"sensor\":1,\"pos\":[1,2,3.5],\"quat\":[0,0,0,1]}\n"
+        );
+    }
+
+    #[test]
+    fn buttons_and_analogs() {
+        let change = ExportedBody::ButtonChange(ButtonChangeReport {
+            changes: vec![ButtonChange::new(ButtonId(2), ButtonState::Pressed)],
+        });
+        assert!(
+            export(ExportFormat::Csv, b"vrpn_Button Change", change.clone())
+                .ends_with(",vrpn_Button Change,2,1\n")
+        );
+        assert!(
+            export(ExportFormat::JsonLines, b"vrpn_Button Change", change)
+                .ends_with("\"changes\":[{\"button\":2,\"pressed\":true}]}\n")
+        );
+
+        let analog = ExportedBody::Analog(AnalogReport::new(vec![0.5, f64::NAN]));
+        assert!(
+            export(ExportFormat::Csv, b"vrpn_Analog Channel", analog.clone())
+                .ends_with(",0.5,NaN\n")
+        );
+        assert!(
+            export(ExportFormat::JsonLines, b"vrpn_Analog Channel", analog)
+                .ends_with("\"channels\":[0.5,null]}\n")
+        );
+    }
+
+    #[test]
+    fn unknown_as_hex() {
+        let msg = GenericMessage {
+            header: MessageHeader::new(None, LocalId(MessageTypeId(0)), LocalId(SenderId(0))),
+            body: GenericBody::new(Bytes::from_static(b"\x01\xab")),
+        };
+        let message_type = MessageTypeName(Bytes::from_static(b"Mystery, Inc."));
+        let body = ExportedBody::decode(&msg, &message_type);
+        assert_eq!(body, ExportedBody::Unknown(Bytes::from_static(b"\x01\xab")));
+        assert!(export(ExportFormat::Csv, b"Mystery, Inc.", body.clone())
+            .ends_with(",\"Mystery, Inc.\",01ab\n"));
+        assert!(export(ExportFormat::JsonLines, b"Mystery, Inc.", body)
+            .ends_with("\"body\":\"01ab\"}\n"));
+    }
+
+    #[test]
+    fn decode_known_types() {
+        let msg = GenericMessage::try_from(TypedMessage::new(
+            None,
+            LocalId(MessageTypeId(0)),
+            LocalId(SenderId(0)),
+            AnalogReport::new(vec![1.0, 2.0]),
+        ))
+        .unwrap();
+        let analog = MessageTypeName(Bytes::from_static(b"vrpn_Analog Channel"));
+        assert_eq!(
+            ExportedBody::decode(&msg, &analog),
+            ExportedBody::Analog(AnalogReport::new(vec![1.0, 2.0]))
+        );
+        // Named as a pose, but doesn't decode as one.
+        let pose = MessageTypeName(Bytes::from_static(b"vrpn_Tracker Pos_Quat"));
+        assert!(matches!(
+            ExportedBody::decode(&msg, &pose),
+            ExportedBody::Unknown(_)
+        ));
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Messages and a client for controlling log-file playback, like mainline's `vrpn_File_Controller`.
+//!
+//! Times in these messages are measured from the first message in the file.
+
+use crate::{
+    buffer_unbuffer::{BufferTo, EmptyMessage, WrappedConstantSize},
+    data_types::{
+        id_types::{LocalId, SenderId},
+        ClassOfService, MessageTypeIdentifier, StaticMessageTypeName, StaticSenderName, TimeVal,
+        TypedMessageBody,
+    },
+    Connection, Result,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// The sender name used for file controller messages.
+pub const FILE_CONTROLLER: StaticSenderName = StaticSenderName(b"vrpn File Controller");
+
+// A relative time goes on the wire as a timeval counted from zero.
+fn timeval_from_elapsed(elapsed: Duration) -> TimeVal {
+    TimeVal::from(SystemTime::UNIX_EPOCH + elapsed)
+}
+
+fn elapsed_from_timeval(time: TimeVal) -> Duration {
+    SystemTime::from(time)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Change the speed of playback: 1.0 is the recorded speed, 0 pauses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SetReplayRate {
+    pub rate: f32,
+}
+
+impl WrappedConstantSize for SetReplayRate {
+    type WrappedType = f32;
+    fn get(&self) -> Self::WrappedType {
+        self.rate
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        SetReplayRate { rate: v }
+    }
+}
+
+impl TypedMessageBody for SetReplayRate {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_File set_replay_rate"));
+}
+
+/// Go back to the start of the file.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Reset;
+
+impl EmptyMessage for Reset {}
+impl TypedMessageBody for Reset {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_File reset"));
+}
+
+/// Immediately play every message up to a time, then carry on from there.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PlayToTime {
+    pub elapsed: TimeVal,
+}
+
+impl PlayToTime {
+    pub fn new(elapsed: Duration) -> PlayToTime {
+        PlayToTime {
+            elapsed: timeval_from_elapsed(elapsed),
+        }
+    }
+
+    /// Get the target time as a duration since the start of the file.
+    pub fn elapsed(&self) -> Duration {
+        elapsed_from_timeval(self.elapsed)
+    }
+}
+
+impl WrappedConstantSize for PlayToTime {
+    type WrappedType = TimeVal;
+    fn get(&self) -> Self::WrappedType {
+        self.elapsed
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        PlayToTime { elapsed: v }
+    }
+}
+
+impl TypedMessageBody for PlayToTime {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_File play_to_time"));
+}
+
+/// Skip to a time without playing the messages in between.
+///
+/// Mainline only offers this as a method on its file connection,
+/// so this message is an addition of ours.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct JumpToTime {
+    pub elapsed: TimeVal,
+}
+
+impl JumpToTime {
+    pub fn new(elapsed: Duration) -> JumpToTime {
+        JumpToTime {
+            elapsed: timeval_from_elapsed(elapsed),
+        }
+    }
+
+    /// Get the target time as a duration since the start of the file.
+    pub fn elapsed(&self) -> Duration {
+        elapsed_from_timeval(self.elapsed)
+    }
+}
+
+impl WrappedConstantSize for JumpToTime {
+    type WrappedType = TimeVal;
+    fn get(&self) -> Self::WrappedType {
+        self.elapsed
+    }
+    fn new(v: Self::WrappedType) -> Self {
+        JumpToTime { elapsed: v }
+    }
+}
+
+impl TypedMessageBody for JumpToTime {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_File jump_to_time"));
+}
+
+/// Sends file controller messages on a connection playing back a log file.
+#[derive(Debug)]
+pub struct Controller<T: Connection + 'static> {
+    connection: Arc<T>,
+    sender: LocalId<SenderId>,
+}
+
+impl<T: Connection + 'static> Controller<T> {
+    pub fn new(connection: Arc<T>) -> Result<Controller<T>> {
+        let sender = connection.register_sender(FILE_CONTROLLER)?;
+        Ok(Controller { connection, sender })
+    }
+
+    pub fn set_replay_rate(&self, rate: f32) -> Result<()> {
+        self.send(SetReplayRate { rate })
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        self.send(Reset)
+    }
+
+    pub fn play_to_time(&self, elapsed: Duration) -> Result<()> {
+        self.send(PlayToTime::new(elapsed))
+    }
+
+    pub fn jump_to_time(&self, elapsed: Duration) -> Result<()> {
+        self.send(JumpToTime::new(elapsed))
+    }
+
+    fn send<U: TypedMessageBody + BufferTo>(&self, body: U) -> Result<()> {
+        self.connection
+            .pack_message_body(None, self.sender, body, ClassOfService::RELIABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_unbuffer::{BytesMutExtras, UnbufferFrom};
+    use bytes::BytesMut;
+
+    #[test]
+    fn elapsed_roundtrip() {
+        let elapsed = Duration::from_millis(12_345);
+        assert_eq!(PlayToTime::new(elapsed).elapsed(), elapsed);
+        assert_eq!(JumpToTime::new(elapsed).elapsed(), elapsed);
+    }
+
+    #[test]
+    fn replay_rate_roundtrip() {
+        let msg = SetReplayRate { rate: 2.5 };
+        let mut buf = BytesMut::allocate_and_buffer(msg).unwrap().freeze();
+        assert_eq!(buf.len(), 4);
+        assert_eq!(SetReplayRate::unbuffer_from(&mut buf).unwrap(), msg);
+    }
+}
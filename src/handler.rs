@@ -5,10 +5,22 @@
 pub use crate::type_dispatcher::HandlerHandle;
 use crate::{
     buffer_unbuffer::{EmptyMessage, UnbufferFrom},
-    data_types::{GenericMessage, MessageHeader, TypedMessage, TypedMessageBody},
-    Result,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        GenericMessage, MessageHeader, TypedMessage, TypedMessageBody,
+    },
+    Connection, Result, VrpnError,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    Future, Stream,
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
 };
-use std::{convert::TryFrom, fmt};
 
 /// Return from a Handler (or its related traits),
 /// indicating whether the handler that just executed should be kept around for the future.
@@ -21,6 +33,10 @@ pub enum HandlerCode {
 }
 
 /// A trait implemented by structs that can handle generic messages
+///
+/// Handlers may pack messages (such as replies) on their connection,
+/// but the type dispatcher is locked while they run, so any types or senders
+/// they need must be registered ahead of time.
 pub trait Handler: Send + Sync {
     fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode>;
 }
@@ -66,3 +82,119 @@ where
         self.handle_typed_bodyless(&msg.header)
     }
 }
+
+/// A future resolving to the first message of type `T` received after
+/// calling `add_reply_handler()`.
+///
+/// The connection must keep being polled elsewhere for the reply to arrive.
+#[derive(Debug)]
+pub struct Reply<T: TypedMessageBody> {
+    rx: oneshot::Receiver<TypedMessage<T>>,
+}
+
+impl<T: TypedMessageBody> Future for Reply<T> {
+    type Output = Result<TypedMessage<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().rx)
+            .poll(cx)
+            .map_err(|_| VrpnError::ReplyCanceled)
+    }
+}
+
+/// A stream of all messages of type `T` received after calling `add_reply_stream_handler()`.
+///
+/// Dropping the stream removes the handler the next time a matching message arrives.
+/// The connection must keep being polled elsewhere for messages to arrive.
+#[derive(Debug)]
+pub struct ReplyStream<T: TypedMessageBody> {
+    rx: mpsc::UnboundedReceiver<TypedMessage<T>>,
+}
+
+impl<T: TypedMessageBody> Stream for ReplyStream<T> {
+    type Item = TypedMessage<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().rx).poll_next(cx)
+    }
+}
+
+struct ReplyHandler<T: TypedMessageBody> {
+    tx: Option<oneshot::Sender<TypedMessage<T>>>,
+}
+
+impl<T: TypedMessageBody> fmt::Debug for ReplyHandler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplyHandler").finish()
+    }
+}
+
+impl<T> TypedHandler for ReplyHandler<T>
+where
+    T: TypedMessageBody + UnbufferFrom + Clone + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        if let Some(tx) = self.tx.take() {
+            // The receiver may have been dropped: that's OK.
+            let _ = tx.send(msg.clone());
+        }
+        Ok(HandlerCode::RemoveThisHandler)
+    }
+}
+
+struct ReplyStreamHandler<T: TypedMessageBody> {
+    tx: mpsc::UnboundedSender<TypedMessage<T>>,
+}
+
+impl<T: TypedMessageBody> fmt::Debug for ReplyStreamHandler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReplyStreamHandler").finish()
+    }
+}
+
+impl<T> TypedHandler for ReplyStreamHandler<T>
+where
+    T: TypedMessageBody + UnbufferFrom + Clone + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        match self.tx.unbounded_send(msg.clone()) {
+            Ok(()) => Ok(HandlerCode::ContinueProcessing),
+            // Nobody is listening anymore.
+            Err(_) => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// Add a handler that captures the next message of type `T`, with an optional filter on sender.
+///
+/// Add this before sending the request that triggers the reply.
+/// The returned future resolves once the connection dispatches that message.
+pub fn add_reply_handler<T, C>(
+    connection: &C,
+    sender_filter: Option<LocalId<SenderId>>,
+) -> Result<Reply<T>>
+where
+    T: TypedMessageBody + UnbufferFrom + Clone + Send + Sync + 'static,
+    C: Connection,
+{
+    let (tx, rx) = oneshot::channel();
+    connection.add_typed_handler(Box::new(ReplyHandler { tx: Some(tx) }), sender_filter)?;
+    Ok(Reply { rx })
+}
+
+/// Add a handler that forwards all messages of type `T` to a stream,
+/// with an optional filter on sender.
+pub fn add_reply_stream_handler<T, C>(
+    connection: &C,
+    sender_filter: Option<LocalId<SenderId>>,
+) -> Result<ReplyStream<T>>
+where
+    T: TypedMessageBody + UnbufferFrom + Clone + Send + Sync + 'static,
+    C: Connection,
+{
+    let (tx, rx) = mpsc::unbounded();
+    connection.add_typed_handler(Box::new(ReplyStreamHandler { tx }), sender_filter)?;
+    Ok(ReplyStream { rx })
+}
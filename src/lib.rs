@@ -30,10 +30,12 @@ extern crate tokio;
 #[cfg(feature = "async-tokio")]
 pub mod vrpn_tokio;
 
-#[cfg(feature = "async-std")]
+#[cfg(feature = "vrpn-async-std")]
 pub mod vrpn_async_std;
 
+pub mod analog;
 pub mod buffer_unbuffer;
+pub mod button;
 pub mod data_types;
 
 mod codec;
@@ -41,24 +43,36 @@ pub mod connection;
 pub mod constants;
 pub mod endpoint;
 pub mod error;
+pub mod export;
+pub mod file_controller;
 pub mod handler;
+pub mod log_file;
+pub mod loopback;
 mod name_registration;
 mod parse_name;
 pub mod ping;
 #[deprecated]
 pub mod prelude;
+pub mod protocol;
+pub mod remote;
+pub mod server;
+pub mod sync_connection;
 pub mod sync_io;
 pub mod tracker;
 pub mod translation_table;
 pub mod type_dispatcher;
+#[cfg(feature = "vrpn-async-std")]
 pub mod vrpn_async;
 
 pub use crate::{
-    connection::{Connection, ConnectionStatus},
+    connection::{
+        ClientConnection, Connection, ConnectionStatus, DroppedConnection, DroppedLastConnection,
+        GotConnection, GotFirstConnection, ReconnectPolicy,
+    },
     endpoint::*,
     error::{Result, VrpnError},
     handler::{Handler, TypedBodylessHandler, TypedHandler},
-    parse_name::{Scheme, ServerInfo},
+    parse_name::{DeviceInfo, Scheme, ServerInfo},
     type_dispatcher::{RegisterMapping, TypeDispatcher},
 };
 
@@ -0,0 +1,1058 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! Recording connections to log files, and reading them back without a runtime.
+//!
+//! A log file is the file magic cookie followed by one record per message, laid out
+//! as mainline's `vrpn_Log` writes them, so mainline can play them back and vice versa.
+//! Each record is a fixed-size header, then the unpadded message body.
+//! The sender and type IDs are as they were on the wire: the description messages
+//! recorded along with everything else say what the IDs mean.
+//! Records carry no sequence numbers.
+
+use crate::{
+    buffer_unbuffer::{BufferSize, UnbufferFrom},
+    data_types::{
+        cookie::check_ver_file_compatible,
+        id_types::{LocalId, MessageTypeId, RemoteId, SenderId},
+        CookieData, GenericBody, GenericMessage, IdWithNameAndDescription, Message, MessageHeader,
+        MessageTypeName, Microseconds, Seconds, SenderName, TimeVal,
+    },
+    endpoint::{parse_system_message, SystemCommand},
+    sync_io::{read_cookie, write_cookie},
+    translation_table::TranslationTable,
+    type_dispatcher::TryIntoDescriptionMessage,
+    RegisterMapping, Result, TranslationTables, TypeDispatcher, VrpnError,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+use crate::data_types::{LogFileNames, LogMode};
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+use std::path::{Component, PathBuf};
+
+/// How much to read from the source at a time.
+const READ_CHUNK: usize = 4096;
+
+/// Size of the header before each message body in a log file.
+///
+/// Mainline writes its `vrpn_HANDLERPARAM` struct as it is in memory, with the fields
+/// in network byte order: this is its size with the 64-bit `long`s and pointers
+/// of Linux and macOS. Logs written by 32-bit or Windows builds of mainline differ.
+const RECORD_HEADER_SIZE: usize = 40;
+
+/// Size of a message as a record in a log file.
+pub(crate) fn record_size(msg: &GenericMessage) -> usize {
+    RECORD_HEADER_SIZE + msg.body.buffer_size()
+}
+
+/// Append a message to `buf` as a log record.
+fn buffer_record(msg: &GenericMessage, buf: &mut BytesMut) -> Result<()> {
+    let len = i32::try_from(msg.body.buffer_size())
+        .map_err(|_| VrpnError::OtherMessage("Message too large to log".to_string()))?;
+    buf.reserve(record_size(msg));
+    buf.put_i32(msg.header.message_type.0);
+    buf.put_i32(msg.header.sender.0);
+    // Each half of the timeval is a `long`, holding a 32-bit value in network order.
+    buf.put_i32(msg.header.time.seconds().0);
+    buf.put_u32(0);
+    buf.put_i32(msg.header.time.microseconds().0);
+    buf.put_u32(0);
+    buf.put_i32(len);
+    // Padding, then the pointer to the body, which means nothing on disk.
+    buf.put_u32(0);
+    buf.put_u64(0);
+    buf.put(msg.body.clone().into_inner());
+    Ok(())
+}
+
+/// Decode the log record at the front of `buf`, if all of it is there.
+///
+/// Leaves `buf` untouched if not.
+pub(crate) fn maybe_decode_record(buf: &mut BytesMut) -> Result<Option<GenericMessage>> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut header = &buf[..RECORD_HEADER_SIZE];
+    let message_type = MessageTypeId(header.get_i32());
+    let sender = SenderId(header.get_i32());
+    let sec = header.get_i32();
+    header.advance(4);
+    let usec = header.get_i32();
+    header.advance(4);
+    let len = header.get_i32();
+    let len = usize::try_from(len).map_err(|_| {
+        VrpnError::OtherMessage(format!("Log record has a negative body size {}", len))
+    })?;
+    if buf.len() < RECORD_HEADER_SIZE + len {
+        return Ok(None);
+    }
+    buf.advance(RECORD_HEADER_SIZE);
+    let body = buf.split_to(len).freeze();
+    let time = TimeVal::new(Seconds(sec), Microseconds(usec));
+    Ok(Some(GenericMessage {
+        header: MessageHeader::new(Some(time), message_type, sender),
+        body: GenericBody::new(body),
+    }))
+}
+
+/// Writes messages to a log file, after its cookie.
+#[derive(Debug)]
+pub struct LogWriter<W: Write> {
+    sink: W,
+}
+
+impl LogWriter<BufWriter<File>> {
+    /// Create (or truncate) a log file and write its cookie.
+    pub fn create(path: impl AsRef<Path>) -> Result<LogWriter<BufWriter<File>>> {
+        LogWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    /// Start a log in `sink`, writing the file cookie.
+    pub fn new(mut sink: W) -> Result<LogWriter<W>> {
+        write_cookie(&mut sink, CookieData::make_file_cookie())?;
+        Ok(LogWriter { sink })
+    }
+
+    /// Append a message.
+    pub fn write_message(&mut self, msg: &GenericMessage) -> Result<()> {
+        let mut buf = BytesMut::new();
+        buffer_record(msg, &mut buf)?;
+        self.sink.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Make sure everything logged so far has reached the sink.
+    pub fn flush(&mut self) -> Result<()> {
+        self.sink.flush()?;
+        Ok(())
+    }
+}
+
+/// A message read from a log, with the names its sender and type were described with.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogEntry {
+    pub message: GenericMessage,
+    /// `None` for system messages, and for IDs not (yet) described in the log.
+    pub sender: Option<SenderName>,
+    /// `None` for system messages, and for IDs not (yet) described in the log.
+    pub message_type: Option<MessageTypeName>,
+}
+
+/// Reads the messages in a log, from any source, in the order they were recorded.
+///
+/// Every message is yielded, system messages included: description messages update
+/// the names given to the messages that follow them.
+#[derive(Debug)]
+pub struct LogReader<R: Read> {
+    source: R,
+    buf: BytesMut,
+    senders: HashMap<SenderId, SenderName>,
+    types: HashMap<MessageTypeId, MessageTypeName>,
+    done: bool,
+}
+
+impl LogReader<File> {
+    /// Open a log file and check its cookie.
+    pub fn open(path: impl AsRef<Path>) -> Result<LogReader<File>> {
+        LogReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    /// Start reading a log from `source`, checking the file cookie.
+    pub fn new(mut source: R) -> Result<LogReader<R>> {
+        let cookie = CookieData::unbuffer_from(&mut Bytes::from(read_cookie(&mut source)?))?;
+        check_ver_file_compatible(cookie.version)?;
+        Ok(LogReader {
+            source,
+            buf: BytesMut::new(),
+            senders: HashMap::new(),
+            types: HashMap::new(),
+            done: false,
+        })
+    }
+
+    /// The name a sender ID has been described with so far in the log.
+    pub fn sender_name(&self, id: SenderId) -> Option<&SenderName> {
+        self.senders.get(&id)
+    }
+
+    /// The name a message type ID has been described with so far in the log.
+    pub fn type_name(&self, id: MessageTypeId) -> Option<&MessageTypeName> {
+        self.types.get(&id)
+    }
+
+    /// Read the next message, or `None` at the end of the log.
+    pub fn read_message(&mut self) -> Result<Option<GenericMessage>> {
+        loop {
+            if let Some(msg) = maybe_decode_record(&mut self.buf)? {
+                return Ok(Some(msg));
+            }
+            let len = self.buf.len();
+            self.buf.resize(len + READ_CHUNK, 0);
+            let n = self.source.read(&mut self.buf[len..])?;
+            self.buf.truncate(len + n);
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(VrpnError::OtherMessage(format!(
+                    "Log ends with a partial message of {} bytes",
+                    self.buf.len()
+                )));
+            }
+        }
+    }
+
+    /// Read the next message and resolve its names.
+    pub fn read_entry(&mut self) -> Result<Option<LogEntry>> {
+        let message = match self.read_message()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let header = &message.header;
+        if message.is_system_message() {
+            self.note_description(&message)?;
+            return Ok(Some(LogEntry {
+                message,
+                sender: None,
+                message_type: None,
+            }));
+        }
+        let sender = self.senders.get(&header.sender).cloned();
+        let message_type = self.types.get(&header.message_type).cloned();
+        Ok(Some(LogEntry {
+            message,
+            sender,
+            message_type,
+        }))
+    }
+
+    fn note_description(&mut self, msg: &GenericMessage) -> Result<()> {
+        match parse_system_message(msg.clone())? {
+            SystemCommand::SenderDescription(desc) => {
+                let _ = self.senders.insert(desc.which, SenderName(desc.name));
+            }
+            SystemCommand::TypeDescription(desc) => {
+                let _ = self.types.insert(desc.which, MessageTypeName(desc.name));
+            }
+            SystemCommand::Extended(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<LogEntry>;
+
+    /// Ends after the end of the log, or after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_entry().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Writes messages read from any number of logs into one, giving their senders and types
+/// fresh IDs in the output: each is described there before its first use.
+#[derive(Debug)]
+pub struct LogMerger<W: Write> {
+    writer: LogWriter<W>,
+    /// Allocates the IDs in the output.
+    dispatcher: TypeDispatcher,
+    /// For each input, maps IDs as recorded there to IDs in the output.
+    inputs: Vec<TranslationTables>,
+}
+
+/// Find the output ID for an ID from an input, registering its name on first use.
+///
+/// Also returns the description to write, if the output ID is a new one.
+fn renumber<I>(
+    table: &mut TranslationTable<I>,
+    id: I,
+    name: &Bytes,
+    register: impl FnOnce() -> Result<RegisterMapping<I>>,
+) -> Result<(LocalId<I>, Option<GenericMessage>)>
+where
+    I: IdWithNameAndDescription,
+{
+    if let Ok(Some(local_id)) = table.map_to_local_id(RemoteId(id)) {
+        return Ok((local_id, None));
+    }
+    let (local_id, description) = match register()? {
+        RegisterMapping::Found(local_id) => (local_id, None),
+        RegisterMapping::NewMapping(local_id) => (
+            local_id,
+            Some(local_id.try_into_description_message(name.clone())?),
+        ),
+    };
+    let _ = table.add_remote_entry(name.clone(), RemoteId(id), local_id)?;
+    Ok((local_id, description))
+}
+
+impl<W: Write> LogMerger<W> {
+    /// Start the merged log, describing the system sender and types first as a connection would.
+    pub fn new(mut writer: LogWriter<W>) -> Result<LogMerger<W>> {
+        let dispatcher = TypeDispatcher::new();
+        for msg in dispatcher.pack_all_descriptions()? {
+            writer.write_message(&msg)?;
+        }
+        Ok(LogMerger {
+            writer,
+            dispatcher,
+            inputs: Vec::new(),
+        })
+    }
+
+    /// Add an input log, returning the index to write its messages with.
+    pub fn add_input(&mut self) -> usize {
+        self.inputs.push(TranslationTables::new());
+        self.inputs.len() - 1
+    }
+
+    /// Write a user message from an input, with the names its IDs have there.
+    pub fn write_message(
+        &mut self,
+        input: usize,
+        msg: GenericMessage,
+        sender: &SenderName,
+        message_type: &MessageTypeName,
+    ) -> Result<()> {
+        let dispatcher = &mut self.dispatcher;
+        let tables = self
+            .inputs
+            .get_mut(input)
+            .ok_or_else(|| VrpnError::OtherMessage(format!("No input {}", input)))?;
+        let (sender_id, sender_desc) =
+            renumber(tables.as_mut(), msg.header.sender, &sender.0, || {
+                dispatcher.register_sender(sender.clone())
+            })?;
+        let (type_id, type_desc) = renumber(
+            tables.as_mut(),
+            msg.header.message_type,
+            &message_type.0,
+            || dispatcher.register_type(message_type.clone()),
+        )?;
+        // Describe new IDs as of when they are first used.
+        for mut desc in sender_desc.into_iter().chain(type_desc) {
+            desc.header.time = msg.header.time;
+            self.writer.write_message(&desc)?;
+        }
+        let mut msg = msg;
+        msg.header.sender = sender_id.0;
+        msg.header.message_type = type_id.0;
+        self.writer.write_message(&msg)
+    }
+
+    /// Make sure everything merged so far has reached the output.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// What to keep when merging logs with `MergeOptions::merge`.
+///
+/// Times are measured from the first message in any of the inputs.
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Drop messages recorded before this long after the first one.
+    pub start: Option<Duration>,
+    /// Drop messages recorded after this long after the first one.
+    pub end: Option<Duration>,
+    /// Drop messages from these senders.
+    pub drop_senders: Vec<SenderName>,
+    /// Drop messages of these types.
+    pub drop_types: Vec<MessageTypeName>,
+    /// Shift times so the first message kept was recorded at this time,
+    /// keeping the spacing of the rest.
+    pub rebase: Option<SystemTime>,
+}
+
+impl MergeOptions {
+    /// Whether a message recorded `elapsed` after the first one falls in the time window.
+    fn in_window(&self, elapsed: Duration) -> bool {
+        !matches!(self.start, Some(start) if elapsed < start)
+            && !matches!(self.end, Some(end) if elapsed > end)
+    }
+
+    /// Whether messages with these names are kept.
+    fn keeps(&self, sender: &SenderName, message_type: &MessageTypeName) -> bool {
+        !self.drop_senders.contains(sender) && !self.drop_types.contains(message_type)
+    }
+
+    /// Merge the user messages of `inputs` into `output` in time order,
+    /// keeping those these options select.
+    ///
+    /// Returns how many messages were kept.
+    pub fn merge<R: Read, W: Write>(
+        &self,
+        inputs: Vec<LogReader<R>>,
+        output: LogWriter<W>,
+    ) -> Result<usize> {
+        let mut merger = LogMerger::new(output)?;
+        let mut inputs = inputs
+            .into_iter()
+            .map(|reader| {
+                let mut input = MergeInput {
+                    reader,
+                    index: merger.add_input(),
+                    next: None,
+                };
+                input.advance()?;
+                Ok(input)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let origin = match inputs.iter().filter_map(MergeInput::next_time).min() {
+            Some(origin) => origin,
+            None => return merger.flush().map(|()| 0),
+        };
+        let mut first_kept = None;
+        let mut kept = 0;
+        // Take the earliest message from any input, until they are all used up.
+        while let Some(input) = inputs
+            .iter_mut()
+            .filter(|input| input.next.is_some())
+            .min_by_key(|input| input.next_time())
+        {
+            let entry = input.next.take().unwrap();
+            let time = SystemTime::from(entry.message.header.time);
+            let elapsed = time.duration_since(origin).unwrap_or_default();
+            let (sender, message_type) = (entry.sender.unwrap(), entry.message_type.unwrap());
+            if self.in_window(elapsed) && self.keeps(&sender, &message_type) {
+                let mut msg = entry.message;
+                if let Some(rebase) = self.rebase {
+                    // The rest keep their spacing from the first message kept.
+                    let first = *first_kept.get_or_insert(time);
+                    let offset = time.duration_since(first).unwrap_or_default();
+                    msg.header.time = TimeVal::from(rebase + offset);
+                }
+                merger.write_message(input.index, msg, &sender, &message_type)?;
+                kept += 1;
+            }
+            input.advance()?;
+        }
+        merger.flush()?;
+        Ok(kept)
+    }
+}
+
+/// An input log being merged, read one user message ahead.
+struct MergeInput<R: Read> {
+    reader: LogReader<R>,
+    /// Which input this is to the merger.
+    index: usize,
+    next: Option<LogEntry>,
+}
+
+impl<R: Read> MergeInput<R> {
+    /// Read ahead to the next user message.
+    ///
+    /// System messages are skipped: the merger describes IDs itself,
+    /// and the rest only made sense on the original connection.
+    /// So are messages with IDs never described, which could not be renumbered.
+    fn advance(&mut self) -> Result<()> {
+        self.next = None;
+        while let Some(entry) = self.reader.read_entry()? {
+            if entry.sender.is_some() && entry.message_type.is_some() {
+                self.next = Some(entry);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_time(&self) -> Option<SystemTime> {
+        self.next
+            .as_ref()
+            .map(|entry| SystemTime::from(entry.message.header.time))
+    }
+}
+
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+fn log_path(name: &Bytes) -> Result<&Path> {
+    std::str::from_utf8(name)
+        .map(Path::new)
+        .map_err(|e| VrpnError::OtherMessage(format!("Log file name is not UTF-8: {}", e)))
+}
+
+/// Resolve a log file name a peer asked for inside `dir`,
+/// refusing absolute names and any that climb out with `..`.
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+fn requested_log_path(dir: &Path, name: &Bytes) -> Result<PathBuf> {
+    let path = log_path(name)?;
+    let mut components = path.components();
+    let contained = components.all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !contained || path.file_name().is_none() {
+        return Err(VrpnError::OtherMessage(format!(
+            "Refusing log file name {:?}: it must be a relative path without '..'",
+            path
+        )));
+    }
+    Ok(dir.join(path))
+}
+
+/// The logs kept by one endpoint: incoming, outgoing, both or neither.
+///
+/// Only the IP connections keep logs.
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+#[derive(Debug, Default)]
+pub(crate) struct EndpointLog {
+    incoming: Option<LogWriter<BufWriter<File>>>,
+    outgoing: Option<LogWriter<BufWriter<File>>>,
+}
+
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+impl EndpointLog {
+    /// Open the log files named, if any.
+    pub(crate) fn open(names: &LogFileNames) -> Result<EndpointLog> {
+        let open = |name: &Option<Bytes>| -> Result<Option<LogWriter<BufWriter<File>>>> {
+            match name {
+                Some(name) => Ok(Some(LogWriter::create(log_path(name)?)?)),
+                None => Ok(None),
+            }
+        };
+        Ok(EndpointLog {
+            incoming: open(names.in_log())?,
+            outgoing: open(names.out_log())?,
+        })
+    }
+
+    /// Open the logs a peer asked for: those in `mode` that `names` has a name for,
+    /// inside `dir`.
+    ///
+    /// Fails without creating anything if any name is unsafe,
+    /// or if a log is requested and `dir` is `None`, meaning remote logging is not allowed.
+    pub(crate) fn open_requested(
+        names: &LogFileNames,
+        mode: LogMode,
+        dir: Option<&Path>,
+    ) -> Result<EndpointLog> {
+        let resolve = |name: &Option<Bytes>, flag| -> Result<Option<PathBuf>> {
+            match name {
+                Some(name) if mode.contains(flag) => match dir {
+                    Some(dir) => Ok(Some(requested_log_path(dir, name)?)),
+                    None => Err(VrpnError::OtherMessage(
+                        "Remote logging is not allowed".to_string(),
+                    )),
+                },
+                _ => Ok(None),
+            }
+        };
+        let incoming = resolve(names.in_log(), LogMode::INCOMING)?;
+        let outgoing = resolve(names.out_log(), LogMode::OUTGOING)?;
+        Ok(EndpointLog {
+            incoming: incoming.map(LogWriter::create).transpose()?,
+            outgoing: outgoing.map(LogWriter::create).transpose()?,
+        })
+    }
+
+    /// Record the descriptions exchanged before the logs were opened,
+    /// so the IDs in the rest of the logs can be understood.
+    pub(crate) fn log_descriptions(
+        &mut self,
+        dispatcher: &TypeDispatcher,
+        translation: &TranslationTables,
+    ) -> Result<()> {
+        if self.incoming.is_some() {
+            for msg in translation.pack_remote_descriptions()? {
+                self.log_incoming(&msg)?;
+            }
+        }
+        if self.outgoing.is_some() {
+            for msg in dispatcher.pack_all_descriptions()? {
+                self.log_outgoing(&msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a message received from the peer, with its remote IDs.
+    pub(crate) fn log_incoming(&mut self, msg: &GenericMessage) -> Result<()> {
+        match &mut self.incoming {
+            Some(log) => log.write_message(msg),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a message being sent to the peer, with its local IDs.
+    pub(crate) fn log_outgoing(&mut self, msg: &GenericMessage) -> Result<()> {
+        match &mut self.outgoing {
+            Some(log) => log.write_message(msg),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        for log in self.incoming.iter_mut().chain(self.outgoing.iter_mut()) {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::ConstantBufferSize,
+        data_types::{StaticMessageTypeName, StaticSenderName},
+    };
+    use bytes::BytesMut;
+
+    /// A log of messages at the given times, in seconds since the epoch,
+    /// from the given senders and of the given types.
+    fn timed_log(messages: &[(u64, &'static [u8], &'static [u8])]) -> Vec<u8> {
+        let mut dispatcher = TypeDispatcher::new();
+        let ids: Vec<_> = messages
+            .iter()
+            .map(|&(_, sender, message_type)| {
+                (
+                    dispatcher
+                        .register_sender(StaticSenderName(sender))
+                        .unwrap()
+                        .into_inner(),
+                    dispatcher
+                        .register_type(StaticMessageTypeName(message_type))
+                        .unwrap()
+                        .into_inner(),
+                )
+            })
+            .collect();
+        let mut log = LogWriter::new(Vec::new()).unwrap();
+        for desc in dispatcher.pack_all_descriptions().unwrap() {
+            log.write_message(&desc).unwrap();
+        }
+        for (&(secs, _, _), &(sender, message_type)) in messages.iter().zip(&ids) {
+            let time = TimeVal::from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            log.write_message(&GenericMessage {
+                header: MessageHeader::new(Some(time), message_type, sender),
+                body: GenericBody::new(Bytes::from_static(b"1234")),
+            })
+            .unwrap();
+        }
+        log.sink
+    }
+
+    /// A log with the descriptions of one sender and type, then a message from them.
+    fn tracker_log() -> Vec<u8> {
+        timed_log(&[(10, b"Tracker0", b"Test Type")])
+    }
+
+    #[test]
+    fn write_log() {
+        let mut buf = BytesMut::from(&tracker_log()[..]);
+        let cookie = CookieData::unbuffer_from(
+            &mut buf.split_to(CookieData::constant_buffer_size()).freeze(),
+        )
+        .unwrap();
+        check_ver_file_compatible(cookie.version).unwrap();
+        let mut messages = Vec::new();
+        while let Some(msg) = maybe_decode_record(&mut buf).unwrap() {
+            messages.push(msg);
+        }
+        assert!(buf.is_empty());
+
+        // Descriptions of the system IDs and ours, then our message.
+        let (last, descriptions) = messages.split_last().unwrap();
+        assert!(descriptions.iter().all(GenericMessage::is_system_message));
+        assert_eq!(
+            descriptions.len(),
+            TypeDispatcher::new()
+                .pack_all_descriptions()
+                .unwrap()
+                .count()
+                + 2
+        );
+        assert_eq!(last.body, GenericBody::new(Bytes::from_static(b"1234")));
+    }
+
+    #[test]
+    fn read_log() {
+        let entries: Vec<LogEntry> = LogReader::new(&tracker_log()[..])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let (last, descriptions) = entries.split_last().unwrap();
+        assert!(descriptions
+            .iter()
+            .all(|entry| entry.message.is_system_message() && entry.sender.is_none()));
+        assert_eq!(
+            last.message.body,
+            GenericBody::new(Bytes::from_static(b"1234"))
+        );
+        assert_eq!(
+            SystemTime::from(last.message.header.time),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+        );
+        assert_eq!(
+            last.sender,
+            Some(SenderName(Bytes::from_static(b"Tracker0")))
+        );
+        assert_eq!(
+            last.message_type,
+            Some(MessageTypeName(Bytes::from_static(b"Test Type")))
+        );
+    }
+
+    /// A log as mainline writes it on 64-bit Linux: the file cookie, then the descriptions of
+    /// sender 0, "Tracker0", and type 0, "Test Type", then a message from them.
+    ///
+    /// Assembled by hand from mainline's vrpn_Log and vrpn_HANDLERPARAM,
+    /// without a recording from mainline itself to compare against.
+    const MAINLINE_LOG: [u8; 175] = hex!(
+        // "vrpn: ver. 04.00  0", padded
+        "76 72 70 6e 3a 20 76 65 72 2e 20 30 34 2e 30 30 20 20 30 00 00 00 00 00"
+        // Sender description: type -1, sender 0, 10.000000 s, 13 bytes
+        "ff ff ff ff 00 00 00 00 00 00 00 0a 00 00 00 00 00 00 00 00 00 00 00 00"
+        "00 00 00 0d 00 00 00 00 00 00 00 00 00 00 00 00"
+        "00 00 00 09 54 72 61 63 6b 65 72 30 00"
+        // Type description: type -2, sender 0, 10.000000 s, 14 bytes
+        "ff ff ff fe 00 00 00 00 00 00 00 0a 00 00 00 00 00 00 00 00 00 00 00 00"
+        "00 00 00 0e 00 00 00 00 00 00 00 00 00 00 00 00"
+        "00 00 00 0a 54 65 73 74 20 54 79 70 65 00"
+        // Message: type 0, sender 0, 10.500000 s, 4 bytes
+        "00 00 00 00 00 00 00 00 00 00 00 0a 00 00 00 00 00 07 a1 20 00 00 00 00"
+        "00 00 00 04 00 00 00 00 00 00 00 00 00 00 00 00"
+        "31 32 33 34"
+    );
+
+    /// The messages in `MAINLINE_LOG`.
+    fn mainline_log_messages() -> Vec<GenericMessage> {
+        let time = TimeVal::new(Seconds(10), Microseconds(0));
+        let sender = LocalId(SenderId(0));
+        let message_type = LocalId(MessageTypeId(0));
+        let mut messages = vec![
+            sender
+                .try_into_description_message(Bytes::from_static(b"Tracker0"))
+                .unwrap(),
+            message_type
+                .try_into_description_message(Bytes::from_static(b"Test Type"))
+                .unwrap(),
+            GenericMessage {
+                header: MessageHeader::new(
+                    Some(TimeVal::new(Seconds(10), Microseconds(500_000))),
+                    message_type,
+                    sender,
+                ),
+                body: GenericBody::new(Bytes::from_static(b"1234")),
+            },
+        ];
+        messages[0].header.time = time;
+        messages[1].header.time = time;
+        messages
+    }
+
+    #[test]
+    fn read_mainline_log() {
+        let entries: Vec<LogEntry> = LogReader::new(&MAINLINE_LOG[..])
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let messages: Vec<GenericMessage> =
+            entries.iter().map(|entry| entry.message.clone()).collect();
+        assert_eq!(messages, mainline_log_messages());
+        assert_eq!(
+            entries[2].sender,
+            Some(SenderName(Bytes::from_static(b"Tracker0")))
+        );
+        assert_eq!(
+            entries[2].message_type,
+            Some(MessageTypeName(Bytes::from_static(b"Test Type")))
+        );
+    }
+
+    #[test]
+    fn write_mainline_log() {
+        let mut log = LogWriter::new(Vec::new()).unwrap();
+        for msg in mainline_log_messages() {
+            log.write_message(&msg).unwrap();
+        }
+        assert_eq!(&log.sink[..], &MAINLINE_LOG[..]);
+    }
+
+    #[test]
+    fn read_truncated_log() {
+        let log = tracker_log();
+        let mut reader = LogReader::new(&log[..log.len() - 4]).unwrap();
+        let results: Vec<Result<LogEntry>> = reader.by_ref().collect();
+        let (last, rest) = results.split_last().unwrap();
+        assert!(rest.iter().all(|result| result.is_ok()));
+        assert!(last.is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn read_not_a_log() {
+        let mut buf = BytesMut::new();
+        buf.resize(CookieData::constant_buffer_size(), 0);
+        assert!(LogReader::new(&buf[..]).is_err());
+    }
+
+    #[test]
+    fn merge_logs() {
+        // Both logs have "Tracker0", with different IDs.
+        let first = timed_log(&[
+            (10, b"Other", b"Test Type"),
+            (11, b"Tracker0", b"Test Type"),
+        ]);
+        let second = timed_log(&[(12, b"Tracker0", b"Test Type")]);
+
+        let mut merger = LogMerger::new(LogWriter::new(Vec::new()).unwrap()).unwrap();
+        for log in [&first, &second] {
+            let input = merger.add_input();
+            for entry in LogReader::new(&log[..]).unwrap() {
+                let entry = entry.unwrap();
+                if let (Some(sender), Some(message_type)) = (&entry.sender, &entry.message_type) {
+                    merger
+                        .write_message(input, entry.message, sender, message_type)
+                        .unwrap();
+                }
+            }
+        }
+        let merged = merger.writer.sink;
+
+        let senders: Vec<Bytes> = LogReader::new(&merged[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .filter_map(|entry| {
+                assert_eq!(
+                    entry.message_type.is_some(),
+                    entry.sender.is_some(),
+                    "user messages should have both names"
+                );
+                entry.sender.map(|sender| sender.0)
+            })
+            .collect();
+        assert_eq!(
+            senders,
+            vec![
+                Bytes::from_static(b"Other"),
+                Bytes::from_static(b"Tracker0"),
+                Bytes::from_static(b"Tracker0")
+            ]
+        );
+        // Each sender and type is described once.
+        let descriptions = LogReader::new(&merged[..])
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().sender.is_none())
+            .count();
+        assert_eq!(
+            descriptions,
+            TypeDispatcher::new()
+                .pack_all_descriptions()
+                .unwrap()
+                .count()
+                + 3
+        );
+    }
+
+    /// Merge logs, returning the time in seconds, sender and type of each message kept.
+    fn merge_with(options: &MergeOptions, logs: &[Vec<u8>]) -> Vec<(u64, Bytes, Bytes)> {
+        let mut merged = Vec::new();
+        let inputs = logs
+            .iter()
+            .map(|log| LogReader::new(&log[..]).unwrap())
+            .collect();
+        let kept = options
+            .merge(inputs, LogWriter::new(&mut merged).unwrap())
+            .unwrap();
+        let entries: Vec<_> = LogReader::new(&merged[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|entry| entry.sender.is_some())
+            .map(|entry| {
+                let time = SystemTime::from(entry.message.header.time);
+                (
+                    time.duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    entry.sender.unwrap().0,
+                    entry.message_type.unwrap().0,
+                )
+            })
+            .collect();
+        assert_eq!(entries.len(), kept);
+        entries
+    }
+
+    fn tracker_and_button_logs() -> Vec<Vec<u8>> {
+        vec![
+            timed_log(&[
+                (10, b"Tracker0", b"Pose"),
+                (11, b"Tracker0", b"Pose"),
+                (12, b"Tracker0", b"Velocity"),
+                (13, b"Tracker0", b"Pose"),
+            ]),
+            timed_log(&[(11, b"Button0", b"Change"), (14, b"Button0", b"Change")]),
+        ]
+    }
+
+    fn entry(secs: u64, sender: &'static [u8], message_type: &'static [u8]) -> (u64, Bytes, Bytes) {
+        (
+            secs,
+            Bytes::from_static(sender),
+            Bytes::from_static(message_type),
+        )
+    }
+
+    #[test]
+    fn merge_everything() {
+        assert_eq!(
+            merge_with(&MergeOptions::default(), &tracker_and_button_logs()),
+            vec![
+                entry(10, b"Tracker0", b"Pose"),
+                entry(11, b"Tracker0", b"Pose"),
+                entry(11, b"Button0", b"Change"),
+                entry(12, b"Tracker0", b"Velocity"),
+                entry(13, b"Tracker0", b"Pose"),
+                entry(14, b"Button0", b"Change"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_trims() {
+        // The window is measured from the first message in either log.
+        let options = MergeOptions {
+            start: Some(Duration::from_secs(1)),
+            end: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        assert_eq!(
+            merge_with(&options, &tracker_and_button_logs()),
+            vec![
+                entry(11, b"Tracker0", b"Pose"),
+                entry(11, b"Button0", b"Change"),
+                entry(12, b"Tracker0", b"Velocity"),
+                entry(13, b"Tracker0", b"Pose"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_drops_senders() {
+        let options = MergeOptions {
+            drop_senders: vec![SenderName(Bytes::from_static(b"Tracker0"))],
+            ..Default::default()
+        };
+        assert_eq!(
+            merge_with(&options, &tracker_and_button_logs()),
+            vec![
+                entry(11, b"Button0", b"Change"),
+                entry(14, b"Button0", b"Change"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_drops_types() {
+        let options = MergeOptions {
+            drop_types: vec![
+                MessageTypeName(Bytes::from_static(b"Pose")),
+                MessageTypeName(Bytes::from_static(b"Change")),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            merge_with(&options, &tracker_and_button_logs()),
+            vec![entry(12, b"Tracker0", b"Velocity")]
+        );
+    }
+
+    #[test]
+    fn merge_rebases() {
+        // The first message kept moves to the new time, and the rest keep their spacing.
+        let options = MergeOptions {
+            start: Some(Duration::from_secs(2)),
+            rebase: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(100)),
+            ..Default::default()
+        };
+        assert_eq!(
+            merge_with(&options, &tracker_and_button_logs()),
+            vec![
+                entry(100, b"Tracker0", b"Velocity"),
+                entry(101, b"Tracker0", b"Pose"),
+                entry(102, b"Button0", b"Change"),
+            ]
+        );
+    }
+
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    #[test]
+    fn endpoint_log_files() {
+        let path =
+            std::env::temp_dir().join(format!("vrpn-rs-endpoint-log-{}.vrpn", std::process::id()));
+        let names = LogFileNames::from_names(Some(path.to_str().unwrap().to_owned()), None);
+        let mut log = EndpointLog::open(&names).unwrap();
+        let msg = GenericMessage {
+            header: MessageHeader::new(None, LocalId(MessageTypeId(0)), LocalId(SenderId(0))),
+            body: GenericBody::new(Bytes::from_static(b"1234")),
+        };
+        log.log_incoming(&msg).unwrap();
+        // Not logging outgoing messages, so this goes nowhere.
+        log.log_outgoing(&msg).unwrap();
+        log.flush().unwrap();
+
+        let mut buf = BytesMut::from(&std::fs::read(&path).unwrap()[..]);
+        let _ = buf.split_to(CookieData::constant_buffer_size());
+        let logged = maybe_decode_record(&mut buf)
+            .unwrap()
+            .expect("should have a message");
+        assert_eq!(logged, msg);
+        assert!(buf.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    #[test]
+    fn requested_log_files() {
+        let dir = std::env::temp_dir();
+        let name = format!("vrpn-rs-requested-log-{}.vrpn", std::process::id());
+        let names = |name: &str| LogFileNames::from_names(None, Some(name.to_owned()));
+
+        // Refused unless the connection allows remote logging.
+        assert!(EndpointLog::open_requested(&names(&name), LogMode::OUTGOING, None).is_err());
+        // Nothing requested, nothing to refuse.
+        assert!(EndpointLog::open_requested(&names(&name), LogMode::INCOMING, None).is_ok());
+
+        let outside = dir.join(&name);
+        for rejected in &[
+            outside.to_str().unwrap(),
+            "../escape.vrpn",
+            "logs/../../escape.vrpn",
+        ] {
+            assert!(
+                EndpointLog::open_requested(&names(rejected), LogMode::OUTGOING, Some(&dir))
+                    .is_err(),
+                "should refuse {:?}",
+                rejected
+            );
+        }
+        assert!(!outside.exists());
+
+        let log =
+            EndpointLog::open_requested(&names(&name), LogMode::OUTGOING, Some(&dir)).unwrap();
+        assert!(log.incoming.is_none());
+        assert!(log.outgoing.is_some());
+        assert!(outside.exists());
+        std::fs::remove_file(outside).unwrap();
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A server and client connected to each other in memory, without sockets.
+//!
+//! Both ends speak the full protocol, cookies and descriptions included,
+//! so this is good for hermetic tests, or for embedding device servers and clients in one process.
+//! Like `sync_connection`, nothing happens until `mainloop()` is called on each end.
+//! To run the same over an async runtime instead,
+//! see `ConnectionIp::new_loopback_pair` in `vrpn_tokio` or `vrpn_async_std`.
+
+use crate::{
+    connection::*,
+    data_types::{ClassOfService, GenericMessage, LogMode},
+    endpoint::{ExtendedSystemCommand, SystemCommand},
+    protocol::ProtocolEndpoint,
+    Endpoint, Result, TranslationTables, TypeDispatcher,
+};
+use bytes::BytesMut;
+use std::sync::{Arc, Mutex};
+
+/// One direction of the in-memory "socket" between the two ends.
+#[derive(Debug, Default)]
+struct Pipe {
+    data: BytesMut,
+    /// Set once the writing end has closed.
+    closed: bool,
+}
+
+/// One end of a loopback connection.
+///
+/// Closes its outgoing pipe when dropped, so the peer sees it go, as with a socket.
+#[derive(Debug)]
+pub struct EndpointLoopback {
+    protocol: ProtocolEndpoint,
+    rx: Arc<Mutex<Pipe>>,
+    tx: Arc<Mutex<Pipe>>,
+    /// Whether we have dispatched the connection events for this endpoint.
+    announced: bool,
+}
+
+impl EndpointLoopback {
+    fn new(rx: Arc<Mutex<Pipe>>, tx: Arc<Mutex<Pipe>>) -> Result<EndpointLoopback> {
+        Ok(EndpointLoopback {
+            protocol: ProtocolEndpoint::new(LogMode::NONE)?,
+            rx,
+            tx,
+            announced: false,
+        })
+    }
+
+    /// Handle everything the peer has sent, appending received messages to `received`
+    /// for the caller to dispatch, then pass along everything queued to send.
+    ///
+    /// Returns false once the endpoint has closed.
+    fn poll_collect(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+    ) -> Result<bool> {
+        {
+            let mut rx = self.rx.lock()?;
+            self.protocol.handle_bytes(&rx.data);
+            rx.data.clear();
+            if rx.closed {
+                self.protocol.handle_eof();
+            }
+        }
+        let mut extended = Vec::new();
+        self.protocol.process(dispatcher, received, &mut extended)?;
+        for cmd in extended {
+            // There is no UDP here, and no logging.
+            if let ExtendedSystemCommand::LogDescription(names) = cmd {
+                eprintln!("Ignoring request to keep logs {:?}", names);
+            }
+        }
+
+        let mut tx = self.tx.lock()?;
+        let n = self.protocol.output().len();
+        tx.data.extend_from_slice(self.protocol.output());
+        self.protocol.consume_output(n);
+        if self.protocol.is_closed() {
+            tx.closed = true;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for EndpointLoopback {
+    fn drop(&mut self) {
+        if let Ok(mut tx) = self.tx.lock() {
+            tx.closed = true;
+        }
+    }
+}
+
+impl Endpoint for EndpointLoopback {
+    fn translation_tables(&self) -> &TranslationTables {
+        self.protocol.translation_tables()
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        self.protocol.translation_tables_mut()
+    }
+
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.protocol.send_system_change(message)
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.protocol.disconnect()
+    }
+
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.protocol.buffer_generic_message(msg, class)
+    }
+}
+
+/// One end of a server and client connected in memory: see `LoopbackConnection::pair`.
+#[derive(Debug)]
+pub struct LoopbackConnection {
+    core: ConnectionCore<EndpointLoopback>,
+    is_server: bool,
+}
+
+impl LoopbackConnection {
+    /// Make a server connection and a client connection to it, in that order.
+    ///
+    /// Each has one endpoint from the start, so anything registered or packed
+    /// before the handshake is sent once it is done.
+    pub fn pair() -> Result<(Arc<LoopbackConnection>, Arc<LoopbackConnection>)> {
+        let to_client = Arc::new(Mutex::new(Pipe::default()));
+        let to_server = Arc::new(Mutex::new(Pipe::default()));
+        let server_endpoint =
+            EndpointLoopback::new(Arc::clone(&to_server), Arc::clone(&to_client))?;
+        let client_endpoint = EndpointLoopback::new(to_client, to_server)?;
+        let server = LoopbackConnection {
+            core: ConnectionCore::new(vec![Some(server_endpoint)], None, None),
+            is_server: true,
+        };
+        let client = LoopbackConnection {
+            core: ConnectionCore::new(vec![Some(client_endpoint)], None, None),
+            is_server: false,
+        };
+        Ok((Arc::new(server), Arc::new(client)))
+    }
+
+    /// Handle and dispatch everything the other end has sent, and pass along what we have queued.
+    ///
+    /// Call it on both ends, in turn, until whatever you are waiting for arrives.
+    pub fn mainloop(&self) -> Result<()> {
+        let endpoints = self.endpoints();
+        let dispatcher = self.dispatcher();
+        let mut dispatcher = dispatcher.lock()?;
+        let mut received = Vec::new();
+        let mut connected = Vec::new();
+        let mut dropped = Vec::new();
+        {
+            let mut endpoints = endpoints.lock()?;
+            for ep in endpoints.iter_mut() {
+                let open = match ep {
+                    Some(endpoint) => {
+                        let open = match endpoint.poll_collect(&mut dispatcher, &mut received) {
+                            Ok(open) => open,
+                            Err(e) => {
+                                eprintln!("Got endpoint error: {:?}", e);
+                                false
+                            }
+                        };
+                        if endpoint.protocol.is_established() && !endpoint.announced {
+                            endpoint.announced = true;
+                            got_connection_events(&mut dispatcher, true, &mut connected)?;
+                        }
+                        open
+                    }
+                    None => false,
+                };
+                if !open {
+                    if let Some(endpoint) = ep.take() {
+                        if endpoint.announced {
+                            dropped_connection_events(&mut dispatcher, true, &mut dropped)?;
+                        }
+                    }
+                }
+            }
+            endpoints.retain(|ep| ep.is_some());
+        }
+
+        // Dispatch with the endpoints unlocked, so handlers can pack replies.
+        for msg in connected.iter().chain(&received).chain(&dropped) {
+            dispatcher.call(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection for LoopbackConnection {
+    type SpecificEndpoint = EndpointLoopback;
+    fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
+        &self.core
+    }
+
+    /// A client whose server has gone reports itself closed.
+    fn status(&self) -> ConnectionStatus {
+        if self.core.is_shut_down() {
+            return ConnectionStatus::Closed;
+        }
+        let endpoints = self.endpoints();
+        let endpoints = match endpoints.lock() {
+            Ok(endpoints) => endpoints,
+            // A poisoned lock means a panic while polling: treat the connection as gone.
+            Err(_) => return ConnectionStatus::Closed,
+        };
+        let num_connected = endpoints.iter().flatten().filter(|ep| ep.announced).count();
+        if self.is_server {
+            ConnectionStatus::Server(num_connected)
+        } else if num_connected > 0 {
+            ConnectionStatus::ClientConnected
+        } else if !endpoints.is_empty() {
+            ConnectionStatus::ClientConnecting
+        } else {
+            ConnectionStatus::Closed
+        }
+    }
+}
+
+/// Run both ends in turn until the condition holds, panicking if it never does.
+#[cfg(test)]
+pub(crate) fn run_until(
+    server: &LoopbackConnection,
+    client: &LoopbackConnection,
+    mut condition: impl FnMut() -> bool,
+) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        server.mainloop().unwrap();
+        client.mainloop().unwrap();
+    }
+    panic!("condition never held");
+}
+
+/// Run both ends in turn until `fut` is ready, panicking if it never is.
+#[cfg(test)]
+pub(crate) fn run_until_ready<F: futures::Future + Unpin>(
+    server: &LoopbackConnection,
+    client: &LoopbackConnection,
+    mut fut: F,
+) -> F::Output {
+    use futures::FutureExt;
+    let mut output = None;
+    run_until(server, client, || {
+        output = (&mut fut).now_or_never();
+        output.is_some()
+    });
+    output.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            constants,
+            id_types::{LocalId, MessageTypeId, SenderId, Sensor},
+            Quat, StaticSenderName, Vec3,
+        },
+        handler::{add_reply_handler, Handler, HandlerCode},
+        ping,
+        remote::TrackerRemote,
+        server::TrackerServer,
+    };
+
+    #[test]
+    fn tracker_server_and_remote() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Server(0));
+        assert_eq!(client.status(), ConnectionStatus::ClientConnecting);
+
+        let tracker = TrackerServer::new("Tracker0", Arc::clone(&server)).unwrap();
+        // The remote sends its first ping as soon as it is made.
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let pong = add_reply_handler::<ping::Pong, _>(&*client, Some(sender)).unwrap();
+        let remote = TrackerRemote::new("Tracker0", Arc::clone(&client)).unwrap();
+        let pos = Vec3::new(1.0, 2.0, 3.0);
+        tracker
+            .report_pose(None, Sensor(0), pos, Quat::identity())
+            .unwrap();
+        run_until(&server, &client, || {
+            remote.pose(Sensor(0)).unwrap().is_some()
+        });
+        assert_eq!(remote.pose(Sensor(0)).unwrap().unwrap().pos, pos);
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        // The server may not have handled the client's cookie yet.
+        run_until(&server, &client, || {
+            server.status() == ConnectionStatus::Server(1)
+        });
+
+        // The remote's ping got answered.
+        let pong = run_until_ready(&server, &client, pong).unwrap();
+        assert_eq!(pong.header.sender, sender.0);
+    }
+
+    /// Records the type of every message from the "VRPN Control" sender.
+    #[derive(Debug)]
+    struct ControlRecorder {
+        control: LocalId<SenderId>,
+        types: Arc<Mutex<Vec<LocalId<MessageTypeId>>>>,
+    }
+    impl Handler for ControlRecorder {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            if LocalId(msg.header.sender) == self.control {
+                self.types.lock()?.push(LocalId(msg.header.message_type));
+            }
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[test]
+    fn events_and_shutdown() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let types = Arc::new(Mutex::new(Vec::new()));
+        client
+            .add_handler(
+                Box::new(ControlRecorder {
+                    control: client.register_sender(constants::CONTROL).unwrap(),
+                    types: Arc::clone(&types),
+                }),
+                None,
+                None,
+            )
+            .unwrap();
+        let event_types: Vec<_> = [
+            constants::GOT_FIRST_CONNECTION,
+            constants::GOT_CONNECTION,
+            constants::DROPPED_CONNECTION,
+            constants::DROPPED_LAST_CONNECTION,
+        ]
+        .iter()
+        .map(|name| client.register_type(name.clone()).unwrap())
+        .collect();
+
+        run_until(&server, &client, || {
+            client.status() == ConnectionStatus::ClientConnected
+        });
+        assert_eq!(*types.lock().unwrap(), event_types[..2]);
+
+        // The client hears the disconnect message and closes.
+        server.shutdown().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Closed);
+        run_until(&server, &client, || {
+            client.status() == ConnectionStatus::Closed
+        });
+        assert!(server.endpoints().lock().unwrap().is_empty());
+        assert_eq!(*types.lock().unwrap(), event_types);
+    }
+
+    #[test]
+    fn dropped_peer() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        run_until(&server, &client, || {
+            server.status() == ConnectionStatus::Server(1)
+        });
+        drop(client);
+        server.mainloop().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Server(0));
+    }
+}
@@ -0,0 +1,558 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! The VRPN protocol for one endpoint, as a state machine that does no IO of its own ("sans-IO").
+//!
+//! Feed a [ProtocolEndpoint] the bytes and datagrams received from the peer,
+//! and the time now and then; it hands back the messages to dispatch,
+//! along with the bytes and datagrams to send.
+//! An IO runtime then only has to move bytes between it and its sockets:
+//! see `sync_io` for an adapter over `std::net`,
+//! and the `endpoint_ip` modules of the async runtimes.
+
+use crate::{
+    buffer_unbuffer::{BytesMutExtras, ConstantBufferSize, UnbufferFrom},
+    codec::{decode_datagram, fits_in_datagram, DatagramPacker},
+    data_types::{
+        cookie::check_ver_nonfile_compatible, id_types::SequenceNumber, message::LengthField,
+        ClassOfService, CookieData, Disconnect, GenericMessage, LogMode, Message, MessageSize,
+        SequencedGenericMessage, TypedMessage,
+    },
+    endpoint::*,
+    error::to_other_error,
+    Result, TranslationTables, TypeDispatcher, VrpnError,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+/// How long the peer has to send its cookie, from the first time we are given the time.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handshake {
+    /// Waiting for the peer's cookie, which is due by the deadline once we know the time.
+    Waiting { deadline: Option<Instant> },
+    /// Got the peer's cookie, asking us to do this logging.
+    Done { log_requested: LogMode },
+}
+
+/// One endpoint of a VRPN connection, without any IO.
+///
+/// Handles the cookie exchange, message framing and sequencing,
+/// system messages, and mapping the peer's IDs to ours.
+/// Our cookie is queued to send right away, so either side may go first.
+#[derive(Debug)]
+pub struct ProtocolEndpoint {
+    translation: TranslationTables,
+    handshake: Handshake,
+    /// Bytes received but not yet making up a whole cookie or message.
+    incoming: BytesMut,
+    /// Bytes waiting to be sent over the reliable channel.
+    outgoing: BytesMut,
+    seq: u32,
+    /// Low-latency messages waiting to be sent, if the peer takes datagrams.
+    packer: Option<DatagramPacker>,
+    /// Set once either side has said it is disconnecting:
+    /// we close once everything queued has been sent.
+    closing: bool,
+    /// Set once the peer has closed the reliable channel.
+    peer_closed: bool,
+    /// Copies of the messages received, as the peer sent them, if asked to keep them for logging.
+    recorded: Option<Vec<GenericMessage>>,
+    system_rx: mpsc::Receiver<SystemCommand>,
+    system_tx: mpsc::Sender<SystemCommand>,
+}
+
+impl ProtocolEndpoint {
+    /// Start a new endpoint, asking the peer in our cookie to do the logging in `remote_log_mode`.
+    pub fn new(remote_log_mode: LogMode) -> Result<ProtocolEndpoint> {
+        let mut cookie = CookieData::make_cookie();
+        cookie.log_mode = Some(remote_log_mode);
+        let mut endpoint = ProtocolEndpoint::established(LogMode::NONE);
+        endpoint.handshake = Handshake::Waiting { deadline: None };
+        endpoint.outgoing = BytesMut::allocate_and_buffer(cookie)?;
+        Ok(endpoint)
+    }
+
+    /// Start a new endpoint for a connection whose cookies have already been exchanged,
+    /// the peer's asking us to do the logging in `log_requested`.
+    pub fn established(log_requested: LogMode) -> ProtocolEndpoint {
+        let (system_tx, system_rx) = mpsc::channel();
+        ProtocolEndpoint {
+            translation: TranslationTables::new(),
+            handshake: Handshake::Done { log_requested },
+            incoming: BytesMut::new(),
+            outgoing: BytesMut::new(),
+            seq: 0,
+            packer: None,
+            closing: false,
+            peer_closed: false,
+            recorded: None,
+            system_tx,
+            system_rx,
+        }
+    }
+
+    /// Whether we have checked the peer's cookie.
+    pub fn is_established(&self) -> bool {
+        matches!(self.handshake, Handshake::Done { .. })
+    }
+
+    /// The logging the peer asked us to do in its cookie, once we have it.
+    pub fn log_requested(&self) -> Option<LogMode> {
+        match self.handshake {
+            Handshake::Done { log_requested } => Some(log_requested),
+            Handshake::Waiting { .. } => None,
+        }
+    }
+
+    /// Whether the endpoint has closed: the peer closed the reliable channel,
+    /// or a disconnect was sent or received and everything queued has been taken to send.
+    ///
+    /// Once closed, close the sockets too.
+    pub fn is_closed(&self) -> bool {
+        self.peer_closed || (self.closing && self.outgoing.is_empty())
+    }
+
+    /// Send low-latency messages as datagrams from now on,
+    /// once the IO side knows where to send them.
+    ///
+    /// Until then, they go over the reliable channel.
+    pub fn enable_datagrams(&mut self) {
+        if self.packer.is_none() {
+            self.packer = Some(DatagramPacker::new());
+        }
+    }
+
+    /// Keep a copy of each message received from here on, with the peer's IDs, for logging.
+    ///
+    /// Collect them with `take_recorded`.
+    pub fn record_incoming(&mut self) {
+        if self.recorded.is_none() {
+            self.recorded = Some(Vec::new());
+        }
+    }
+
+    /// Take the messages received since last called, if recording them.
+    pub fn take_recorded(&mut self) -> Vec<GenericMessage> {
+        match &mut self.recorded {
+            Some(recorded) => std::mem::take(recorded),
+            None => Vec::new(),
+        }
+    }
+
+    /// Take bytes received from the peer over the reliable channel.
+    ///
+    /// They are handled by the next call to `process`.
+    pub fn handle_bytes(&mut self, data: &[u8]) {
+        self.incoming.extend_from_slice(data);
+    }
+
+    /// Note that the peer has closed the reliable channel.
+    pub fn handle_eof(&mut self) {
+        self.peer_closed = true;
+    }
+
+    /// Note the time, failing if the peer has run out of time to send its cookie.
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<()> {
+        if let Handshake::Waiting { deadline } = &mut self.handshake {
+            let deadline = *deadline.get_or_insert(now + HANDSHAKE_TIMEOUT);
+            if now >= deadline {
+                return Err(VrpnError::OtherMessage(
+                    "Timed out waiting for handshake".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the peer's cookie, if we have received it and not already checked it.
+    ///
+    /// Returns whether the handshake is complete.
+    pub fn handle_handshake(&mut self) -> Result<bool> {
+        if let Handshake::Waiting { .. } = self.handshake {
+            let cookie_size = CookieData::constant_buffer_size();
+            if self.incoming.len() < cookie_size {
+                return Ok(false);
+            }
+            let mut buf = self.incoming.split_to(cookie_size).freeze();
+            let cookie = CookieData::unbuffer_from(&mut buf)?;
+            check_ver_nonfile_compatible(cookie.version)?;
+            self.handshake = Handshake::Done {
+                log_requested: cookie.log_mode.unwrap_or(LogMode::NONE),
+            };
+        }
+        Ok(true)
+    }
+
+    /// Handle everything received over the reliable channel so far, once the handshake is done,
+    /// along with the system changes we sent ourself.
+    ///
+    /// System messages are applied right away, so that descriptions are known before
+    /// any following message that uses them gets mapped.
+    /// Other messages, mapped to local IDs, are appended to `received` for the caller to dispatch.
+    /// Extended system commands other than disconnecting are appended to `extended`
+    /// for the IO side to handle.
+    pub fn process(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        extended: &mut Vec<ExtendedSystemCommand>,
+    ) -> Result<()> {
+        if !self.handle_handshake()? {
+            return Ok(());
+        }
+        // Split off each message once all of it has arrived, leaving a partial one in place,
+        // so a large message arriving a little at a time is not decoded over and over.
+        while let Some(size) = self.next_message_size()? {
+            let mut buf = self.incoming.split_to(size).freeze();
+            let msg = SequencedGenericMessage::try_read_from_buf(&mut buf)?;
+            self.collect_message(msg, dispatcher, received, extended)?;
+        }
+
+        // Now, process the messages we sent ourself.
+        while let Ok(cmd) = self.system_rx.try_recv() {
+            if let Some(cmd) = handle_system_command(dispatcher, &mut self.translation, cmd)? {
+                self.collect_extended(cmd, extended);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a datagram received from the peer, like `process`.
+    ///
+    /// Datagrams are best-effort: the caller may drop one that fails.
+    pub fn handle_datagram(
+        &mut self,
+        data: &[u8],
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        extended: &mut Vec<ExtendedSystemCommand>,
+    ) -> Result<()> {
+        for msg in decode_datagram(Bytes::copy_from_slice(data))? {
+            self.collect_message(msg, dispatcher, received, extended)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes waiting to be sent over the reliable channel.
+    ///
+    /// Call `consume_output` with however many were sent.
+    pub fn output(&self) -> &[u8] {
+        &self.outgoing
+    }
+
+    /// Drop the first `n` bytes of `output()`, once sent.
+    pub fn consume_output(&mut self, n: usize) {
+        self.outgoing.advance(n);
+    }
+
+    /// Take the next datagram to send, if any.
+    pub fn take_datagram(&mut self) -> Option<Bytes> {
+        let packer = self.packer.as_mut()?;
+        packer.finish();
+        packer.pop()
+    }
+
+    /// The padded size of the next message received over the reliable channel,
+    /// if all of it has arrived.
+    fn next_message_size(&self) -> Result<Option<usize>> {
+        if self.incoming.len() < LengthField::constant_buffer_size() {
+            return Ok(None);
+        }
+        let length_field = LengthField::unbuffer_from(&mut &self.incoming[..])?;
+        let size = MessageSize::try_from_length_field(length_field)?.padded_message_size();
+        Ok(Some(size).filter(|&size| self.incoming.len() >= size))
+    }
+
+    fn collect_extended(
+        &mut self,
+        cmd: ExtendedSystemCommand,
+        extended: &mut Vec<ExtendedSystemCommand>,
+    ) {
+        match cmd {
+            ExtendedSystemCommand::DisconnectMessage => self.closing = true,
+            cmd => extended.push(cmd),
+        }
+    }
+
+    fn collect_message(
+        &mut self,
+        msg: SequencedGenericMessage,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        extended: &mut Vec<ExtendedSystemCommand>,
+    ) -> Result<()> {
+        let msg = msg.into_inner();
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push(msg.clone());
+        }
+        if msg.is_system_message() {
+            let cmd = parse_system_message(msg)?;
+            if let Some(cmd) = handle_system_command(dispatcher, &mut self.translation, cmd)? {
+                self.collect_extended(cmd, extended);
+            }
+        } else {
+            received.push(self.map_remote_message_to_local(msg)?);
+        }
+        Ok(())
+    }
+}
+
+impl Endpoint for ProtocolEndpoint {
+    fn translation_tables(&self) -> &TranslationTables {
+        &self.translation
+    }
+
+    fn translation_tables_mut(&mut self) -> &mut TranslationTables {
+        &mut self.translation
+    }
+
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.system_tx.send(message).map_err(to_other_error)?;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        self.buffer_message(TypedMessage::from(Disconnect), ClassOfService::RELIABLE)?;
+        self.closing = true;
+        Ok(())
+    }
+
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        if !class.contains(ClassOfService::RELIABLE) {
+            if let Some(packer) = &mut self.packer {
+                // Messages too big for a datagram go over the reliable channel instead.
+                if fits_in_datagram(&msg) {
+                    packer.push(msg)?;
+                    return Ok(());
+                }
+            }
+        }
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let sequenced = msg.into_sequenced_message(SequenceNumber(seq));
+        self.outgoing.extend_from_slice(&sequenced.try_into_buf()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            id_types::{LocalId, Sensor},
+            MessageTypeIdentifier, Quat, StaticSenderName, TypedMessageBody, Vec3,
+        },
+        tracker::PoseReport,
+    };
+    use std::convert::TryFrom;
+
+    /// Move everything one endpoint has to send over to the other, a few bytes at a time.
+    fn transfer(from: &mut ProtocolEndpoint, to: &mut ProtocolEndpoint, chunk: usize) {
+        while !from.output().is_empty() {
+            let n = chunk.min(from.output().len());
+            to.handle_bytes(&from.output()[..n]);
+            from.consume_output(n);
+        }
+    }
+
+    fn report() -> PoseReport {
+        PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Queue a pose report from "Tracker0", describing the sender and type first.
+    fn send_report(
+        ep: &mut ProtocolEndpoint,
+        dispatcher: &mut TypeDispatcher,
+        class: ClassOfService,
+    ) {
+        let sender = dispatcher
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap()
+            .into_inner();
+        let name = match PoseReport::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => name,
+            MessageTypeIdentifier::SystemMessageId(_) => unreachable!(),
+        };
+        let message_type = dispatcher.register_type(name).unwrap().into_inner();
+        ep.send_all_descriptions(dispatcher).unwrap();
+        ep.buffer_message(
+            TypedMessage::new(None, message_type, sender, report()),
+            class,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn handshake_and_message() {
+        let (mut server_dispatcher, mut client_dispatcher) =
+            (TypeDispatcher::new(), TypeDispatcher::new());
+        let mut server = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let mut client = ProtocolEndpoint::new(LogMode::INCOMING).unwrap();
+        assert!(!client.is_established());
+        send_report(
+            &mut server,
+            &mut server_dispatcher,
+            ClassOfService::RELIABLE,
+        );
+
+        let (mut received, mut extended) = (Vec::new(), Vec::new());
+        transfer(&mut client, &mut server, 1000);
+        server
+            .process(&mut server_dispatcher, &mut received, &mut extended)
+            .unwrap();
+        assert_eq!(server.log_requested(), Some(LogMode::INCOMING));
+
+        // Split the stream awkwardly, to check partial messages wait for the rest.
+        transfer(&mut server, &mut client, 7);
+        client
+            .process(&mut client_dispatcher, &mut received, &mut extended)
+            .unwrap();
+        assert_eq!(client.log_requested(), Some(LogMode::NONE));
+        assert!(extended.is_empty());
+        assert_eq!(received.len(), 1);
+
+        // It arrives mapped to the client's own IDs for the names.
+        let msg = TypedMessage::<PoseReport>::try_from(&received[0]).unwrap();
+        assert_eq!(msg.body, report());
+        let sender = client_dispatcher
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        assert_eq!(LocalId(msg.header.sender), sender.into_inner());
+    }
+
+    #[test]
+    fn partial_messages() {
+        let (mut server_dispatcher, mut client_dispatcher) =
+            (TypeDispatcher::new(), TypeDispatcher::new());
+        let mut server = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let mut client = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        send_report(
+            &mut server,
+            &mut server_dispatcher,
+            ClassOfService::RELIABLE,
+        );
+
+        // Process after every byte: only whole messages come out, each just once.
+        let (mut received, mut extended) = (Vec::new(), Vec::new());
+        while !server.output().is_empty() {
+            assert!(received.is_empty());
+            client.handle_bytes(&server.output()[..1]);
+            server.consume_output(1);
+            client
+                .process(&mut client_dispatcher, &mut received, &mut extended)
+                .unwrap();
+        }
+        assert_eq!(received.len(), 1);
+        assert!(client.incoming.is_empty());
+    }
+
+    #[test]
+    fn datagrams() {
+        let (mut server_dispatcher, mut client_dispatcher) =
+            (TypeDispatcher::new(), TypeDispatcher::new());
+        let mut server = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let mut client = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        server.enable_datagrams();
+        send_report(
+            &mut server,
+            &mut server_dispatcher,
+            ClassOfService::LOW_LATENCY,
+        );
+        transfer(&mut server, &mut client, 1000);
+
+        let (mut received, mut extended) = (Vec::new(), Vec::new());
+        client
+            .process(&mut client_dispatcher, &mut received, &mut extended)
+            .unwrap();
+        assert!(received.is_empty(), "report should not be sent reliably");
+        let datagram = server.take_datagram().unwrap();
+        assert!(server.take_datagram().is_none());
+        client
+            .handle_datagram(
+                &datagram,
+                &mut client_dispatcher,
+                &mut received,
+                &mut extended,
+            )
+            .unwrap();
+        assert_eq!(received.len(), 1);
+    }
+
+    #[test]
+    fn established_records_incoming() {
+        let (mut server_dispatcher, mut client_dispatcher) =
+            (TypeDispatcher::new(), TypeDispatcher::new());
+        let mut server = ProtocolEndpoint::established(LogMode::NONE);
+        let mut client = ProtocolEndpoint::established(LogMode::INCOMING);
+        assert!(server.output().is_empty(), "cookies were already exchanged");
+        assert_eq!(client.log_requested(), Some(LogMode::INCOMING));
+        client.record_incoming();
+        send_report(
+            &mut server,
+            &mut server_dispatcher,
+            ClassOfService::RELIABLE,
+        );
+        transfer(&mut server, &mut client, 1000);
+
+        let (mut received, mut extended) = (Vec::new(), Vec::new());
+        client
+            .process(&mut client_dispatcher, &mut received, &mut extended)
+            .unwrap();
+        assert_eq!(received.len(), 1);
+        // The descriptions are recorded too, before the report.
+        let recorded = client.take_recorded();
+        assert!(recorded.len() > 1);
+        assert!(recorded[..recorded.len() - 1]
+            .iter()
+            .all(|msg| msg.is_system_message()));
+        assert_eq!(recorded.last().unwrap().body, received[0].body);
+        assert!(client.take_recorded().is_empty());
+    }
+
+    #[test]
+    fn bad_cookie() {
+        let mut ep = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let cookie = BytesMut::allocate_and_buffer(CookieData::make_file_cookie()).unwrap();
+        ep.handle_bytes(&cookie);
+        assert!(ep.handle_handshake().is_err());
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let mut ep = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let start = Instant::now();
+        ep.handle_timeout(start).unwrap();
+        ep.handle_timeout(start + HANDSHAKE_TIMEOUT / 2).unwrap();
+        assert!(ep.handle_timeout(start + HANDSHAKE_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut dispatcher = TypeDispatcher::new();
+        let mut a = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        let mut b = ProtocolEndpoint::new(LogMode::NONE).unwrap();
+        transfer(&mut b, &mut a, 1000);
+        a.disconnect().unwrap();
+        assert!(!a.is_closed(), "should wait for the disconnect to be sent");
+        transfer(&mut a, &mut b, 1000);
+        assert!(a.is_closed());
+
+        let (mut received, mut extended) = (Vec::new(), Vec::new());
+        b.process(&mut dispatcher, &mut received, &mut extended)
+            .unwrap();
+        assert!(b.is_closed());
+        assert!(received.is_empty());
+        assert!(extended.is_empty());
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright 2021, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! High-level objects for receiving data from a remote device.
+//!
+//! Each object registers its sender, adds the handlers that track the latest state,
+//! and keeps a ping client, like the `vrpn_*_Remote` classes in mainline VRPN.
+//! The underlying connection still needs to be polled for anything to arrive.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    analog::AnalogReport,
+    buffer_unbuffer::UnbufferFrom,
+    button::{ButtonChangeReport, ButtonState, ButtonStatesReport},
+    data_types::{
+        id_types::{ButtonId, LocalId, SenderId, Sensor},
+        SenderName, TypedMessage, TypedMessageBody,
+    },
+    handler::{add_reply_stream_handler, HandlerCode, HandlerHandle, ReplyStream, TypedHandler},
+    ping,
+    tracker::{AccelReport, PoseReport, VelocityReport},
+    ClientConnection, Connection, DeviceInfo, Result, VrpnError,
+};
+
+/// Parts common to all remote device objects.
+struct RemoteCore<C: Connection + 'static> {
+    connection: Arc<C>,
+    sender: LocalId<SenderId>,
+    ping_client: ping::Client<C>,
+    handlers: Vec<HandlerHandle>,
+}
+
+impl<C: Connection + 'static> RemoteCore<C> {
+    fn new(device: &str, connection: Arc<C>) -> Result<RemoteCore<C>> {
+        let sender = connection.register_sender(SenderName(Bytes::from(device.to_string())))?;
+        let ping_client = ping::Client::new(sender, Arc::clone(&connection))?;
+        Ok(RemoteCore {
+            connection,
+            sender,
+            ping_client,
+            handlers: Vec::new(),
+        })
+    }
+
+    fn add_state_handler<T, S, F>(&mut self, state: &Arc<Mutex<S>>, update: F) -> Result<()>
+    where
+        T: TypedMessageBody + UnbufferFrom + Send + Sync + 'static,
+        S: Send + 'static,
+        F: FnMut(&mut S, &TypedMessage<T>) + Send + Sync + 'static,
+    {
+        let handle = self.connection.add_typed_handler(
+            Box::new(StateHandler {
+                state: Arc::downgrade(state),
+                update,
+                phantom: PhantomData,
+            }),
+            Some(self.sender),
+        )?;
+        self.handlers.push(handle);
+        Ok(())
+    }
+
+    fn reports<T>(&self) -> Result<ReplyStream<T>>
+    where
+        T: TypedMessageBody + UnbufferFrom + Clone + Send + Sync + 'static,
+    {
+        add_reply_stream_handler(&*self.connection, Some(self.sender))
+    }
+}
+
+impl<C: Connection + 'static> Drop for RemoteCore<C> {
+    fn drop(&mut self) {
+        for handle in self.handlers.drain(..) {
+            // Nothing useful to do with an error here.
+            let _ = self.connection.remove_handler(handle);
+        }
+    }
+}
+
+/// Parse a `"Device@host:port"` string, requiring the device part.
+fn parse_device(device_and_server: &str) -> Result<(String, DeviceInfo)> {
+    let info: DeviceInfo = device_and_server.parse()?;
+    let device = info.device.clone().ok_or_else(|| {
+        VrpnError::OtherMessage(format!(
+            "no device name in {}, expected something like Tracker0@localhost",
+            device_and_server
+        ))
+    })?;
+    Ok((device, info))
+}
+
+/// Handler that applies each received message to some shared state.
+struct StateHandler<T, S, F> {
+    state: Weak<Mutex<S>>,
+    update: F,
+    phantom: PhantomData<fn(T)>,
+}
+
+impl<T, S, F> fmt::Debug for StateHandler<T, S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StateHandler").finish()
+    }
+}
+
+impl<T, S, F> TypedHandler for StateHandler<T, S, F>
+where
+    T: TypedMessageBody + UnbufferFrom + Send + Sync,
+    S: Send,
+    F: FnMut(&mut S, &TypedMessage<T>) + Send + Sync,
+{
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        match self.state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock()?;
+                (self.update)(&mut state, msg);
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            // The remote object has gone away
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+macro_rules! impl_remote_common {
+    ($remote:ident) => {
+        impl<C: Connection + 'static> $remote<C> {
+            /// Get the connection used by this remote object, to poll it or share it.
+            pub fn connection(&self) -> &Arc<C> {
+                &self.core.connection
+            }
+
+            /// Get the local ID of the sender (device) this object listens to.
+            pub fn sender(&self) -> LocalId<SenderId> {
+                self.core.sender
+            }
+
+            /// Checks whether we're due for another ping, sending one if so.
+            ///
+            /// Returns the duration since the first unanswered ping,
+            /// or None if there are no unanswered pings.
+            pub fn check_ping_cycle(&self) -> Result<Option<Duration>> {
+                self.core.ping_client.check_ping_cycle()
+            }
+        }
+
+        impl<C: ClientConnection + 'static> $remote<C> {
+            /// Create a client connection to a device given as `"Device@host:port"`.
+            pub fn connect(device_and_server: &str) -> Result<$remote<C>> {
+                let (device, info) = parse_device(device_and_server)?;
+                let connection = C::new_client_connection(info.server)?;
+                $remote::new(&device, connection)
+            }
+        }
+    };
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    poses: HashMap<Sensor, PoseReport>,
+    velocities: HashMap<Sensor, VelocityReport>,
+    accelerations: HashMap<Sensor, AccelReport>,
+}
+
+/// Client-side object for a remote `vrpn_Tracker` device.
+pub struct TrackerRemote<C: Connection + 'static> {
+    core: RemoteCore<C>,
+    state: Arc<Mutex<TrackerState>>,
+}
+
+impl<C: Connection + 'static> TrackerRemote<C> {
+    /// Listen to the tracker named `device` (like `"Tracker0"`) on an existing connection.
+    pub fn new(device: &str, connection: Arc<C>) -> Result<TrackerRemote<C>> {
+        let mut core = RemoteCore::new(device, connection)?;
+        let state = Arc::new(Mutex::new(TrackerState::default()));
+        core.add_state_handler(
+            &state,
+            |state: &mut TrackerState, msg: &TypedMessage<PoseReport>| {
+                state.poses.insert(msg.body.sensor, msg.body.clone());
+            },
+        )?;
+        core.add_state_handler(
+            &state,
+            |state: &mut TrackerState, msg: &TypedMessage<VelocityReport>| {
+                state.velocities.insert(msg.body.sensor, msg.body);
+            },
+        )?;
+        core.add_state_handler(
+            &state,
+            |state: &mut TrackerState, msg: &TypedMessage<AccelReport>| {
+                state.accelerations.insert(msg.body.sensor, msg.body);
+            },
+        )?;
+        Ok(TrackerRemote { core, state })
+    }
+
+    /// Latest pose received for a sensor, if any.
+    pub fn pose(&self, sensor: Sensor) -> Result<Option<PoseReport>> {
+        Ok(self.state.lock()?.poses.get(&sensor).cloned())
+    }
+
+    /// Latest velocity received for a sensor, if any.
+    pub fn velocity(&self, sensor: Sensor) -> Result<Option<VelocityReport>> {
+        Ok(self.state.lock()?.velocities.get(&sensor).copied())
+    }
+
+    /// Latest acceleration received for a sensor, if any.
+    pub fn acceleration(&self, sensor: Sensor) -> Result<Option<AccelReport>> {
+        Ok(self.state.lock()?.accelerations.get(&sensor).copied())
+    }
+
+    /// All sensors that a pose has been received for, in order.
+    pub fn sensors(&self) -> Result<Vec<Sensor>> {
+        let mut sensors: Vec<Sensor> = self.state.lock()?.poses.keys().copied().collect();
+        sensors.sort();
+        Ok(sensors)
+    }
+
+    /// Stream of all pose reports received from now on.
+    pub fn pose_reports(&self) -> Result<ReplyStream<PoseReport>> {
+        self.core.reports()
+    }
+
+    /// Stream of all velocity reports received from now on.
+    pub fn velocity_reports(&self) -> Result<ReplyStream<VelocityReport>> {
+        self.core.reports()
+    }
+
+    /// Stream of all acceleration reports received from now on.
+    pub fn acceleration_reports(&self) -> Result<ReplyStream<AccelReport>> {
+        self.core.reports()
+    }
+}
+
+impl_remote_common!(TrackerRemote);
+
+/// Client-side object for a remote `vrpn_Button` device.
+pub struct ButtonRemote<C: Connection + 'static> {
+    core: RemoteCore<C>,
+    state: Arc<Mutex<Vec<ButtonState>>>,
+}
+
+impl<C: Connection + 'static> ButtonRemote<C> {
+    /// Listen to the button device named `device` (like `"Button0"`) on an existing connection.
+    pub fn new(device: &str, connection: Arc<C>) -> Result<ButtonRemote<C>> {
+        let mut core = RemoteCore::new(device, connection)?;
+        let state = Arc::new(Mutex::new(Vec::new()));
+        core.add_state_handler(
+            &state,
+            |state: &mut Vec<ButtonState>, msg: &TypedMessage<ButtonStatesReport>| {
+                *state = msg.body.states.clone();
+            },
+        )?;
+        core.add_state_handler(
+            &state,
+            |state: &mut Vec<ButtonState>, msg: &TypedMessage<ButtonChangeReport>| {
+                for change in &msg.body.changes {
+                    // Unbuffering only accepts ids below MAX_BUTTONS.
+                    let index = change.button.0 as usize;
+                    if index >= state.len() {
+                        state.resize(index + 1, ButtonState::default());
+                    }
+                    state[index] = change.state;
+                }
+            },
+        )?;
+        Ok(ButtonRemote { core, state })
+    }
+
+    /// Latest state of a button: released if we haven't heard about it.
+    pub fn button(&self, button: ButtonId) -> Result<ButtonState> {
+        let state = self.state.lock()?;
+        Ok(usize::try_from(button.0)
+            .ok()
+            .and_then(|index| state.get(index).copied())
+            .unwrap_or_default())
+    }
+
+    /// Latest states of all buttons we've heard about, indexed by button id.
+    pub fn buttons(&self) -> Result<Vec<ButtonState>> {
+        Ok(self.state.lock()?.clone())
+    }
+
+    /// Stream of all button change reports received from now on.
+    pub fn change_reports(&self) -> Result<ReplyStream<ButtonChangeReport>> {
+        self.core.reports()
+    }
+
+    /// Stream of all full button state reports received from now on.
+    pub fn states_reports(&self) -> Result<ReplyStream<ButtonStatesReport>> {
+        self.core.reports()
+    }
+}
+
+impl_remote_common!(ButtonRemote);
+
+/// Client-side object for a remote `vrpn_Analog` device.
+pub struct AnalogRemote<C: Connection + 'static> {
+    core: RemoteCore<C>,
+    state: Arc<Mutex<Vec<f64>>>,
+}
+
+impl<C: Connection + 'static> AnalogRemote<C> {
+    /// Listen to the analog device named `device` (like `"Analog0"`) on an existing connection.
+    pub fn new(device: &str, connection: Arc<C>) -> Result<AnalogRemote<C>> {
+        let mut core = RemoteCore::new(device, connection)?;
+        let state = Arc::new(Mutex::new(Vec::new()));
+        core.add_state_handler(
+            &state,
+            |state: &mut Vec<f64>, msg: &TypedMessage<AnalogReport>| {
+                *state = msg.body.channels.clone();
+            },
+        )?;
+        Ok(AnalogRemote { core, state })
+    }
+
+    /// Latest value of a channel, if we've heard about it.
+    pub fn channel(&self, channel: usize) -> Result<Option<f64>> {
+        Ok(self.state.lock()?.get(channel).copied())
+    }
+
+    /// Latest values of all channels.
+    pub fn channels(&self) -> Result<Vec<f64>> {
+        Ok(self.state.lock()?.clone())
+    }
+
+    /// Stream of all analog reports received from now on.
+    pub fn reports(&self) -> Result<ReplyStream<AnalogReport>> {
+        self.core.reports()
+    }
+}
+
+impl_remote_common!(AnalogRemote);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::BufferTo,
+        button::{ButtonChange, MAX_BUTTONS},
+        data_types::{ClassOfService, Quat, Vec3},
+        loopback::{run_until, LoopbackConnection},
+    };
+
+    /// A server and client connected in memory, and the sender `device` registered on the server.
+    fn server_and_client(
+        device: &'static [u8],
+    ) -> (
+        Arc<LoopbackConnection>,
+        Arc<LoopbackConnection>,
+        LocalId<SenderId>,
+    ) {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let sender = server
+            .register_sender(SenderName(Bytes::from_static(device)))
+            .unwrap();
+        (server, client, sender)
+    }
+
+    fn pack<T: TypedMessageBody + BufferTo>(
+        server: &LoopbackConnection,
+        sender: LocalId<SenderId>,
+        body: T,
+    ) {
+        server
+            .pack_message_body(None, sender, body, ClassOfService::RELIABLE)
+            .unwrap();
+    }
+
+    #[test]
+    fn button_remote() {
+        use ButtonState::*;
+        let (server, client, sender) = server_and_client(b"Button0");
+        let remote = ButtonRemote::new("Button0", Arc::clone(&client)).unwrap();
+
+        pack(
+            &server,
+            sender,
+            ButtonStatesReport {
+                states: vec![Pressed, Released, Pressed],
+            },
+        );
+        run_until(&server, &client, || remote.buttons().unwrap().len() == 3);
+        assert_eq!(remote.buttons().unwrap(), vec![Pressed, Released, Pressed]);
+
+        pack(
+            &server,
+            sender,
+            ButtonChangeReport {
+                changes: vec![
+                    ButtonChange::new(ButtonId(0), Released),
+                    ButtonChange::new(ButtonId(5), Pressed),
+                ],
+            },
+        );
+        run_until(&server, &client, || remote.buttons().unwrap().len() == 6);
+        assert_eq!(remote.button(ButtonId(0)).unwrap(), Released);
+        assert_eq!(remote.button(ButtonId(2)).unwrap(), Pressed);
+        assert_eq!(remote.button(ButtonId(4)).unwrap(), Released);
+        assert_eq!(remote.button(ButtonId(5)).unwrap(), Pressed);
+        assert_eq!(remote.button(ButtonId(100)).unwrap(), Released);
+
+        // Reports naming ids past MAX_BUTTONS are refused, when packing or unbuffering,
+        // so the remote's state can't grow without bound.
+        let bogus = ButtonChangeReport {
+            changes: vec![ButtonChange::new(ButtonId(MAX_BUTTONS as i32), Pressed)],
+        };
+        assert!(server
+            .pack_message_body(None, sender, bogus, ClassOfService::RELIABLE)
+            .is_err());
+        assert_eq!(remote.buttons().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn analog_remote() {
+        let (server, client, sender) = server_and_client(b"Analog0");
+        let remote = AnalogRemote::new("Analog0", Arc::clone(&client)).unwrap();
+        assert_eq!(remote.channel(0).unwrap(), None);
+
+        pack(&server, sender, AnalogReport::new(vec![0.5, -1.0]));
+        run_until(&server, &client, || remote.channel(0).unwrap().is_some());
+        assert_eq!(remote.channels().unwrap(), vec![0.5, -1.0]);
+        assert_eq!(remote.channel(1).unwrap(), Some(-1.0));
+        assert_eq!(remote.channel(2).unwrap(), None);
+
+        // Each report replaces all the channels.
+        pack(&server, sender, AnalogReport::new(vec![2.0]));
+        run_until(&server, &client, || remote.channels().unwrap().len() == 1);
+        assert_eq!(remote.channel(0).unwrap(), Some(2.0));
+    }
+
+    #[test]
+    fn tracker_remote() {
+        let (server, client, sender) = server_and_client(b"Tracker0");
+        let remote = TrackerRemote::new("Tracker0", Arc::clone(&client)).unwrap();
+        // Reports from other devices are not ours.
+        let other = server
+            .register_sender(SenderName(Bytes::from_static(b"Tracker1")))
+            .unwrap();
+        let pose = |sensor, x| PoseReport {
+            sensor: Sensor(sensor),
+            pos: Vec3::new(x, 0.0, 0.0),
+            quat: Quat::identity(),
+        };
+        pack(&server, other, pose(1, -1.0));
+        pack(&server, sender, pose(0, 1.0));
+        pack(&server, sender, pose(2, 2.0));
+        let velocity = VelocityReport {
+            sensor: Sensor(0),
+            vel: Vec3::new(0.0, 1.0, 0.0),
+            vel_quat: Quat::identity(),
+            vel_quat_dt: 0.5,
+        };
+        pack(&server, sender, velocity);
+        let accel = AccelReport {
+            sensor: Sensor(2),
+            acc: Vec3::new(0.0, 0.0, -1.0),
+            acc_quat: Quat::identity(),
+            acc_quat_dt: 0.25,
+        };
+        pack(&server, sender, accel);
+
+        run_until(&server, &client, || {
+            remote.acceleration(Sensor(2)).unwrap().is_some()
+        });
+        assert_eq!(remote.sensors().unwrap(), vec![Sensor(0), Sensor(2)]);
+        assert_eq!(remote.pose(Sensor(0)).unwrap(), Some(pose(0, 1.0)));
+        assert_eq!(remote.pose(Sensor(2)).unwrap(), Some(pose(2, 2.0)));
+        assert_eq!(remote.pose(Sensor(1)).unwrap(), None);
+        assert_eq!(remote.velocity(Sensor(0)).unwrap(), Some(velocity));
+        assert_eq!(remote.velocity(Sensor(2)).unwrap(), None);
+        assert_eq!(remote.acceleration(Sensor(2)).unwrap(), Some(accel));
+    }
+
+    #[test]
+    fn device_names() {
+        let (device, info) = parse_device("Tracker0@127.0.0.1:3884").unwrap();
+        assert_eq!(device, "Tracker0");
+        assert_eq!(info.server.socket_addr.port(), 3884);
+
+        let (device, _) = parse_device("Button0@tcp://127.0.0.1").unwrap();
+        assert_eq!(device, "Button0");
+
+        assert!(parse_device("127.0.0.1:3883").is_err());
+    }
+}
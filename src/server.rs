@@ -0,0 +1,626 @@
+// Copyright 2021, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! High-level objects for publishing data as a device.
+//!
+//! Each object registers its sender, answers pings with a `ping::Server`,
+//! and packs timestamped reports with the class of service mainline uses for that device,
+//! like the `vrpn_*_Server` classes in mainline VRPN.
+//! The underlying connection still needs to be polled for anything to be sent.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    analog::{AnalogReport, MAX_CHANNELS},
+    buffer_unbuffer::{BufferTo, BufferUnbufferError},
+    button::{ButtonChange, ButtonChangeReport, ButtonState, ButtonStatesReport},
+    data_types::{
+        id_types::{ButtonId, LocalId, MessageTypeId, SenderId, Sensor},
+        ClassOfService, MessageHeader, MessageTypeIdentifier, Quat, SenderName, TimeVal,
+        TypedMessage, TypedMessageBody, Vec3,
+    },
+    handler::{HandlerCode, HandlerHandle, TypedBodylessHandler},
+    ping,
+    tracker::{
+        PoseReport, RequestTrackerToRoom, RequestUnitToSensor, RequestWorkspace,
+        TrackerToRoomReport, UnitToSensorReport, WorkspaceReport,
+    },
+    Connection, Result, VrpnError,
+};
+
+/// Limits how often reports are sent.
+#[derive(Debug, Clone, Copy, Default)]
+struct Throttle {
+    min_interval: Option<Duration>,
+    last_sent: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(min_interval: Option<Duration>) -> Throttle {
+        Throttle {
+            min_interval,
+            last_sent: None,
+        }
+    }
+
+    /// Returns true (and records the send) if enough time has passed since the last send.
+    fn try_send(&mut self, now: Instant) -> bool {
+        let ready = match (self.min_interval, self.last_sent) {
+            (Some(min_interval), Some(last_sent)) => {
+                now.saturating_duration_since(last_sent) >= min_interval
+            }
+            _ => true,
+        };
+        if ready {
+            self.last_sent = Some(now);
+        }
+        ready
+    }
+}
+
+/// Parts common to all device server objects.
+struct ServerCore<C: Connection + 'static> {
+    connection: Arc<C>,
+    sender: LocalId<SenderId>,
+    _ping_server: ping::Server,
+    handlers: Vec<HandlerHandle>,
+}
+
+impl<C: Connection + 'static> ServerCore<C> {
+    fn new(device: &str, connection: Arc<C>) -> Result<ServerCore<C>> {
+        let sender = connection.register_sender(SenderName(Bytes::from(device.to_string())))?;
+        let ping_server = ping::Server::new(sender, Arc::clone(&connection))?;
+        Ok(ServerCore {
+            connection,
+            sender,
+            _ping_server: ping_server,
+            handlers: Vec::new(),
+        })
+    }
+
+    fn register_type<T: TypedMessageBody>(&self) -> Result<LocalId<MessageTypeId>> {
+        match T::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => self.connection.register_type(name),
+            MessageTypeIdentifier::SystemMessageId(id) => Ok(LocalId(id)),
+        }
+    }
+
+    fn pack<T: TypedMessageBody + BufferTo>(
+        &self,
+        time: Option<TimeVal>,
+        body: T,
+        class: ClassOfService,
+    ) -> Result<()> {
+        self.connection
+            .pack_message_body(time, self.sender, body, class)
+    }
+}
+
+impl<C: Connection + 'static> Drop for ServerCore<C> {
+    fn drop(&mut self) {
+        for handle in self.handlers.drain(..) {
+            // Nothing useful to do with an error here.
+            let _ = self.connection.remove_handler(handle);
+        }
+    }
+}
+
+macro_rules! impl_server_common {
+    ($server:ident) => {
+        impl<C: Connection + 'static> $server<C> {
+            /// Get the connection used by this server object, to poll it or share it.
+            pub fn connection(&self) -> &Arc<C> {
+                &self.core.connection
+            }
+
+            /// Get the local ID of the sender (device) this object reports as.
+            pub fn sender(&self) -> LocalId<SenderId> {
+                self.core.sender
+            }
+        }
+    };
+}
+
+#[derive(Debug)]
+struct TrackerServerState {
+    tracker_to_room: TrackerToRoomReport,
+    unit_to_sensor: HashMap<Sensor, UnitToSensorReport>,
+    workspace: WorkspaceReport,
+}
+
+impl Default for TrackerServerState {
+    fn default() -> Self {
+        TrackerServerState {
+            tracker_to_room: TrackerToRoomReport {
+                pos: Vec3::default(),
+                quat: Quat::identity(),
+            },
+            unit_to_sensor: HashMap::new(),
+            workspace: WorkspaceReport {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            },
+        }
+    }
+}
+
+/// Handler that replies to one of the tracker requests using the server state.
+///
+/// The reply types are registered up front: handlers can pack messages,
+/// but the dispatcher is busy while they run, so they can't register anything.
+struct TrackerRequestHandler<C: Connection, R> {
+    connection: Weak<C>,
+    state: Weak<Mutex<TrackerServerState>>,
+    sender: LocalId<SenderId>,
+    reply_type: LocalId<MessageTypeId>,
+    phantom: std::marker::PhantomData<fn(R)>,
+}
+
+impl<C: Connection, R> fmt::Debug for TrackerRequestHandler<C, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TrackerRequestHandler").finish()
+    }
+}
+
+impl<C: Connection, R> TrackerRequestHandler<C, R> {
+    fn reply<T: TypedMessageBody + BufferTo>(
+        &self,
+        make_replies: impl FnOnce(&TrackerServerState) -> Vec<T>,
+    ) -> Result<HandlerCode> {
+        match (self.connection.upgrade(), self.state.upgrade()) {
+            (Some(connection), Some(state)) => {
+                let replies = make_replies(&*state.lock()?);
+                for body in replies {
+                    let msg = TypedMessage::new(None, self.reply_type, self.sender, body);
+                    connection.pack_message(msg, ClassOfService::RELIABLE)?;
+                }
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            _ => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+impl<C: Connection> TypedBodylessHandler for TrackerRequestHandler<C, RequestTrackerToRoom> {
+    type Item = RequestTrackerToRoom;
+    fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
+        self.reply(|state| vec![state.tracker_to_room])
+    }
+}
+
+impl<C: Connection> TypedBodylessHandler for TrackerRequestHandler<C, RequestUnitToSensor> {
+    type Item = RequestUnitToSensor;
+    fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
+        self.reply(|state| {
+            let mut replies: Vec<UnitToSensorReport> =
+                state.unit_to_sensor.values().copied().collect();
+            replies.sort_by_key(|report| report.sensor);
+            replies
+        })
+    }
+}
+
+impl<C: Connection> TypedBodylessHandler for TrackerRequestHandler<C, RequestWorkspace> {
+    type Item = RequestWorkspace;
+    fn handle_typed_bodyless(&mut self, _header: &MessageHeader) -> Result<HandlerCode> {
+        self.reply(|state| vec![state.workspace])
+    }
+}
+
+/// Server-side object for publishing a `vrpn_Tracker` device.
+///
+/// Also answers the tracker-to-room, unit-to-sensor and workspace requests
+/// using the values set on it.
+pub struct TrackerServer<C: Connection + 'static> {
+    core: ServerCore<C>,
+    state: Arc<Mutex<TrackerServerState>>,
+    throttles: Mutex<SensorThrottles>,
+}
+
+#[derive(Debug, Default)]
+struct SensorThrottles {
+    min_interval: Option<Duration>,
+    by_sensor: HashMap<Sensor, Throttle>,
+}
+
+impl<C: Connection + 'static> TrackerServer<C> {
+    /// Publish a tracker named `device` (like `"Tracker0"`) on a connection.
+    pub fn new(device: &str, connection: Arc<C>) -> Result<TrackerServer<C>> {
+        let mut core = ServerCore::new(device, connection)?;
+        let state = Arc::new(Mutex::new(TrackerServerState::default()));
+
+        let handle = core.connection.add_typed_handler(
+            Box::new(TrackerRequestHandler::<C, RequestTrackerToRoom> {
+                connection: Arc::downgrade(&core.connection),
+                state: Arc::downgrade(&state),
+                sender: core.sender,
+                reply_type: core.register_type::<TrackerToRoomReport>()?,
+                phantom: std::marker::PhantomData,
+            }),
+            Some(core.sender),
+        )?;
+        core.handlers.push(handle);
+        let handle = core.connection.add_typed_handler(
+            Box::new(TrackerRequestHandler::<C, RequestUnitToSensor> {
+                connection: Arc::downgrade(&core.connection),
+                state: Arc::downgrade(&state),
+                sender: core.sender,
+                reply_type: core.register_type::<UnitToSensorReport>()?,
+                phantom: std::marker::PhantomData,
+            }),
+            Some(core.sender),
+        )?;
+        core.handlers.push(handle);
+        let handle = core.connection.add_typed_handler(
+            Box::new(TrackerRequestHandler::<C, RequestWorkspace> {
+                connection: Arc::downgrade(&core.connection),
+                state: Arc::downgrade(&state),
+                sender: core.sender,
+                reply_type: core.register_type::<WorkspaceReport>()?,
+                phantom: std::marker::PhantomData,
+            }),
+            Some(core.sender),
+        )?;
+        core.handlers.push(handle);
+
+        Ok(TrackerServer {
+            core,
+            state,
+            throttles: Mutex::new(SensorThrottles::default()),
+        })
+    }
+
+    /// Set the minimum interval between pose reports for each sensor.
+    ///
+    /// Reports arriving sooner are dropped. `None` (the default) sends every report.
+    pub fn set_min_interval(&self, min_interval: Option<Duration>) -> Result<()> {
+        *self.throttles.lock()? = SensorThrottles {
+            min_interval,
+            by_sensor: HashMap::new(),
+        };
+        Ok(())
+    }
+
+    /// Report the pose of a sensor, timestamped `time` (now if `None`).
+    ///
+    /// Returns false if the report was dropped by throttling.
+    pub fn report_pose(
+        &self,
+        time: Option<TimeVal>,
+        sensor: Sensor,
+        pos: Vec3,
+        quat: Quat,
+    ) -> Result<bool> {
+        {
+            let mut throttles = self.throttles.lock()?;
+            let min_interval = throttles.min_interval;
+            let throttle = throttles
+                .by_sensor
+                .entry(sensor)
+                .or_insert_with(|| Throttle::new(min_interval));
+            if !throttle.try_send(Instant::now()) {
+                return Ok(false);
+            }
+        }
+        self.core.pack(
+            time,
+            PoseReport { sensor, pos, quat },
+            ClassOfService::LOW_LATENCY,
+        )?;
+        Ok(true)
+    }
+
+    /// Set the tracker-to-room transform sent in reply to requests.
+    pub fn set_tracker_to_room(&self, pos: Vec3, quat: Quat) -> Result<()> {
+        self.state.lock()?.tracker_to_room = TrackerToRoomReport { pos, quat };
+        Ok(())
+    }
+
+    /// Set the unit-to-sensor transform for a sensor, sent in reply to requests.
+    pub fn set_unit_to_sensor(&self, sensor: Sensor, pos: Vec3, quat: Quat) -> Result<()> {
+        self.state
+            .lock()?
+            .unit_to_sensor
+            .insert(sensor, UnitToSensorReport { sensor, pos, quat });
+        Ok(())
+    }
+
+    /// Set the workspace bounds sent in reply to requests.
+    pub fn set_workspace(&self, min: Vec3, max: Vec3) -> Result<()> {
+        self.state.lock()?.workspace = WorkspaceReport { min, max };
+        Ok(())
+    }
+}
+
+impl_server_common!(TrackerServer);
+
+/// Server-side object for publishing a `vrpn_Button` device.
+///
+/// Changes are sent as they happen, reliably, like mainline does.
+pub struct ButtonServer<C: Connection + 'static> {
+    core: ServerCore<C>,
+    states: Mutex<Vec<ButtonState>>,
+}
+
+impl<C: Connection + 'static> ButtonServer<C> {
+    /// Publish a button device named `device` (like `"Button0"`) with `num_buttons` buttons.
+    pub fn new(device: &str, num_buttons: usize, connection: Arc<C>) -> Result<ButtonServer<C>> {
+        let core = ServerCore::new(device, connection)?;
+        Ok(ButtonServer {
+            core,
+            states: Mutex::new(vec![ButtonState::default(); num_buttons]),
+        })
+    }
+
+    /// Set the state of a button, timestamped `time` (now if `None`).
+    ///
+    /// A change report is only sent if the state actually changed: returns whether one was.
+    pub fn set_button(
+        &self,
+        time: Option<TimeVal>,
+        button: ButtonId,
+        state: ButtonState,
+    ) -> Result<bool> {
+        {
+            let mut states = self.states.lock()?;
+            let current = usize::try_from(button.0)
+                .ok()
+                .and_then(|index| states.get_mut(index))
+                .ok_or(VrpnError::InvalidId(button.0))?;
+            if *current == state {
+                return Ok(false);
+            }
+            *current = state;
+        }
+        self.core.pack(
+            time,
+            ButtonChangeReport {
+                changes: vec![ButtonChange::new(button, state)],
+            },
+            ClassOfService::RELIABLE,
+        )?;
+        Ok(true)
+    }
+
+    /// Get the current states of all buttons.
+    pub fn buttons(&self) -> Result<Vec<ButtonState>> {
+        Ok(self.states.lock()?.clone())
+    }
+
+    /// Send the states of all buttons, timestamped `time` (now if `None`).
+    ///
+    /// Useful right after a client connects.
+    pub fn report_states(&self, time: Option<TimeVal>) -> Result<()> {
+        let states = self.states.lock()?.clone();
+        self.core.pack(
+            time,
+            ButtonStatesReport { states },
+            ClassOfService::RELIABLE,
+        )
+    }
+}
+
+impl_server_common!(ButtonServer);
+
+#[derive(Debug, Default)]
+struct AnalogServerState {
+    channels: Vec<f64>,
+    last_sent: Option<Vec<f64>>,
+    throttle: Throttle,
+}
+
+/// Server-side object for publishing a `vrpn_Analog` device.
+///
+/// Only sends reports when the channel values change.
+pub struct AnalogServer<C: Connection + 'static> {
+    core: ServerCore<C>,
+    state: Mutex<AnalogServerState>,
+}
+
+impl<C: Connection + 'static> AnalogServer<C> {
+    /// Publish an analog device named `device` (like `"Analog0"`).
+    pub fn new(device: &str, connection: Arc<C>) -> Result<AnalogServer<C>> {
+        let core = ServerCore::new(device, connection)?;
+        Ok(AnalogServer {
+            core,
+            state: Mutex::new(AnalogServerState::default()),
+        })
+    }
+
+    /// Set the minimum interval between reports.
+    ///
+    /// Changes arriving sooner are held until `report_pending()` or the next `set_channels()`.
+    /// `None` (the default) sends every change.
+    pub fn set_min_interval(&self, min_interval: Option<Duration>) -> Result<()> {
+        self.state.lock()?.throttle = Throttle::new(min_interval);
+        Ok(())
+    }
+
+    /// Set the values of all channels, timestamped `time` (now if `None`).
+    ///
+    /// Returns whether a report was sent.
+    pub fn set_channels(&self, time: Option<TimeVal>, channels: &[f64]) -> Result<bool> {
+        if channels.len() > MAX_CHANNELS {
+            return Err(BufferUnbufferError::CountExceedsMaximum {
+                count: channels.len(),
+                max: MAX_CHANNELS,
+            }
+            .into());
+        }
+        self.state.lock()?.channels = channels.to_vec();
+        self.report_pending(time)
+    }
+
+    /// Send the current channel values if they have changed since the last report
+    /// and throttling allows, timestamped `time` (now if `None`).
+    ///
+    /// Call this periodically if throttling is enabled, so held changes get sent.
+    /// Returns whether a report was sent.
+    pub fn report_pending(&self, time: Option<TimeVal>) -> Result<bool> {
+        let channels = {
+            let mut state = self.state.lock()?;
+            if state.last_sent.as_ref() == Some(&state.channels)
+                || !state.throttle.try_send(Instant::now())
+            {
+                return Ok(false);
+            }
+            state.last_sent = Some(state.channels.clone());
+            state.channels.clone()
+        };
+        self.core.pack(
+            time,
+            AnalogReport::new(channels),
+            ClassOfService::LOW_LATENCY,
+        )?;
+        Ok(true)
+    }
+
+    /// Get the current channel values.
+    pub fn channels(&self) -> Result<Vec<f64>> {
+        Ok(self.state.lock()?.channels.clone())
+    }
+}
+
+impl_server_common!(AnalogServer);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::add_reply_handler,
+        loopback::{run_until, run_until_ready, LoopbackConnection},
+        remote::{AnalogRemote, ButtonRemote},
+        tracker::{request_tracker_to_room, request_unit_to_sensor, request_workspace},
+    };
+    use futures::StreamExt;
+
+    #[test]
+    fn throttle() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Some(Duration::from_millis(100)));
+        assert!(throttle.try_send(start));
+        assert!(!throttle.try_send(start + Duration::from_millis(50)));
+        assert!(throttle.try_send(start + Duration::from_millis(100)));
+        assert!(!throttle.try_send(start + Duration::from_millis(150)));
+
+        let mut unthrottled = Throttle::default();
+        assert!(unthrottled.try_send(start));
+        assert!(unthrottled.try_send(start));
+    }
+
+    #[test]
+    fn button_server() {
+        use ButtonState::*;
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let buttons = ButtonServer::new("Button0", 3, Arc::clone(&server)).unwrap();
+        let remote = ButtonRemote::new("Button0", Arc::clone(&client)).unwrap();
+
+        assert!(buttons.set_button(None, ButtonId(1), Pressed).unwrap());
+        // Only changes are sent.
+        assert!(!buttons.set_button(None, ButtonId(1), Pressed).unwrap());
+        assert!(buttons.set_button(None, ButtonId(3), Pressed).is_err());
+        assert!(buttons.set_button(None, ButtonId(-1), Pressed).is_err());
+        assert_eq!(
+            buttons.buttons().unwrap(),
+            vec![Released, Pressed, Released]
+        );
+        run_until(&server, &client, || {
+            remote.button(ButtonId(1)).unwrap() == Pressed
+        });
+        assert_eq!(remote.buttons().unwrap(), vec![Released, Pressed]);
+
+        buttons.report_states(None).unwrap();
+        run_until(&server, &client, || remote.buttons().unwrap().len() == 3);
+        assert_eq!(remote.buttons().unwrap(), buttons.buttons().unwrap());
+    }
+
+    #[test]
+    fn analog_server() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let analog = AnalogServer::new("Analog0", Arc::clone(&server)).unwrap();
+        let remote = AnalogRemote::new("Analog0", Arc::clone(&client)).unwrap();
+
+        assert!(analog.set_channels(None, &[0.5, 1.0]).unwrap());
+        // Only changes are sent.
+        assert!(!analog.set_channels(None, &[0.5, 1.0]).unwrap());
+        assert!(analog
+            .set_channels(None, &vec![0.0; MAX_CHANNELS + 1])
+            .is_err());
+        run_until(&server, &client, || !remote.channels().unwrap().is_empty());
+        assert_eq!(remote.channels().unwrap(), vec![0.5, 1.0]);
+
+        // Throttled changes are held until they can be sent.
+        analog
+            .set_min_interval(Some(Duration::from_secs(3600)))
+            .unwrap();
+        assert!(analog.set_channels(None, &[2.0]).unwrap());
+        assert!(!analog.set_channels(None, &[3.0]).unwrap());
+        assert!(!analog.report_pending(None).unwrap());
+        assert_eq!(analog.channels().unwrap(), vec![3.0]);
+        run_until(&server, &client, || remote.channels().unwrap() == vec![2.0]);
+    }
+
+    #[test]
+    fn answers_pings() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let _tracker = TrackerServer::new("Tracker0", Arc::clone(&server)).unwrap();
+        let sender = client
+            .register_sender(SenderName(Bytes::from_static(b"Tracker0")))
+            .unwrap();
+        let pong = add_reply_handler::<ping::Pong, _>(&*client, Some(sender)).unwrap();
+        // Sends the first ping right away.
+        let _ping = ping::Client::new(sender, Arc::clone(&client)).unwrap();
+        let pong = run_until_ready(&server, &client, pong).unwrap();
+        assert_eq!(pong.header.sender, sender.0);
+    }
+
+    #[test]
+    fn answers_tracker_requests() {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let tracker = TrackerServer::new("Tracker0", Arc::clone(&server)).unwrap();
+        let sender = client
+            .register_sender(SenderName(Bytes::from_static(b"Tracker0")))
+            .unwrap();
+
+        let to_room = TrackerToRoomReport {
+            pos: Vec3::new(1.0, 0.0, 0.0),
+            quat: Quat::new(0.5, 0.5, 0.5, 0.5),
+        };
+        tracker
+            .set_tracker_to_room(to_room.pos, to_room.quat)
+            .unwrap();
+        let reply = request_tracker_to_room(&*client, sender).unwrap();
+        let reply = run_until_ready(&server, &client, reply).unwrap();
+        assert_eq!(reply.body, to_room);
+
+        let workspace = WorkspaceReport {
+            min: Vec3::new(-3.0, -3.0, 0.0),
+            max: Vec3::new(3.0, 3.0, 2.5),
+        };
+        tracker.set_workspace(workspace.min, workspace.max).unwrap();
+        let reply = request_workspace(&*client, sender).unwrap();
+        let reply = run_until_ready(&server, &client, reply).unwrap();
+        assert_eq!(reply.body, workspace);
+
+        // One reply per sensor, in order.
+        for sensor in [2, 0] {
+            tracker
+                .set_unit_to_sensor(Sensor(sensor), Vec3::new(0.0, 0.0, 1.0), Quat::identity())
+                .unwrap();
+        }
+        let mut replies = request_unit_to_sensor(&*client, sender).unwrap();
+        for sensor in [0, 2] {
+            let reply = run_until_ready(&server, &client, replies.next()).unwrap();
+            assert_eq!(reply.body.sensor, Sensor(sensor));
+        }
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! A connection using synchronous IO, driven by calling `mainloop()` like mainline's.
+//!
+//! No async runtime is needed: `mainloop()` never blocks,
+//! so call it regularly from your own loop.
+
+use crate::{
+    connection::*, constants::DEFAULT_PORT, sync_io::EndpointSyncTcp, Endpoint, Result, Scheme,
+    ServerInfo, VrpnError,
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+};
+
+/// The listening side of a server connection.
+#[derive(Debug)]
+struct SyncAcceptor {
+    /// `None` once shut down.
+    listener: Option<TcpListener>,
+    max_clients: Option<usize>,
+}
+
+impl SyncAcceptor {
+    /// Whether we can take another client, when already serving `num_clients`.
+    fn has_room_for(&self, num_clients: usize) -> bool {
+        !matches!(self.max_clients, Some(max) if num_clients >= max)
+    }
+
+    /// Accept everyone waiting to connect, without blocking.
+    fn accept(&self) -> Result<Vec<EndpointSyncTcp>> {
+        let mut accepted = Vec::new();
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return Ok(accepted),
+        };
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    eprintln!("Got connection from {:?}", peer);
+                    accepted.push(EndpointSyncTcp::new(stream)?);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(accepted),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// A client or server connection using synchronous IO.
+///
+/// Servers only accept direct TCP connections: they don't listen for the UDP
+/// connection requests that clients using the `x-vrpn` scheme may send instead.
+#[derive(Debug)]
+pub struct SyncConnection {
+    core: ConnectionCore<EndpointSyncTcp>,
+    /// Only used by a server.
+    acceptor: Option<Mutex<SyncAcceptor>>,
+    /// Connected, but still in the handshake.
+    new_endpoints: Mutex<Vec<EndpointSyncTcp>>,
+}
+
+impl SyncConnection {
+    /// Create a new SyncConnection that is a server, listening on `addr`,
+    /// or on all interfaces at the default port if not specified.
+    ///
+    /// Incoming connections are accepted in `mainloop()`.
+    pub fn new_server(addr: Option<SocketAddr>) -> Result<Arc<SyncConnection>> {
+        let addr = addr.unwrap_or_else(|| {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), DEFAULT_PORT)
+        });
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Arc::new(SyncConnection {
+            core: ConnectionCore::new(Vec::new(), None, None),
+            acceptor: Some(Mutex::new(SyncAcceptor {
+                listener: Some(listener),
+                max_clients: None,
+            })),
+            new_endpoints: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Create a new SyncConnection that is a client, connecting to the server right away.
+    ///
+    /// Blocks until connected, but the handshake happens in `mainloop()`.
+    /// With the `x-vrpn` scheme, still connects over TCP,
+    /// but also asks the server to send low-latency messages over UDP.
+    pub fn new_client(server: ServerInfo) -> Result<Arc<SyncConnection>> {
+        let stream = TcpStream::connect(server.socket_addr)?;
+        let udp = match server.scheme {
+            Scheme::UdpAndTcp => Some(UdpSocket::bind(SocketAddr::new(
+                stream.local_addr()?.ip(),
+                0,
+            ))?),
+            Scheme::TcpOnly => None,
+        };
+        let mut endpoint = EndpointSyncTcp::new_with_udp(stream, udp)?;
+        endpoint.send_udp_description()?;
+        Ok(Arc::new(SyncConnection {
+            core: ConnectionCore::new(Vec::new(), None, None),
+            acceptor: None,
+            new_endpoints: Mutex::new(vec![endpoint]),
+        }))
+    }
+
+    /// Get the address a server connection is listening on.
+    ///
+    /// Returns `None` for a client connection.
+    pub fn server_addr(&self) -> Result<Option<SocketAddr>> {
+        let acceptor = match &self.acceptor {
+            Some(acceptor) => acceptor.lock()?,
+            None => return Ok(None),
+        };
+        match &acceptor.listener {
+            Some(listener) => Ok(Some(listener.local_addr()?)),
+            None => Err(VrpnError::OtherMessage(
+                "Server connection has shut down".to_owned(),
+            )),
+        }
+    }
+
+    /// Limit how many clients a server connection serves at once, or `None` for no limit.
+    ///
+    /// Once at the limit, new clients are disconnected right after the handshake,
+    /// until one of the existing clients leaves.
+    /// Has no effect on a client connection.
+    pub fn set_max_clients(&self, max_clients: Option<usize>) -> Result<()> {
+        if let Some(acceptor) = &self.acceptor {
+            acceptor.lock()?.max_clients = max_clients;
+        }
+        Ok(())
+    }
+
+    /// Accept any new clients and move handshakes along, then receive and send on each endpoint
+    /// and dispatch the messages received.
+    ///
+    /// Never blocks: call it regularly, since nothing is sent or received otherwise.
+    pub fn mainloop(&self) -> Result<()> {
+        let shut_down = self.core.is_shut_down();
+        let mut handshaking = self.new_endpoints.lock()?;
+        if shut_down {
+            // Drop any connections still in their handshake.
+            handshaking.clear();
+        }
+
+        // Accept any new clients if we are a server.
+        let mut num_clients = self.endpoints().lock()?.len();
+        let acceptor = match &self.acceptor {
+            Some(acceptor) => {
+                let mut acceptor = acceptor.lock()?;
+                if shut_down {
+                    acceptor.listener = None;
+                }
+                handshaking.extend(acceptor.accept()?);
+                Some(acceptor)
+            }
+            None => None,
+        };
+
+        let mut new_endpoints = Vec::new();
+        for mut endpoint in std::mem::take(&mut *handshaking) {
+            match endpoint.poll_handshake() {
+                Ok(true) => {
+                    if matches!(&acceptor, Some(acceptor) if !acceptor.has_room_for(num_clients)) {
+                        // Dropping the endpoint closes it.
+                        eprintln!(
+                            "Refusing connection: already serving {} clients",
+                            num_clients
+                        );
+                        continue;
+                    }
+                    num_clients += 1;
+                    new_endpoints.push(endpoint);
+                }
+                Ok(false) => handshaking.push(endpoint),
+                Err(e) => {
+                    // A failed handshake only affects that one peer.
+                    eprintln!("Handshake failed: {:?}", e);
+                }
+            }
+        }
+        drop(acceptor);
+        drop(handshaking);
+
+        let endpoints = self.endpoints();
+        let dispatcher = self.dispatcher();
+        let mut dispatcher = dispatcher.lock()?;
+        let mut received = Vec::new();
+        let mut connected = Vec::new();
+        let mut dropped = Vec::new();
+        {
+            let mut endpoints = endpoints.lock()?;
+            for mut endpoint in new_endpoints {
+                endpoint.send_all_descriptions(&dispatcher)?;
+                got_connection_events(&mut dispatcher, endpoints.is_empty(), &mut connected)?;
+                endpoints.push(Some(endpoint));
+            }
+            let mut num_dropped = 0;
+            // Go through and poll each endpoint, "taking" the ones that are closed.
+            for ep in endpoints.iter_mut() {
+                let open = match ep {
+                    Some(endpoint) => match endpoint.poll_collect(&mut dispatcher, &mut received) {
+                        Ok(open) => open,
+                        Err(e) => {
+                            eprintln!("Got endpoint error: {:?}", e);
+                            false
+                        }
+                    },
+                    None => false,
+                };
+                if !open && ep.take().is_some() {
+                    num_dropped += 1;
+                }
+            }
+            // Now, retain only the non-taken endpoints in the vector.
+            endpoints.retain(|ep| ep.is_some());
+            for i in 1..=num_dropped {
+                let last = i == num_dropped && endpoints.is_empty();
+                dropped_connection_events(&mut dispatcher, last, &mut dropped)?;
+            }
+        }
+
+        // Dispatch with the endpoints unlocked, so handlers can pack replies.
+        for msg in connected.iter().chain(&received).chain(&dropped) {
+            dispatcher.call(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Connection for SyncConnection {
+    type SpecificEndpoint = EndpointSyncTcp;
+    fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
+        &self.core
+    }
+
+    /// A client that has lost its server reports itself closed:
+    /// this connection doesn't reconnect.
+    fn status(&self) -> ConnectionStatus {
+        if self.core.is_shut_down() {
+            return ConnectionStatus::Closed;
+        }
+        // A poisoned lock means a panic while polling: treat the connection as gone.
+        let num_endpoints = match self.endpoints().lock() {
+            Ok(endpoints) => endpoints.len(),
+            Err(_) => return ConnectionStatus::Closed,
+        };
+        let handshaking = match self.new_endpoints.lock() {
+            Ok(new_endpoints) => !new_endpoints.is_empty(),
+            Err(_) => return ConnectionStatus::Closed,
+        };
+        if self.acceptor.is_some() {
+            ConnectionStatus::Server(num_endpoints)
+        } else if num_endpoints > 0 {
+            ConnectionStatus::ClientConnected
+        } else if handshaking {
+            ConnectionStatus::ClientConnecting
+        } else {
+            ConnectionStatus::Closed
+        }
+    }
+}
+
+impl ClientConnection for SyncConnection {
+    fn new_client_connection(server: ServerInfo) -> Result<Arc<SyncConnection>> {
+        SyncConnection::new_client(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            id_types::{LocalId, SenderId, Sensor},
+            ClassOfService, Quat, StaticSenderName, TypedMessage, Vec3,
+        },
+        handler::{HandlerCode, TypedHandler},
+        tracker::PoseReport,
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[derive(Debug)]
+    struct TrackerHandler {
+        flag: Arc<AtomicBool>,
+    }
+    impl TypedHandler for TrackerHandler {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, _msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+            self.flag.store(true, Ordering::SeqCst);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    fn new_server() -> (Arc<SyncConnection>, SocketAddr, LocalId<SenderId>) {
+        let server = SyncConnection::new_server(Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        let sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        (server, addr, sender)
+    }
+
+    /// Connect a client that sets its flag when it gets a pose report from "Tracker0".
+    fn new_tracker_client(url: String) -> (Arc<SyncConnection>, Arc<AtomicBool>) {
+        let client = SyncConnection::new_client(url.parse().unwrap()).unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        client
+            .add_typed_handler(
+                Box::new(TrackerHandler {
+                    flag: Arc::clone(&flag),
+                }),
+                Some(sender),
+            )
+            .unwrap();
+        (client, flag)
+    }
+
+    /// Run both mainloops until the condition holds, panicking if it takes too long.
+    fn run_until(connections: &[&Arc<SyncConnection>], condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            for connection in connections {
+                connection.mainloop().unwrap();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn send_report(server: &SyncConnection, sender: LocalId<SenderId>, class: ClassOfService) {
+        server
+            .pack_message_body(
+                None,
+                sender,
+                PoseReport {
+                    sensor: Sensor(0),
+                    pos: Vec3::new(0.0, 0.0, 0.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+                class,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn local_server_and_client() {
+        let (server, addr, server_sender) = new_server();
+        let (client, flag) = new_tracker_client(format!("tcp://{}", addr));
+        assert_eq!(client.status(), ConnectionStatus::ClientConnecting);
+
+        run_until(&[&server, &client], || {
+            send_report(&server, server_sender, ClassOfService::RELIABLE);
+            flag.load(Ordering::SeqCst)
+        });
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+    }
+
+    #[test]
+    fn low_latency_over_udp() {
+        let (server, addr, server_sender) = new_server();
+        let (client, flag) = new_tracker_client(format!("x-vrpn://{}", addr));
+
+        // Low-latency reports go over TCP until the client has described its UDP port,
+        // so wait for that: after it, they can only arrive over UDP.
+        let sends_datagrams = || {
+            server
+                .endpoints()
+                .lock()
+                .unwrap()
+                .iter()
+                .flatten()
+                .any(|endpoint| endpoint.sends_datagrams())
+        };
+        run_until(&[&server, &client], sends_datagrams);
+        run_until(&[&server, &client], || {
+            send_report(&server, server_sender, ClassOfService::LOW_LATENCY);
+            flag.load(Ordering::SeqCst)
+        });
+    }
+
+    #[test]
+    fn shutdown() {
+        let (server, addr, _) = new_server();
+        let (client, _) = new_tracker_client(format!("tcp://{}", addr));
+        run_until(&[&server, &client], || {
+            client.status() == ConnectionStatus::ClientConnected
+        });
+
+        // The client hears the disconnect message and closes.
+        server.shutdown().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Closed);
+        run_until(&[&server, &client], || {
+            client.status() == ConnectionStatus::Closed
+                && server.endpoints().lock().unwrap().is_empty()
+        });
+        assert!(TcpStream::connect(addr).is_err(), "should stop listening");
+    }
+}
@@ -1,38 +1,34 @@
-// Copyright 2018-2019, Collabora, Ltd.
+// Copyright 2018-2022, Collabora, Ltd.
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-//! A simple, synchronous-IO client for testing purposes.
+//! Synchronous (blocking) IO: an endpoint using `std::net` sockets.
 //!
 //! Doesn't use any of the async-io stuff in the vrpn crate,
 //! so this is durable even if Tokio totally changes everything.
+//! The protocol itself is left to `protocol::ProtocolEndpoint`:
+//! this just moves bytes between it and the sockets.
+//! See `sync_connection` for a `Connection` built on this.
 
 extern crate bytes;
 
 use crate::{
-    buffer_unbuffer::{
-        peek_u32, size_requirement::MayContainSizeRequirement, BytesMutExtras, ConstantBufferSize,
-        SizeRequirement,
-    },
+    buffer_unbuffer::{BytesMutExtras, ConstantBufferSize},
+    constants::UDP_BUFLEN,
     data_types::{
-        self, id_types::SequenceNumber, CookieData, GenericMessage, Message, MessageSize,
-        SequencedGenericMessage,
+        ClassOfService, CookieData, GenericMessage, LogMode, TypedMessage, UdpDescription,
     },
-    endpoint::SystemCommand,
+    endpoint::{ExtendedSystemCommand, SystemCommand},
     error::VrpnError,
-    handle_system_command, parse_system_message,
+    protocol::ProtocolEndpoint,
     translation_table::TranslationTables,
     Endpoint, EndpointGeneric, TypeDispatcher,
 };
 use bytes::BytesMut;
 use std::{
     io::{self, Read, Write},
-    net::TcpStream,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        mpsc,
-    },
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, UdpSocket},
+    time::Instant,
 };
 
 /// Write a cookie to a synchronous sink implementing Write.
@@ -55,127 +51,250 @@ where
     Ok(buf)
 }
 
+/// Bind a UDP socket on an ephemeral port, of the same address family as `addr`.
+fn bind_udp_like(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let any: IpAddr = if addr.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    UdpSocket::bind(SocketAddr::new(any, 0))
+}
+
+/// An endpoint over a TCP stream, with an optional UDP socket for low-latency messages.
+///
+/// Never blocks: reading and writing happen, as far as the sockets allow,
+/// in `poll_handshake` and `poll_collect`.
 #[derive(Debug)]
 pub struct EndpointSyncTcp {
-    translation: TranslationTables,
+    protocol: ProtocolEndpoint,
     stream: TcpStream,
-    system_rx: mpsc::Receiver<SystemCommand>,
-    system_tx: mpsc::Sender<SystemCommand>,
-    seq: AtomicUsize,
+    /// Our inbound UDP socket, if we have one.
+    udp_rx: Option<UdpSocket>,
+    /// Where the peer should send us datagrams, if we have an inbound UDP socket.
+    udp_description: Option<UdpDescription>,
+    /// Connected to where the peer asked us to send it datagrams.
+    udp_tx: Option<UdpSocket>,
 }
 
 impl EndpointSyncTcp {
-    pub fn new(stream: TcpStream) -> EndpointSyncTcp {
-        let (system_tx, system_rx) = mpsc::channel();
-        EndpointSyncTcp {
-            translation: TranslationTables::new(),
-            stream,
-            system_tx,
-            system_rx,
-            seq: AtomicUsize::new(0),
-        }
+    /// Wrap a newly-connected TCP stream: the handshake happens as it is polled.
+    pub fn new(stream: TcpStream) -> Result<EndpointSyncTcp, VrpnError> {
+        EndpointSyncTcp::new_with_udp(stream, None)
     }
 
-    fn read_single_message(&mut self) -> Result<SequencedGenericMessage, VrpnError> {
-        self.stream
-            .set_read_timeout(Some(Duration::from_millis(1)))?;
-        let mut buf = BytesMut::with_capacity(24);
-
-        // Peek the message header and padding
-        self.stream.peek(buf.as_mut()).map_err(|e| {
-            use io::ErrorKind::*;
-            match e.kind() {
-                WouldBlock | TimedOut => VrpnError::from(SizeRequirement::Unknown),
-                // Not a "need more data"
-                _ => e.into(),
+    /// Wrap a newly-connected TCP stream,
+    /// along with a socket to receive low-latency messages on, if any.
+    pub(crate) fn new_with_udp(
+        stream: TcpStream,
+        udp: Option<UdpSocket>,
+    ) -> Result<EndpointSyncTcp, VrpnError> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        // Advertise the address the peer reaches us at over TCP, with our inbound UDP port.
+        let udp_description = match &udp {
+            Some(udp) => {
+                udp.set_nonblocking(true)?;
+                let ip = stream.local_addr()?.ip();
+                Some(UdpDescription::new(SocketAddr::new(
+                    ip,
+                    udp.local_addr()?.port(),
+                )))
             }
-        })?;
+            None => None,
+        };
+        Ok(EndpointSyncTcp {
+            protocol: ProtocolEndpoint::new(LogMode::NONE)?,
+            stream,
+            udp_rx: udp,
+            udp_description,
+            udp_tx: None,
+        })
+    }
 
-        // Peek the size field, to compute the MessageSize.
-        let total_len = peek_u32(&buf.clone().freeze()).unwrap();
-        let size = MessageSize::try_from_length_field(total_len)?;
+    /// Tell the peer where to send us low-latency messages, if we have an inbound UDP socket.
+    ///
+    /// Clients send this right after connecting.
+    pub(crate) fn send_udp_description(&mut self) -> Result<(), VrpnError> {
+        if let Some(desc) = self.udp_description.clone() {
+            self.buffer_message(TypedMessage::from(desc), ClassOfService::RELIABLE)?;
+        }
+        Ok(())
+    }
 
-        // Read the body of the message
-        let mut msg_buf = BytesMut::with_capacity(size.padded_message_size());
-        self.stream.read_exact(msg_buf.as_mut())?;
-        let mut msg_buf = msg_buf.freeze();
+    /// Whether low-latency messages go to the peer as datagrams.
+    #[cfg(test)]
+    pub(crate) fn sends_datagrams(&self) -> bool {
+        self.udp_tx.is_some()
+    }
 
-        // Unbuffer the message.
-        let result = SequencedGenericMessage::try_read_from_buf(&mut msg_buf)?;
-        Ok(result)
+    /// Start sending low-latency messages as datagrams to `peer`,
+    /// from a new UDP socket connected to it.
+    fn set_udp_destination(&mut self, peer: SocketAddr) -> Result<(), VrpnError> {
+        let socket = bind_udp_like(peer)?;
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        self.udp_tx = Some(socket);
+        self.protocol.enable_datagrams();
+        Ok(())
     }
 
-    pub fn poll_endpoint(&mut self, mut dispatcher: &mut TypeDispatcher) -> Result<(), VrpnError> {
+    /// Hand the protocol the time and whatever the peer has sent over TCP, without blocking.
+    fn read_available(&mut self) -> Result<(), VrpnError> {
+        self.protocol.handle_timeout(Instant::now())?;
+        let mut buf = [0u8; 4096];
         loop {
-            match self.read_single_message() {
-                Ok(msg) => {
-                    let msg = self.map_remote_message_to_local(msg.into_inner())?;
-                    if msg.is_system_message() {
-                        self.send_system_change(parse_system_message(msg)?)?;
-                    } else {
-                        dispatcher.call(&msg)?;
-                    }
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.protocol.handle_eof();
+                    return Ok(());
                 }
-                Err(e) => {
-                    if (&e).try_get_size_requirement().is_some() {
-                        break;
-                    }
-                    return Err(e);
+                Ok(n) => self.protocol.handle_bytes(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Send as much of what the protocol has queued as the sockets will take, without blocking.
+    ///
+    /// Datagrams are best-effort: one that fails to send is dropped.
+    fn write_available(&mut self) -> Result<(), VrpnError> {
+        while let Some(datagram) = self.protocol.take_datagram() {
+            if let Some(socket) = &self.udp_tx {
+                if let Err(e) = socket.send(&datagram) {
+                    eprintln!("Dropping datagram: {}", e);
                 }
             }
         }
-        // Now, process the system commands that have been queued.
-        loop {
-            match self.system_rx.recv_timeout(Duration::from_micros(1)) {
-                Ok(cmd) => {
-                    if handle_system_command(&mut dispatcher, self.translation_tables_mut(), cmd)?
-                        .is_some()
-                    {
-                        // we don't handle any other system commands in this endpoint right now
+        while !self.protocol.output().is_empty() {
+            match self.stream.write(self.protocol.output()) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(n) => self.protocol.consume_output(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the handshake along, without blocking.
+    ///
+    /// Returns whether it is complete,
+    /// or an error if it failed or the peer closed the connection first.
+    pub fn poll_handshake(&mut self) -> Result<bool, VrpnError> {
+        self.read_available()?;
+        let done = self.protocol.handle_handshake()?;
+        self.write_available()?;
+        if !done && self.protocol.is_closed() {
+            return Err(VrpnError::EndpointClosed);
+        }
+        Ok(done)
+    }
+
+    /// Receive everything the peer has sent, handling system messages and appending
+    /// other received messages to `received` for the caller to dispatch,
+    /// then send as much of what is queued as the sockets will take.
+    ///
+    /// Never blocks. Returns false once the endpoint has closed,
+    /// or finished closing after a disconnect.
+    pub(crate) fn poll_collect(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+    ) -> Result<bool, VrpnError> {
+        let mut extended = Vec::new();
+        self.read_available()?;
+        self.protocol.process(dispatcher, received, &mut extended)?;
+
+        if let Some(udp) = self.udp_rx.take() {
+            let mut datagram = [0u8; UDP_BUFLEN];
+            loop {
+                match udp.recv_from(&mut datagram) {
+                    Ok((n, addr)) => {
+                        if let Err(e) = self.protocol.handle_datagram(
+                            &datagram[..n],
+                            dispatcher,
+                            received,
+                            &mut extended,
+                        ) {
+                            eprintln!("Dropping low-latency messages from {}: {}", addr, e);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.udp_rx = Some(udp);
+                        break;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        // The low-latency channel failing doesn't close us.
+                        eprintln!("Low-latency channel failed: {}", e);
+                        break;
                     }
                 }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // no more commands
-                    break;
+            }
+        }
+
+        for cmd in extended {
+            match cmd {
+                ExtendedSystemCommand::UdpDescription(desc) => {
+                    self.set_udp_destination(desc.socket_address)?;
                 }
-                Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    // todo how to handle this? Might never happen.
-                    break;
+                ExtendedSystemCommand::LogDescription(names) => {
+                    eprintln!("Ignoring request to keep logs {:?}", names);
                 }
+                // The protocol handles this one itself.
+                ExtendedSystemCommand::DisconnectMessage => {}
             }
         }
-        Ok(())
+
+        // Flush anything queued, including replies to what we just handled.
+        self.write_available()?;
+        if self.protocol.is_closed() {
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Receive and dispatch everything the peer has sent,
+    /// and send as much of what is queued as the sockets will take.
+    ///
+    /// Never blocks. Returns false once the endpoint has closed.
+    pub fn poll_endpoint(&mut self, dispatcher: &mut TypeDispatcher) -> Result<bool, VrpnError> {
+        let mut received = Vec::new();
+        let open = self.poll_collect(dispatcher, &mut received)?;
+        for msg in &received {
+            dispatcher.call(msg)?;
+        }
+        Ok(open)
     }
 }
 
 impl Endpoint for EndpointSyncTcp {
     fn translation_tables(&self) -> &TranslationTables {
-        &self.translation
+        self.protocol.translation_tables()
     }
 
     fn translation_tables_mut(&mut self) -> &mut TranslationTables {
-        &mut self.translation
+        self.protocol.translation_tables_mut()
     }
 
     fn send_system_change(&self, message: SystemCommand) -> Result<(), VrpnError> {
-        println!("send_system_change {:?}", message);
-        self.system_tx
-            .send(message)
-            .map_err(|e| VrpnError::OtherMessage(e.to_string()))?;
-        Ok(())
+        self.protocol.send_system_change(message)
+    }
+
+    fn disconnect(&mut self) -> Result<(), VrpnError> {
+        self.protocol.disconnect()
     }
 
     fn buffer_generic_message(
         &mut self,
         msg: GenericMessage,
-        _class: data_types::ClassOfService,
+        class: ClassOfService,
     ) -> Result<(), VrpnError> {
-        // Ignore class of service here
-        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
-        let sequenced = msg.into_sequenced_message(SequenceNumber(seq as u32));
-        let buf = sequenced.try_into_buf()?;
-
-        self.stream.write_all(&buf[..])?;
-        Ok(())
+        self.protocol.buffer_generic_message(msg, class)
     }
 }
@@ -8,12 +8,16 @@ use crate::{
     buffer_unbuffer::{
         buffer::{BufferResult, BufferTo},
         unbuffer::{check_unbuffer_remaining, UnbufferFrom, UnbufferResult},
-        ConstantBufferSize,
+        ConstantBufferSize, EmptyMessage,
     },
     data_types::{
-        id_types::Sensor, message::TypedMessageBody, name_types::StaticMessageTypeName,
-        MessageTypeIdentifier, Quat, Vec3,
+        id_types::{LocalId, SenderId, Sensor},
+        message::TypedMessageBody,
+        name_types::StaticMessageTypeName,
+        ClassOfService, MessageTypeIdentifier, Quat, Vec3,
     },
+    handler::{add_reply_handler, add_reply_stream_handler, Reply, ReplyStream},
+    Connection, Result,
 };
 use bytes::{Buf, BufMut};
 
@@ -66,9 +70,13 @@ impl UnbufferFrom for PoseReport {
 /// Linear and angular velocity for trackers.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct VelocityReport {
+    /// Sensor id
     pub sensor: Sensor,
+    /// Linear velocity
     pub vel: Vec3,
+    /// Angular velocity, as the rotation over `vel_quat_dt`
     pub vel_quat: Quat,
+    /// Time interval for `vel_quat`, in seconds
     pub vel_quat_dt: f64,
 }
 
@@ -77,12 +85,54 @@ impl TypedMessageBody for VelocityReport {
         MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker Velocity"));
 }
 
+impl ConstantBufferSize for VelocityReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+            + f64::constant_buffer_size()
+    }
+}
+
+impl BufferTo for VelocityReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.sensor.buffer_to(buf)?;
+        // padding
+        self.sensor.buffer_to(buf)?;
+        self.vel.buffer_to(buf)?;
+        self.vel_quat.buffer_to(buf)?;
+        self.vel_quat_dt.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for VelocityReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let sensor = Sensor::unbuffer_from(buf)?;
+        let _ = Sensor::unbuffer_from(buf)?;
+        let vel = Vec3::unbuffer_from(buf)?;
+        let vel_quat = Quat::unbuffer_from(buf)?;
+        let vel_quat_dt = f64::unbuffer_from(buf)?;
+        Ok(VelocityReport {
+            sensor,
+            vel,
+            vel_quat,
+            vel_quat_dt,
+        })
+    }
+}
+
 /// Linear and angular acceleration for trackers.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct AccelReport {
+    /// Sensor id
     pub sensor: Sensor,
+    /// Linear acceleration
     pub acc: Vec3,
+    /// Angular acceleration, as the change in rotation over `acc_quat_dt`
     pub acc_quat: Quat,
+    /// Time interval for `acc_quat`, in seconds
     pub acc_quat_dt: f64,
 }
 
@@ -90,3 +140,558 @@ impl TypedMessageBody for AccelReport {
     const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
         MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker Acceleration"));
 }
+
+impl ConstantBufferSize for AccelReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+            + f64::constant_buffer_size()
+    }
+}
+
+impl BufferTo for AccelReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.sensor.buffer_to(buf)?;
+        // padding
+        self.sensor.buffer_to(buf)?;
+        self.acc.buffer_to(buf)?;
+        self.acc_quat.buffer_to(buf)?;
+        self.acc_quat_dt.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for AccelReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let sensor = Sensor::unbuffer_from(buf)?;
+        let _ = Sensor::unbuffer_from(buf)?;
+        let acc = Vec3::unbuffer_from(buf)?;
+        let acc_quat = Quat::unbuffer_from(buf)?;
+        let acc_quat_dt = f64::unbuffer_from(buf)?;
+        Ok(AccelReport {
+            sensor,
+            acc,
+            acc_quat,
+            acc_quat_dt,
+        })
+    }
+}
+
+/// Request to reset the origin of the tracker to the current pose.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ResetOrigin;
+
+impl EmptyMessage for ResetOrigin {}
+impl TypedMessageBody for ResetOrigin {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker Reset_Origin"));
+}
+
+/// Request for the tracker-to-room transform, answered with a `TrackerToRoomReport`.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestTrackerToRoom;
+
+impl EmptyMessage for RequestTrackerToRoom {}
+impl TypedMessageBody for RequestTrackerToRoom {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticMessageTypeName(b"vrpn_Tracker Request_Tracker_To_Room"),
+    );
+}
+
+/// Transform from tracker space to room space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrackerToRoomReport {
+    /// Position
+    pub pos: Vec3,
+    /// Orientation
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for TrackerToRoomReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker To_Room"));
+}
+
+impl ConstantBufferSize for TrackerToRoomReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() + Quat::constant_buffer_size()
+    }
+}
+
+impl BufferTo for TrackerToRoomReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.pos.buffer_to(buf)?;
+        self.quat.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for TrackerToRoomReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let pos = Vec3::unbuffer_from(buf)?;
+        let quat = Quat::unbuffer_from(buf)?;
+        Ok(TrackerToRoomReport { pos, quat })
+    }
+}
+
+/// Request for the unit-to-sensor transforms, answered with one `UnitToSensorReport` per sensor.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestUnitToSensor;
+
+impl EmptyMessage for RequestUnitToSensor {}
+impl TypedMessageBody for RequestUnitToSensor {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticMessageTypeName(b"vrpn_Tracker Request_Unit_To_Sensor"),
+    );
+}
+
+/// Transform from a sensor's unit space to the space it reports in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UnitToSensorReport {
+    /// Sensor id
+    pub sensor: Sensor,
+    /// Position
+    pub pos: Vec3,
+    /// Orientation
+    pub quat: Quat,
+}
+
+impl TypedMessageBody for UnitToSensorReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticMessageTypeName(b"vrpn_Tracker Unit_To_Sensor"),
+    );
+}
+
+impl ConstantBufferSize for UnitToSensorReport {
+    fn constant_buffer_size() -> usize {
+        Sensor::constant_buffer_size() * 2
+            + Vec3::constant_buffer_size()
+            + Quat::constant_buffer_size()
+    }
+}
+
+impl BufferTo for UnitToSensorReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.sensor.buffer_to(buf)?;
+        // padding
+        self.sensor.buffer_to(buf)?;
+        self.pos.buffer_to(buf)?;
+        self.quat.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for UnitToSensorReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let sensor = Sensor::unbuffer_from(buf)?;
+        let _ = Sensor::unbuffer_from(buf)?;
+        let pos = Vec3::unbuffer_from(buf)?;
+        let quat = Quat::unbuffer_from(buf)?;
+        Ok(UnitToSensorReport { sensor, pos, quat })
+    }
+}
+
+/// Request for the tracker workspace bounds, answered with a `WorkspaceReport`.
+///
+/// Has no body.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RequestWorkspace;
+
+impl EmptyMessage for RequestWorkspace {}
+impl TypedMessageBody for RequestWorkspace {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier = MessageTypeIdentifier::UserMessageName(
+        StaticMessageTypeName(b"vrpn_Tracker Request_Tracker_Workspace"),
+    );
+}
+
+/// Axis-aligned bounding box of the tracker workspace.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WorkspaceReport {
+    /// Minimum corner
+    pub min: Vec3,
+    /// Maximum corner
+    pub max: Vec3,
+}
+
+impl TypedMessageBody for WorkspaceReport {
+    const MESSAGE_IDENTIFIER: MessageTypeIdentifier =
+        MessageTypeIdentifier::UserMessageName(StaticMessageTypeName(b"vrpn_Tracker Workspace"));
+}
+
+impl ConstantBufferSize for WorkspaceReport {
+    fn constant_buffer_size() -> usize {
+        Vec3::constant_buffer_size() * 2
+    }
+}
+
+impl BufferTo for WorkspaceReport {
+    fn buffer_to<T: BufMut>(&self, buf: &mut T) -> BufferResult {
+        self.min.buffer_to(buf)?;
+        self.max.buffer_to(buf)?;
+        Ok(())
+    }
+}
+
+impl UnbufferFrom for WorkspaceReport {
+    fn unbuffer_from<T: Buf>(buf: &mut T) -> UnbufferResult<Self> {
+        check_unbuffer_remaining(buf, Self::constant_buffer_size())?;
+        let min = Vec3::unbuffer_from(buf)?;
+        let max = Vec3::unbuffer_from(buf)?;
+        Ok(WorkspaceReport { min, max })
+    }
+}
+
+/// Ask the tracker `sender` to reset its origin to the current pose.
+pub fn reset_origin<C: Connection>(connection: &C, sender: LocalId<SenderId>) -> Result<()> {
+    connection.pack_message_body(None, sender, ResetOrigin, ClassOfService::RELIABLE)
+}
+
+/// Ask the tracker `sender` for its tracker-to-room transform.
+///
+/// The returned future resolves once the reply has been dispatched,
+/// so the connection must keep being polled.
+pub fn request_tracker_to_room<C: Connection>(
+    connection: &C,
+    sender: LocalId<SenderId>,
+) -> Result<Reply<TrackerToRoomReport>> {
+    let reply = add_reply_handler(connection, Some(sender))?;
+    connection.pack_message_body(None, sender, RequestTrackerToRoom, ClassOfService::RELIABLE)?;
+    Ok(reply)
+}
+
+/// Ask the tracker `sender` for its unit-to-sensor transforms.
+///
+/// The tracker replies with one message per sensor, so this returns a stream:
+/// the connection must keep being polled for its items to arrive.
+pub fn request_unit_to_sensor<C: Connection>(
+    connection: &C,
+    sender: LocalId<SenderId>,
+) -> Result<ReplyStream<UnitToSensorReport>> {
+    let replies = add_reply_stream_handler(connection, Some(sender))?;
+    connection.pack_message_body(None, sender, RequestUnitToSensor, ClassOfService::RELIABLE)?;
+    Ok(replies)
+}
+
+/// Ask the tracker `sender` for its workspace bounds.
+///
+/// The returned future resolves once the reply has been dispatched,
+/// so the connection must keep being polled.
+pub fn request_workspace<C: Connection>(
+    connection: &C,
+    sender: LocalId<SenderId>,
+) -> Result<Reply<WorkspaceReport>> {
+    let reply = add_reply_handler(connection, Some(sender))?;
+    connection.pack_message_body(None, sender, RequestWorkspace, ClassOfService::RELIABLE)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::BytesMutExtras,
+        data_types::{id_types::MessageTypeId, GenericMessage, SenderName, TypedMessage},
+        handler::{Handler, HandlerCode},
+        loopback::{run_until, run_until_ready, LoopbackConnection},
+    };
+    use bytes::{Bytes, BytesMut};
+    use futures::StreamExt;
+    use std::sync::{Arc, Mutex, Weak};
+
+    // Hand-built, not captured: round values laid out following mainline's
+    // vrpn_Tracker::encode_vel_to and encode_acc_to, including the repeated sensor id
+    // used as padding, and the quaternion in x, y, z, w order.
+    const VELOCITY: [u8; 72] = hex!(
+        "00 00 00 01 00 00 00 01"
+        "3f f0 00 00 00 00 00 00 40 00 00 00 00 00 00 00 bf e0 00 00 00 00 00 00"
+        "00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 3f f0 00 00 00 00 00 00"
+        "3f d0 00 00 00 00 00 00"
+    );
+
+    const ACCEL: [u8; 72] = hex!(
+        "00 00 00 02 00 00 00 02"
+        "3f e0 00 00 00 00 00 00 00 00 00 00 00 00 00 00 bf f0 00 00 00 00 00 00"
+        "3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00 3f e0 00 00 00 00 00 00"
+        "3f c0 00 00 00 00 00 00"
+    );
+
+    fn velocity() -> VelocityReport {
+        VelocityReport {
+            sensor: Sensor(1),
+            vel: Vec3::new(1.0, 2.0, -0.5),
+            vel_quat: Quat::identity(),
+            vel_quat_dt: 0.25,
+        }
+    }
+
+    fn accel() -> AccelReport {
+        AccelReport {
+            sensor: Sensor(2),
+            acc: Vec3::new(0.5, 0.0, -1.0),
+            acc_quat: Quat::new(0.5, 0.5, 0.5, 0.5),
+            acc_quat_dt: 0.125,
+        }
+    }
+
+    #[test]
+    fn velocity_unbuffer() {
+        let mut buf = Bytes::from_static(&VELOCITY);
+        assert_eq!(VelocityReport::unbuffer_from(&mut buf).unwrap(), velocity());
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn velocity_buffer() {
+        assert_eq!(VelocityReport::constant_buffer_size(), VELOCITY.len());
+        let buf = BytesMut::allocate_and_buffer(velocity()).unwrap();
+        assert_eq!(&buf[..], &VELOCITY[..]);
+    }
+
+    #[test]
+    fn accel_unbuffer() {
+        let mut buf = Bytes::from_static(&ACCEL);
+        assert_eq!(AccelReport::unbuffer_from(&mut buf).unwrap(), accel());
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn accel_buffer() {
+        assert_eq!(AccelReport::constant_buffer_size(), ACCEL.len());
+        let buf = BytesMut::allocate_and_buffer(accel()).unwrap();
+        assert_eq!(&buf[..], &ACCEL[..]);
+    }
+
+    #[test]
+    fn transform_roundtrip() {
+        let report = UnitToSensorReport {
+            sensor: Sensor(3),
+            pos: Vec3::new(0.0, 1.0, 2.0),
+            quat: Quat::identity(),
+        };
+        let mut buf = BytesMut::allocate_and_buffer(report).unwrap().freeze();
+        assert_eq!(buf.len(), UnitToSensorReport::constant_buffer_size());
+        assert_eq!(UnitToSensorReport::unbuffer_from(&mut buf).unwrap(), report);
+
+        let report = TrackerToRoomReport {
+            pos: Vec3::new(-1.0, 0.5, 0.0),
+            quat: Quat::new(0.5, 0.5, 0.5, 0.5),
+        };
+        let mut buf = BytesMut::allocate_and_buffer(report).unwrap().freeze();
+        assert_eq!(buf.len(), TrackerToRoomReport::constant_buffer_size());
+        assert_eq!(
+            TrackerToRoomReport::unbuffer_from(&mut buf).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn workspace_fixture() {
+        let mut buf = Bytes::from_static(&hex!(
+            "bf f0 00 00 00 00 00 00 bf f0 00 00 00 00 00 00 00 00 00 00 00 00 00 00"
+            "3f f0 00 00 00 00 00 00 3f f0 00 00 00 00 00 00 40 00 00 00 00 00 00 00"
+        ));
+        assert_eq!(
+            WorkspaceReport::unbuffer_from(&mut buf).unwrap(),
+            WorkspaceReport {
+                min: Vec3::new(-1.0, -1.0, 0.0),
+                max: Vec3::new(1.0, 1.0, 2.0)
+            }
+        );
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = Bytes::from_static(&VELOCITY[..64]);
+        assert!(VelocityReport::unbuffer_from(&mut buf).is_err());
+    }
+
+    type PackReply =
+        fn(&LoopbackConnection, LocalId<SenderId>, LocalId<MessageTypeId>) -> Result<()>;
+
+    /// Answers one request type the way a mainline tracker server would,
+    /// using the names mainline puts on the wire rather than our constants.
+    ///
+    /// The reply type is registered up front, since handlers can't register anything.
+    struct MainlineResponder {
+        connection: Weak<LoopbackConnection>,
+        sender: LocalId<SenderId>,
+        reply: Option<(LocalId<MessageTypeId>, PackReply)>,
+        requests: Arc<Mutex<usize>>,
+    }
+
+    impl Handler for MainlineResponder {
+        fn handle(&mut self, _msg: &GenericMessage) -> Result<HandlerCode> {
+            *self.requests.lock()? += 1;
+            if let (Some(connection), Some((reply_type, pack))) =
+                (self.connection.upgrade(), self.reply)
+            {
+                pack(&connection, self.sender, reply_type)?;
+            }
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    fn pack_reply<T: TypedMessageBody + BufferTo>(
+        connection: &LoopbackConnection,
+        sender: LocalId<SenderId>,
+        reply_type: LocalId<MessageTypeId>,
+        body: T,
+    ) -> Result<()> {
+        connection.pack_message(
+            TypedMessage::new(None, reply_type, sender, body),
+            ClassOfService::RELIABLE,
+        )
+    }
+
+    fn to_room_reply(
+        connection: &LoopbackConnection,
+        sender: LocalId<SenderId>,
+        reply_type: LocalId<MessageTypeId>,
+    ) -> Result<()> {
+        let body = TrackerToRoomReport {
+            pos: Vec3::new(1.0, 2.0, 3.0),
+            quat: Quat::identity(),
+        };
+        pack_reply(connection, sender, reply_type, body)
+    }
+
+    fn unit_to_sensor_replies(
+        connection: &LoopbackConnection,
+        sender: LocalId<SenderId>,
+        reply_type: LocalId<MessageTypeId>,
+    ) -> Result<()> {
+        for sensor in 0..2 {
+            let body = UnitToSensorReport {
+                sensor: Sensor(sensor),
+                pos: Vec3::new(f64::from(sensor), 0.0, 0.0),
+                quat: Quat::identity(),
+            };
+            pack_reply(connection, sender, reply_type, body)?;
+        }
+        Ok(())
+    }
+
+    fn workspace_reply(
+        connection: &LoopbackConnection,
+        sender: LocalId<SenderId>,
+        reply_type: LocalId<MessageTypeId>,
+    ) -> Result<()> {
+        let body = WorkspaceReport {
+            min: Vec3::new(-2.0, -2.0, 0.0),
+            max: Vec3::new(2.0, 2.0, 3.0),
+        };
+        pack_reply(connection, sender, reply_type, body)
+    }
+
+    /// A server connection answering the request named `request`
+    /// with the messages named in `reply`, if any,
+    /// and a client connection to it with "Tracker0" registered.
+    ///
+    /// Also returns the number of requests the server has received.
+    fn mainline_server(
+        request: &'static [u8],
+        reply: Option<(&'static [u8], PackReply)>,
+    ) -> (
+        Arc<LoopbackConnection>,
+        Arc<LoopbackConnection>,
+        LocalId<SenderId>,
+        Arc<Mutex<usize>>,
+    ) {
+        let (server, client) = LoopbackConnection::pair().unwrap();
+        let server_sender = server
+            .register_sender(SenderName(Bytes::from_static(b"Tracker0")))
+            .unwrap();
+        let request_type = server
+            .register_type(StaticMessageTypeName(request))
+            .unwrap();
+        let reply = reply.map(|(name, pack)| {
+            let reply_type = server.register_type(StaticMessageTypeName(name)).unwrap();
+            (reply_type, pack)
+        });
+        let requests = Arc::new(Mutex::new(0));
+        server
+            .add_handler(
+                Box::new(MainlineResponder {
+                    connection: Arc::downgrade(&server),
+                    sender: server_sender,
+                    reply,
+                    requests: Arc::clone(&requests),
+                }),
+                Some(request_type),
+                Some(server_sender),
+            )
+            .unwrap();
+        let sender = client
+            .register_sender(SenderName(Bytes::from_static(b"Tracker0")))
+            .unwrap();
+        (server, client, sender, requests)
+    }
+
+    #[test]
+    fn reset_origin_request() {
+        let (server, client, sender, requests) =
+            mainline_server(b"vrpn_Tracker Reset_Origin", None);
+        reset_origin(&*client, sender).unwrap();
+        run_until(&server, &client, || *requests.lock().unwrap() > 0);
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn tracker_to_room_request() {
+        let (server, client, sender, requests) = mainline_server(
+            b"vrpn_Tracker Request_Tracker_To_Room",
+            Some((b"vrpn_Tracker To_Room", to_room_reply as PackReply)),
+        );
+        let reply = request_tracker_to_room(&*client, sender).unwrap();
+        let reply = run_until_ready(&server, &client, reply).unwrap();
+        assert_eq!(reply.body.pos, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn unit_to_sensor_request() {
+        let (server, client, sender, requests) = mainline_server(
+            b"vrpn_Tracker Request_Unit_To_Sensor",
+            Some((
+                b"vrpn_Tracker Unit_To_Sensor",
+                unit_to_sensor_replies as PackReply,
+            )),
+        );
+        let mut replies = request_unit_to_sensor(&*client, sender).unwrap();
+        for sensor in 0..2 {
+            let reply = run_until_ready(&server, &client, replies.next()).unwrap();
+            assert_eq!(reply.body.sensor, Sensor(sensor));
+            assert_eq!(reply.body.pos, Vec3::new(f64::from(sensor), 0.0, 0.0));
+        }
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn workspace_request() {
+        let (server, client, sender, requests) = mainline_server(
+            b"vrpn_Tracker Request_Tracker_Workspace",
+            Some((b"vrpn_Tracker Workspace", workspace_reply as PackReply)),
+        );
+        let reply = request_workspace(&*client, sender).unwrap();
+        let reply = run_until_ready(&server, &client, reply).unwrap();
+        assert_eq!(
+            reply.body,
+            WorkspaceReport {
+                min: Vec3::new(-2.0, -2.0, 0.0),
+                max: Vec3::new(2.0, 2.0, 3.0),
+            }
+        );
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+}
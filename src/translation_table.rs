@@ -7,7 +7,7 @@
 use std::convert::TryFrom;
 
 use crate::{
-    data_types::{id_types::*, GenericMessage},
+    data_types::{id_types::*, GenericMessage, MessageHeader, MessageTypeName, SenderName},
     type_dispatcher::TryIntoDescriptionMessage,
     Result, VrpnError,
 };
@@ -160,6 +160,24 @@ impl<T: UnwrappedId> TranslationTable<T> {
     }
 }
 
+#[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+impl<T: TryIntoDescriptionMessage + UnwrappedId> TranslationTable<T> {
+    /// Re-create the description messages the peer sent to fill this table,
+    /// with their remote IDs.
+    pub(crate) fn pack_remote_descriptions(&self) -> Result<Vec<GenericMessage>> {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|entry| {
+                entry
+                    .remote_id
+                    .into_id()
+                    .try_into_description_message(entry.name.clone())
+            })
+            .collect()
+    }
+}
+
 /// A type owning two translation tables: one for message types, one for senders.
 #[derive(Debug)]
 pub struct TranslationTables {
@@ -179,6 +197,32 @@ impl TranslationTables {
         self.types.clear();
         self.senders.clear();
     }
+
+    /// Re-create the sender and type descriptions received from the peer, with their remote IDs.
+    #[cfg(any(feature = "async-tokio", feature = "vrpn-async-std"))]
+    pub(crate) fn pack_remote_descriptions(&self) -> Result<Vec<GenericMessage>> {
+        let mut messages = self.senders.pack_remote_descriptions()?;
+        messages.extend(self.types.pack_remote_descriptions()?);
+        Ok(messages)
+    }
+
+    /// Find the names the peer described a message's sender and type with,
+    /// given the message with local IDs.
+    pub(crate) fn find_names(
+        &self,
+        header: &MessageHeader,
+    ) -> Option<(SenderName, MessageTypeName)> {
+        let sender = self
+            .senders
+            .find_by_predicate(|entry| entry.local_id == LocalId(header.sender))?;
+        let message_type = self
+            .types
+            .find_by_predicate(|entry| entry.local_id == LocalId(header.message_type))?;
+        Some((
+            SenderName(sender.name.clone()),
+            MessageTypeName(message_type.name.clone()),
+        ))
+    }
 }
 
 impl Default for TranslationTables {
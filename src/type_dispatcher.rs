@@ -22,7 +22,7 @@ use bytes::Bytes;
 use futures::future::LocalBoxFuture;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     fmt,
     hash::Hash,
@@ -269,6 +269,10 @@ pub struct TypeDispatcher {
     generic_callbacks: CallbackCollection,
     /// Index is the local sender ID
     senders: NameRegistrationContainer<SenderId>,
+    /// Types first registered because a peer described them, which we haven't described ourselves.
+    undescribed_types: HashSet<LocalId<MessageTypeId>>,
+    /// Senders first registered because a peer described them, which we haven't described ourselves.
+    undescribed_senders: HashSet<LocalId<SenderId>>,
 }
 
 impl Default for TypeDispatcher {
@@ -283,6 +287,8 @@ impl TypeDispatcher {
             message_types: PerIdData::new(NameRegistrationContainer::default()),
             generic_callbacks: CallbackCollection::new(/* Bytes::from_static(GENERIC) */),
             senders: NameRegistrationContainer::default(),
+            undescribed_types: HashSet::new(),
+            undescribed_senders: HashSet::new(),
         };
 
         try_register_system_senders_and_messages(&mut disp.senders, &mut disp.message_types);
@@ -312,19 +318,60 @@ impl TypeDispatcher {
 
     /// Calls add_type if get_type_id() returns None.
     /// Returns the corresponding MessageTypeId in all cases.
+    ///
+    /// A type first registered by `register_remote_type` counts as a new mapping
+    /// the first time it is registered here, since our peers have yet to hear of it from us.
     pub fn register_type(
         &mut self,
         name: impl Into<MessageTypeName>,
     ) -> Result<RegisterMapping<MessageTypeId>> {
-        Ok(self.message_types.try_insert_or_get(name)?.into())
+        match self.message_types.try_insert_or_get(name)?.into() {
+            RegisterMapping::Found(id) if self.undescribed_types.remove(&id) => {
+                Ok(RegisterMapping::NewMapping(id))
+            }
+            mapping => Ok(mapping),
+        }
     }
 
     /// Calls add_sender if get_sender_id() returns None.
+    ///
+    /// A sender first registered by `register_remote_sender` counts as a new mapping
+    /// the first time it is registered here, since our peers have yet to hear of it from us.
     pub fn register_sender(
         &mut self,
         name: impl Into<SenderName>,
     ) -> Result<RegisterMapping<SenderId>> {
-        Ok(self.senders.try_insert_or_get(name)?.into())
+        match self.senders.try_insert_or_get(name)?.into() {
+            RegisterMapping::Found(id) if self.undescribed_senders.remove(&id) => {
+                Ok(RegisterMapping::NewMapping(id))
+            }
+            mapping => Ok(mapping),
+        }
+    }
+
+    /// Register a type name a peer described to us.
+    pub(crate) fn register_remote_type(
+        &mut self,
+        name: impl Into<MessageTypeName>,
+    ) -> Result<LocalId<MessageTypeId>> {
+        let mapping: RegisterMapping<MessageTypeId> =
+            self.message_types.try_insert_or_get(name)?.into();
+        if let RegisterMapping::NewMapping(id) = mapping {
+            self.undescribed_types.insert(id);
+        }
+        Ok(mapping.into_inner())
+    }
+
+    /// Register a sender name a peer described to us.
+    pub(crate) fn register_remote_sender(
+        &mut self,
+        name: impl Into<SenderName>,
+    ) -> Result<LocalId<SenderId>> {
+        let mapping: RegisterMapping<SenderId> = self.senders.try_insert_or_get(name)?.into();
+        if let RegisterMapping::NewMapping(id) = mapping {
+            self.undescribed_senders.insert(id);
+        }
+        Ok(mapping.into_inner())
     }
 
     /// Returns the ID for the sender name, if found.
@@ -548,4 +595,42 @@ mod tests {
         dispatcher.call(&msg2).unwrap();
         assert_eq!(*val.lock().unwrap(), 10);
     }
+
+    #[test]
+    fn remote_registration() {
+        let mut dispatcher = TypeDispatcher::new();
+        let id = dispatcher
+            .register_remote_type(MessageTypeName(Bytes::from_static(b"remote type")))
+            .unwrap();
+        // The first local registration still has to be described to our peers.
+        assert_eq!(
+            dispatcher
+                .register_type(MessageTypeName(Bytes::from_static(b"remote type")))
+                .unwrap(),
+            RegisterMapping::NewMapping(id)
+        );
+        assert_eq!(
+            dispatcher
+                .register_type(MessageTypeName(Bytes::from_static(b"remote type")))
+                .unwrap(),
+            RegisterMapping::Found(id)
+        );
+
+        let id = dispatcher
+            .register_sender(SenderName(Bytes::from_static(b"local sender")))
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            dispatcher
+                .register_remote_sender(SenderName(Bytes::from_static(b"local sender")))
+                .unwrap(),
+            id
+        );
+        assert_eq!(
+            dispatcher
+                .register_sender(SenderName(Bytes::from_static(b"local sender")))
+                .unwrap(),
+            RegisterMapping::Found(id)
+        );
+    }
 }
@@ -7,6 +7,7 @@ use crate::{
     data_types::{
         constants::COOKIE_SIZE,
         cookie::{check_ver_file_compatible, check_ver_nonfile_compatible, CookieData},
+        LogMode,
     },
     VrpnError,
 };
@@ -35,12 +36,18 @@ where
     Ok(buf.to_vec())
 }
 
-/// Writes the "non-file" magic cookie to the stream.
-pub async fn send_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+/// Writes the "non-file" magic cookie to the stream,
+/// including the logging we would like the peer to do.
+pub async fn send_nonfile_cookie<T>(
+    stream: &mut T,
+    remote_log_mode: LogMode,
+) -> Result<(), VrpnError>
 where
     T: AsyncWrite + Unpin,
 {
-    write_cookie(stream, CookieData::make_cookie()).await
+    let mut cookie = CookieData::make_cookie();
+    cookie.log_mode = Some(remote_log_mode);
+    write_cookie(stream, cookie).await
 }
 
 /// Writes the "file" magic cookie to the stream.
@@ -52,7 +59,9 @@ where
 }
 
 /// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
-pub async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+///
+/// Future resolves to the logging the peer would like us to do.
+pub async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<LogMode, VrpnError>
 where
     T: AsyncRead + Unpin,
 {
@@ -60,7 +69,7 @@ where
     let mut buf = Bytes::from(read_buf);
     let msg = CookieData::unbuffer_from(&mut buf)?;
     check_ver_nonfile_compatible(msg.version)?;
-    Ok(())
+    Ok(msg.log_mode.unwrap_or(LogMode::NONE))
 }
 
 /// Reads a cookie's worth of data from the stream, and checks to make sure it is the right version.
@@ -79,7 +88,7 @@ where
 mod tests {
     use crate::{
         buffer_unbuffer::{BytesMutExtras, ConstantBufferSize},
-        data_types::{constants::COOKIE_SIZE, CookieData},
+        data_types::{constants::COOKIE_SIZE, CookieData, LogMode},
     };
     use async_std::task;
     use bytes::{Bytes, BytesMut};
@@ -119,8 +128,9 @@ mod tests {
         {
             let cookie = get_cookie_buf(false);
             let mut reader = Cursor::new(&cookie[..]);
-            task::block_on(super::read_and_check_nonfile_cookie(&mut reader))
+            let log_mode = task::block_on(super::read_and_check_nonfile_cookie(&mut reader))
                 .expect("checking cookie should pass");
+            assert_eq!(log_mode, LogMode::NONE);
         }
         {
             let cookie = get_cookie_buf(true);
@@ -134,7 +144,7 @@ mod tests {
     fn write_cookie() {
         {
             let mut writer = Cursor::new(vec![0u8; COOKIE_SIZE]);
-            task::block_on(super::send_nonfile_cookie(&mut writer)).unwrap();
+            task::block_on(super::send_nonfile_cookie(&mut writer, LogMode::NONE)).unwrap();
             let write_buf = writer.into_inner();
             assert_eq!(&get_cookie_buf(false), &write_buf);
         }
@@ -4,7 +4,7 @@
 
 use std::{
     io,
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
@@ -16,14 +16,16 @@ use bytes::{BufMut, Bytes, BytesMut};
 use socket2::{SockAddr, SockRef};
 
 use crate::{
+    data_types::LogMode,
     vrpn_async::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
     Result, Scheme, ServerInfo, VrpnError,
 };
 
 pub struct ConnectResults {
-    pub(crate) server_info: ServerInfo,
     pub(crate) tcp: TcpStream,
     pub(crate) udp: Option<UdpSocket>,
+    /// The logging the server asked us to do on its behalf.
+    pub(crate) log_requested: LogMode,
 }
 
 pub(crate) fn make_tcp_socket(addr: SocketAddr) -> io::Result<socket2::Socket> {
@@ -58,7 +60,6 @@ async fn make_udp_socket() -> io::Result<UdpSocket> {
         let sock = SockRef::from(&sock);
         sock.set_reuse_address(true)?;
         sock.set_nonblocking(true)?;
-        sock.set_nodelay(true)?;
     }
     Ok(sock)
 }
@@ -74,12 +75,39 @@ async fn outgoing_tcp_connect(addr: std::net::SocketAddr) -> Result<TcpStream> {
     Ok(TcpStream::from(std::net::TcpStream::from(sock)))
 }
 
+/// Find the address of our interface that reaches `peer`, by "connecting" a UDP socket,
+/// which sends nothing.
+fn local_ip_for(peer: SocketAddr) -> io::Result<IpAddr> {
+    let any: IpAddr = if peer.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(any, 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Format the "call me back" datagram sent to a server: `IP PORT`, null-terminated.
+fn make_lobbed_buf(addr: SocketAddr) -> Bytes {
+    let addr_str = addr.ip().to_string();
+    let port_str = addr.port().to_string();
+    let mut buf = BytesMut::with_capacity(addr_str.len() + port_str.len() + 2);
+
+    buf.put(addr_str.as_bytes());
+    buf.put(" ".as_bytes());
+    buf.put(port_str.as_bytes());
+    buf.put_u8(0);
+    buf.freeze()
+}
+
+/// Send the lobbed datagram, then wait a little while for the server to connect back to us.
 async fn lobbing(
     udp: &UdpSocket,
     buf: &Bytes,
     tcp_listener: &TcpListener,
-    server: ServerInfo,
-) -> std::result::Result<Option<(TcpStream, SocketAddr)>, io::Error> {
+    server: &ServerInfo,
+) -> io::Result<Option<(TcpStream, SocketAddr)>> {
     udp.send_to(buf, server.socket_addr).await?;
     match timeout(
         Duration::from_millis(MILLIS_BETWEEN_ATTEMPTS),
@@ -93,57 +121,104 @@ async fn lobbing(
 }
 
 async fn handshake(
-    server_info: ServerInfo,
     tcp: TcpStream,
     udp: Option<UdpSocket>,
+    remote_log_mode: LogMode,
 ) -> Result<ConnectResults> {
     let mut tcp = tcp;
-    send_nonfile_cookie(&mut tcp).await?;
-    read_and_check_nonfile_cookie(&mut tcp).await?;
+    send_nonfile_cookie(&mut tcp, remote_log_mode).await?;
+    let log_requested = read_and_check_nonfile_cookie(&mut tcp).await?;
     Ok(ConnectResults {
-        server_info,
         tcp,
         udp,
+        log_requested,
     })
 }
 
-async fn connect_tcp_and_udp(server: ServerInfo) -> Result<ConnectResults> {
+async fn connect_tcp_and_udp(
+    server: ServerInfo,
+    remote_log_mode: LogMode,
+) -> Result<ConnectResults> {
     let udp = make_udp_socket().await?;
-    let addr = "localhost".to_socket_addrs()?.next().unwrap();
-    let addr = SocketAddr::new(addr.ip(), 0);
-    let tcp_listener = TcpListener::bind(&addr).await?;
-    let port = udp.local_addr()?.port();
-    let addr = SocketAddr::new(addr.ip(), port);
-    let lobbed_buf = {
-        let addr_str = addr.ip().to_string();
-        let port_str = addr.port().to_string();
-        let mut buf = BytesMut::with_capacity(addr_str.len() + port_str.len() + 2);
-
-        buf.put(addr_str.as_bytes());
-        buf.put(" ".as_bytes());
-        buf.put(port_str.as_bytes());
-        buf.put_u8(0);
-        buf
-    };
-    let lobbed_buf = lobbed_buf.freeze();
-    for _ in 0..5 {
-        if let Some((tcp_stream, _)) =
-            lobbing(&udp, &lobbed_buf, &tcp_listener, server.clone()).await?
-        {
-            return handshake(server, tcp_stream, Some(udp)).await;
+    let local_ip = local_ip_for(server.socket_addr)?;
+    let tcp_listener = TcpListener::bind(SocketAddr::new(local_ip, 0)).await?;
+    // The server connects back to our TCP listener.
+    let lobbed_buf = make_lobbed_buf(tcp_listener.local_addr()?);
+    for _ in 0..LOBBING_ATTEMPTS {
+        if let Some((tcp_stream, _)) = lobbing(&udp, &lobbed_buf, &tcp_listener, &server).await? {
+            tcp_stream.set_nodelay(true)?;
+            return handshake(tcp_stream, Some(udp), remote_log_mode).await;
         }
     }
     Err(VrpnError::CouldNotConnect)
 }
-async fn connect_tcp_only(server: ServerInfo) -> Result<ConnectResults> {
+
+async fn connect_tcp_only(server: ServerInfo, remote_log_mode: LogMode) -> Result<ConnectResults> {
     let tcp = outgoing_tcp_connect(server.socket_addr).await?;
-    return handshake(server, tcp, None).await;
+    handshake(tcp, None, remote_log_mode).await
 }
 
 const MILLIS_BETWEEN_ATTEMPTS: u64 = 500;
-pub async fn connect(server: ServerInfo) -> Result<ConnectResults> {
+const LOBBING_ATTEMPTS: usize = 5;
+
+/// Connect to a server and perform the handshake,
+/// asking it to do the logging in `remote_log_mode`.
+pub async fn connect(server: ServerInfo, remote_log_mode: LogMode) -> Result<ConnectResults> {
     match server.scheme {
-        Scheme::UdpAndTcp => connect_tcp_and_udp(server).await,
-        Scheme::TcpOnly => connect_tcp_only(server).await,
+        Scheme::UdpAndTcp => connect_tcp_and_udp(server, remote_log_mode).await,
+        Scheme::TcpOnly => connect_tcp_only(server, remote_log_mode).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer_unbuffer::UnbufferFrom, data_types::CookieData, sync_io, Result, VrpnError,
+    };
+    use futures::executor::block_on;
+
+    #[test]
+    fn lobbed_buf() {
+        let buf = make_lobbed_buf("127.0.0.1:3883".parse().unwrap());
+        assert_eq!(&buf[..], &b"127.0.0.1 3883\0"[..]);
+    }
+
+    /// Act as a server for one lobbing client: read its request from `lobby`,
+    /// connect back to the address in it, and do the server's side of the handshake,
+    /// asking the client to log its incoming messages.
+    fn serve_lobbed_request(lobby: std::net::UdpSocket) -> Result<LogMode> {
+        let mut buf = [0u8; 64];
+        let (len, _) = lobby.recv_from(&mut buf)?;
+        let invalid = || VrpnError::OtherMessage(format!("bad request {:?}", &buf[..len]));
+        let request = std::str::from_utf8(&buf[..len])
+            .map_err(|_| invalid())?
+            .trim_end_matches('\0');
+        let (ip, port) = request.split_once(' ').ok_or_else(invalid)?;
+        let addr = SocketAddr::new(
+            ip.parse().map_err(|_| invalid())?,
+            port.parse().map_err(|_| invalid())?,
+        );
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        let cookie = sync_io::read_cookie(&mut stream)?;
+        let cookie = CookieData::unbuffer_from(&mut &cookie[..])?;
+        let mut reply = CookieData::make_cookie();
+        reply.log_mode = Some(LogMode::INCOMING);
+        sync_io::write_cookie(&mut stream, reply)?;
+        Ok(cookie.log_mode.unwrap_or(LogMode::NONE))
+    }
+
+    #[test]
+    fn lobbing_client() {
+        let lobby = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = format!("x-vrpn://{}", lobby.local_addr().unwrap())
+            .parse::<ServerInfo>()
+            .unwrap();
+        let fake_server = std::thread::spawn(move || serve_lobbed_request(lobby));
+
+        let results = block_on(connect(server, LogMode::OUTGOING)).unwrap();
+        assert!(results.udp.is_some());
+        assert_eq!(results.log_requested, LogMode::INCOMING);
+        assert_eq!(fake_server.join().unwrap().unwrap(), LogMode::OUTGOING);
     }
 }
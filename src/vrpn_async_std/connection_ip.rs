@@ -2,35 +2,63 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use crate::{connection::*, data_types::log::LogFileNames, Result, ServerInfo};
+use crate::{
+    connection::*,
+    data_types::{log::LogFileNames, LogMode},
+    log_file::EndpointLog,
+    vrpn_async::cookie::{read_and_check_nonfile_cookie, send_nonfile_cookie},
+    Endpoint, Result, ServerInfo,
+};
 use async_std::net::TcpListener;
 use futures::{future::BoxFuture, FutureExt, Stream};
 use std::{
     net::SocketAddr,
+    path::PathBuf,
     sync::{Arc, Mutex},
     task::Poll,
 };
 
 use super::{
     connect::{connect, ConnectResults},
+    duplex::{duplex, DuplexStream},
     endpoint_ip::EndpointIp,
 };
 
 pub(crate) enum ConnectionIpInfo {
-    /// This variant stores the server info for reconnecting
-    ClientConnectionInfo(ServerInfo),
+    /// This marks us as a client that has connected: `Reconnect` keeps the server info
+    ClientConnected,
     /// This stores the future that connects
     ClientConnectionSetupFuture(BoxFuture<'static, Result<ConnectResults>>),
     /// This just marks us as a server
     Server,
+    /// This stores the handshake of one end of a loopback connection
+    LoopbackSetupFuture {
+        handshake: BoxFuture<'static, Result<(DuplexStream, LogMode)>>,
+        server: bool,
+    },
+    /// This marks us as the client end of a loopback connection, which never reconnects
+    ClientLoopback,
 }
 
 impl ConnectionIpInfo {
     pub(crate) fn status(&self, num_endpoints: usize) -> ConnectionStatus {
         match self {
             ConnectionIpInfo::ClientConnectionSetupFuture(_) => ConnectionStatus::ClientConnecting,
-            ConnectionIpInfo::ClientConnectionInfo(_) => ConnectionStatus::ClientConnected,
+            ConnectionIpInfo::ClientConnected if num_endpoints > 0 => {
+                ConnectionStatus::ClientConnected
+            }
+            ConnectionIpInfo::ClientConnected => ConnectionStatus::Closed,
             ConnectionIpInfo::Server => ConnectionStatus::Server(num_endpoints),
+            ConnectionIpInfo::LoopbackSetupFuture { server: true, .. } => {
+                ConnectionStatus::Server(num_endpoints)
+            }
+            ConnectionIpInfo::LoopbackSetupFuture { server: false, .. } => {
+                ConnectionStatus::ClientConnecting
+            }
+            ConnectionIpInfo::ClientLoopback if num_endpoints > 0 => {
+                ConnectionStatus::ClientConnected
+            }
+            ConnectionIpInfo::ClientLoopback => ConnectionStatus::Closed,
         }
     }
 }
@@ -39,6 +67,10 @@ pub struct ConnectionIp {
     server_tcp: Option<Mutex<TcpListener>>,
     // server_acceptor: Arc<Mutex<Option<ConnectionIpAcceptor>>>,
     client_info: Mutex<ConnectionIpInfo>,
+    /// Only used by a client.
+    reconnect: Option<Mutex<Reconnect>>,
+    /// Where we keep logs that peers ask for, if we allow them.
+    remote_log_dir: Mutex<Option<PathBuf>>,
 }
 
 const DEFAULT_PORT: u16 = 3883;
@@ -55,6 +87,8 @@ impl ConnectionIp {
             // server_tcp: Some(Mutex::new(server_tcp)),
             server_tcp: None,
             client_info: Mutex::new(ConnectionIpInfo::Server),
+            reconnect: None,
+            remote_log_dir: Mutex::new(None),
         });
         // {
         //     let accepter = ConnectionIpAcceptor::new(Arc::downgrade(&conn), addr)?;
@@ -71,19 +105,98 @@ impl ConnectionIp {
         remote_log_names: Option<LogFileNames>,
     ) -> Result<Arc<ConnectionIp>> {
         let endpoints: Vec<Option<EndpointIp>> = Vec::new();
+        let remote_log_mode = LogFileNames::from(remote_log_names.clone()).log_mode();
         // let connect = Connect::new(server)?;
         let ret = Arc::new(ConnectionIp {
             core: ConnectionCore::new(endpoints, local_log_names, remote_log_names),
             // server_acceptor: None,
             client_info: Mutex::new(ConnectionIpInfo::ClientConnectionSetupFuture(
-                connect(server).boxed(),
+                connect(server.clone(), remote_log_mode).boxed(),
             )),
             server_tcp: None,
+            reconnect: Some(Mutex::new(Reconnect::new(server))),
+            remote_log_dir: Mutex::new(None),
         });
         ret.send_all_descriptions()?;
         Ok(ret)
     }
 
+    /// Create a server and a client connected to it in memory, in that order, without sockets.
+    ///
+    /// The two exchange cookies and descriptions as over TCP, once each is polled,
+    /// so this suits hermetic tests, or device servers and clients in one process.
+    /// There is no low-latency channel, and the client never reconnects.
+    pub fn new_loopback_pair() -> Result<(Arc<ConnectionIp>, Arc<ConnectionIp>)> {
+        let (mut server_stream, mut client_stream) = duplex();
+        let new_end = |client_info| ConnectionIp {
+            core: ConnectionCore::new(Vec::new(), None, None),
+            server_tcp: None,
+            client_info: Mutex::new(client_info),
+            reconnect: None,
+            remote_log_dir: Mutex::new(None),
+        };
+        let server = new_end(ConnectionIpInfo::LoopbackSetupFuture {
+            handshake: async move {
+                let log_requested = read_and_check_nonfile_cookie(&mut server_stream).await?;
+                send_nonfile_cookie(&mut server_stream, LogMode::NONE).await?;
+                Ok((server_stream, log_requested))
+            }
+            .boxed(),
+            server: true,
+        });
+        let client = new_end(ConnectionIpInfo::LoopbackSetupFuture {
+            handshake: async move {
+                send_nonfile_cookie(&mut client_stream, LogMode::NONE).await?;
+                let log_requested = read_and_check_nonfile_cookie(&mut client_stream).await?;
+                Ok((client_stream, log_requested))
+            }
+            .boxed(),
+            server: false,
+        });
+        Ok((Arc::new(server), Arc::new(client)))
+    }
+
+    /// Set how a client connection tries to reconnect after losing its server,
+    /// or failing to reach it in the first place.
+    ///
+    /// With `None`, the default, it gives up instead:
+    /// `poll_endpoints` resolves and the status becomes `Closed`, so a lost server is noticed.
+    /// Mainline clients silently reconnect: pass `Some(ReconnectPolicy::default())` to do the same.
+    /// Has no effect on a server connection.
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) -> Result<()> {
+        if let Some(reconnect) = &self.reconnect {
+            reconnect.lock()?.set_policy(policy);
+        }
+        Ok(())
+    }
+
+    /// Allow peers to ask us to keep logs of the connection, in files inside `dir`.
+    ///
+    /// With `None`, the default, such requests are refused.
+    /// Peers name the files, so only relative names that stay inside `dir` are accepted.
+    /// A refused request leaves the connection open.
+    pub fn set_remote_log_dir(&self, dir: Option<PathBuf>) -> Result<()> {
+        *self.remote_log_dir.lock()? = dir;
+        Ok(())
+    }
+
+    /// Get a future for the next attempt to connect, after the policy's delay,
+    /// if the reconnect policy says to make one.
+    fn next_attempt(&self) -> Result<Option<BoxFuture<'static, Result<ConnectResults>>>> {
+        let attempt = match &self.reconnect {
+            Some(reconnect) => reconnect.lock()?.next_attempt(),
+            None => None,
+        };
+        let remote_log_mode = self.core.remote_log_names().log_mode();
+        Ok(attempt.map(|(server, delay)| {
+            async move {
+                async_std::task::sleep(delay).await;
+                connect(server, remote_log_mode).await
+            }
+            .boxed()
+        }))
+    }
+
     pub fn poll_endpoints(&self, cx: &mut std::task::Context<'_>) -> Poll<Result<Option<()>>> {
         // eprintln!("in <ConnectionIp as Future>::poll");
         // if let Some(listener_mutex) = &self.server_tcp {
@@ -111,20 +224,53 @@ impl ConnectionIp {
         // }
 
         // Connect/reconnect if needed.
-        {
+        let mut new_endpoints = Vec::new();
+        let shut_down = self.core.is_shut_down();
+        if !shut_down {
             let mut client_info = self.client_info.lock()?;
-            let ep_arc = self.endpoints();
-            let mut endpoints = ep_arc.lock()?;
-            if let ConnectionIpInfo::ClientConnectionSetupFuture(f) = &mut *client_info {
+            while let ConnectionIpInfo::ClientConnectionSetupFuture(f) = &mut *client_info {
                 match f.as_mut().poll(cx) {
                     Poll::Ready(Ok(results)) => {
-                        endpoints.push(Some(EndpointIp::new(results.tcp, results.udp)));
-                        *client_info = ConnectionIpInfo::ClientConnectionInfo(results.server_info)
+                        let mut endpoint = EndpointIp::new(results.tcp, results.udp);
+                        endpoint.set_log(EndpointLog::open(self.core.local_log_names())?);
+                        endpoint.set_peer_log_request(
+                            results.log_requested,
+                            self.remote_log_dir.lock()?.clone(),
+                        );
+                        endpoint.send_udp_description()?;
+                        endpoint.send_log_description(self.core.remote_log_names())?;
+                        new_endpoints.push(endpoint);
+                        if let Some(reconnect) = &self.reconnect {
+                            reconnect.lock()?.connected();
+                        }
+                        *client_info = ConnectionIpInfo::ClientConnected
                     }
-                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Err(e)) => match self.next_attempt()? {
+                        Some(attempt) => {
+                            eprintln!("Failed to connect, will try again: {:?}", e);
+                            *client_info = ConnectionIpInfo::ClientConnectionSetupFuture(attempt);
+                        }
+                        None => return Poll::Ready(Err(e)),
+                    },
                     Poll::Pending => return Poll::Pending,
                 }
-            };
+            }
+            if let ConnectionIpInfo::LoopbackSetupFuture { handshake, server } = &mut *client_info {
+                let (stream, log_requested) = match handshake.as_mut().poll(cx) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => return Poll::Pending,
+                };
+                let mut endpoint = EndpointIp::from_stream(stream);
+                endpoint.set_log(EndpointLog::open(self.core.local_log_names())?);
+                endpoint.set_peer_log_request(log_requested, self.remote_log_dir.lock()?.clone());
+                if *server {
+                    *client_info = ConnectionIpInfo::Server;
+                } else {
+                    endpoint.send_log_description(self.core.remote_log_names())?;
+                    *client_info = ConnectionIpInfo::ClientLoopback;
+                }
+                new_endpoints.push(endpoint);
+            }
         }
 
         // let mut acceptor = self.server_acceptor.lock()?;
@@ -141,30 +287,67 @@ impl ConnectionIp {
         // }
         let endpoints = self.endpoints();
         let dispatcher = self.dispatcher();
-        {
+        let mut dispatcher = dispatcher.lock()?;
+        let mut received = Vec::new();
+        let mut connected = Vec::new();
+        let mut dropped = Vec::new();
+        let got_not_ready = {
             let mut endpoints = endpoints.lock()?;
-            let mut dispatcher = dispatcher.lock()?;
+            for mut endpoint in new_endpoints {
+                endpoint.send_all_descriptions(&dispatcher)?;
+                got_connection_events(&mut dispatcher, endpoints.is_empty(), &mut connected)?;
+                endpoints.push(Some(endpoint));
+            }
             let mut got_not_ready = false;
+            let mut num_dropped = 0;
             // Go through and poll each endpoint, "taking" the ones that are closed.
             for ep in endpoints.iter_mut() {
                 let ready = match ep {
-                    Some(endpoint) => endpoint.poll_endpoint(&mut dispatcher, cx).is_ready(),
+                    Some(endpoint) => endpoint
+                        .poll_endpoint(&mut dispatcher, &mut received, cx)
+                        .is_ready(),
                     _ => true,
                 };
                 if ready {
-                    let _ = ep.take();
+                    if ep.take().is_some() {
+                        num_dropped += 1;
+                    }
                 } else {
                     got_not_ready = true;
                 }
             }
             // Now, retain only the non-taken endpoints in the vector.
             endpoints.retain(|ep| ep.is_some());
+            for i in 1..=num_dropped {
+                let last = i == num_dropped && endpoints.is_empty();
+                dropped_connection_events(&mut dispatcher, last, &mut dropped)?;
+            }
+            got_not_ready
+        };
+
+        // Dispatch with the endpoints unlocked, so handlers can pack replies.
+        for msg in connected.iter().chain(&received).chain(&dropped) {
+            dispatcher.call(msg)?;
+        }
+        drop(dispatcher);
 
-            if got_not_ready {
+        if got_not_ready {
+            return Poll::Pending;
+        }
+        if shut_down {
+            return Poll::Ready(Ok(None));
+        }
+        match self.next_attempt()? {
+            Some(attempt) => {
+                // The new endpoint starts with empty translation tables,
+                // and gets all our descriptions once connected.
+                // Handlers stay registered with the dispatcher.
+                eprintln!("Lost connection to server, will reconnect");
+                *self.client_info.lock()? = ConnectionIpInfo::ClientConnectionSetupFuture(attempt);
+                cx.waker().wake_by_ref();
                 Poll::Pending
-            } else {
-                Poll::Ready(Ok(Some(())))
             }
+            None => Poll::Ready(Ok(Some(()))),
         }
     }
 }
@@ -176,6 +359,9 @@ impl Connection for ConnectionIp {
     }
 
     fn status(&self) -> ConnectionStatus {
+        if self.core.is_shut_down() {
+            return ConnectionStatus::Closed;
+        }
         let ep = self.endpoints();
         let endpoints = ep.lock().unwrap();
         let info = self.client_info.lock().unwrap();
@@ -183,6 +369,12 @@ impl Connection for ConnectionIp {
     }
 }
 
+impl ClientConnection for ConnectionIp {
+    fn new_client_connection(server: ServerInfo) -> Result<Arc<ConnectionIp>> {
+        ConnectionIp::new_client(server, None, None)
+    }
+}
+
 pub struct ConnectionIpStream {
     connection: Arc<ConnectionIp>,
 }
@@ -209,13 +401,19 @@ impl Stream for ConnectionIpStream {
 mod tests {
     use super::*;
     use crate::{
-        data_types::{Message, StaticMessageTypeName, StaticSenderName, TypedMessage},
+        data_types::{
+            id_types::{LocalId, SenderId, Sensor},
+            ClassOfService, Quat, StaticMessageTypeName, StaticSenderName, TypedMessage, Vec3,
+        },
         handler::{HandlerCode, TypedHandler},
         tracker::*,
     };
-    use std::sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::Context,
     };
 
     #[derive(Debug)]
@@ -238,84 +436,115 @@ mod tests {
         }
     }
 
-    #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn tracker_tcp() {
-        let flag = Arc::new(AtomicBool::new(false));
-        async fn function(flag: &Arc<AtomicBool>) -> Result<()> {
-            let mut cx = futures::task::Context::from_waker(futures::task::noop_waker_ref());
-            let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>()?;
-            let conn = ConnectionIp::new_client(server, None, None)?;
-            let sender = conn
-                .register_sender(StaticSenderName(b"Tracker0"))
-                .expect("should be able to register sender");
-            let handler_handle = conn.add_typed_handler(TrackerHandler::new(flag), Some(sender))?;
-            conn.send_all_descriptions()?;
-            for _ in 0..4 {
-                let _ = conn.poll_endpoints(&mut cx)?;
+    /// Make a loopback pair, the server with a "Tracker0" sender.
+    fn loopback_pair() -> (Arc<ConnectionIp>, Arc<ConnectionIp>, LocalId<SenderId>) {
+        let (server, client) = ConnectionIp::new_loopback_pair().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Server(0));
+        assert_eq!(client.status(), ConnectionStatus::ClientConnecting);
+        let sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        (server, client, sender)
+    }
+
+    /// Poll both ends in turn, the server sending pose reports from `sender`,
+    /// until `condition` holds, panicking with `what` if it never does.
+    fn send_reports_until(
+        server: &ConnectionIp,
+        client: &ConnectionIp,
+        sender: LocalId<SenderId>,
+        what: &str,
+        mut condition: impl FnMut() -> bool,
+    ) {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let report = PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(0.0, 0.0, 0.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        };
+        for _ in 0..100 {
+            if condition() {
+                return;
             }
-            conn.remove_handler(handler_handle)
-                .expect("should be able to remove handler");
-            Ok(())
+            server
+                .pack_message_body(None, sender, report.clone(), ClassOfService::RELIABLE)
+                .unwrap();
+            let _ = server.poll_endpoints(&mut cx);
+            let _ = client.poll_endpoints(&mut cx);
         }
-        futures::executor::block_on(function(&flag)).unwrap();
-
-        assert!(flag.load(Ordering::SeqCst));
+        panic!("{}", what);
     }
 
-    #[ignore] // because it requires an external server to be running.
     #[test]
-    fn tracker() {
+    fn loopback_tracker() {
+        let (server, client, server_sender) = loopback_pair();
         let flag = Arc::new(AtomicBool::new(false));
-        async fn function(flag: &Arc<AtomicBool>) -> Result<()> {
-            let mut cx = futures::task::Context::from_waker(futures::task::noop_waker_ref());
-            let server = "127.0.0.1:3883".parse::<ServerInfo>()?;
-            let conn = ConnectionIp::new_client(server, None, None)?;
-            let sender = conn
-                .register_sender(StaticSenderName(b"Tracker0"))
-                .expect("should be able to register sender");
-            let handler_handle = conn.add_typed_handler(TrackerHandler::new(flag), Some(sender))?;
-            while conn.status() == ConnectionStatus::ClientConnecting {
-                let _ = conn.poll_endpoints(&mut cx)?;
-            }
-            for _ in 0..4 {
-                let _ = conn.poll_endpoints(&mut cx)?;
-            }
-            conn.remove_handler(handler_handle)
-                .expect("should be able to remove handler");
-            Ok(())
-        }
-        futures::executor::block_on(function(&flag)).unwrap();
-        assert!(flag.load(Ordering::SeqCst));
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .expect("should be able to register sender");
+        client
+            .add_typed_handler(TrackerHandler::new(&flag), Some(sender))
+            .unwrap();
+
+        send_reports_until(
+            &server,
+            &client,
+            server_sender,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        );
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
     }
-    #[ignore] // because it requires an external server to be running.
+
     #[test]
-    fn tracker_manual() {
+    fn loopback_tracker_manual() {
+        let (server, client, server_sender) = loopback_pair();
         let flag = Arc::new(AtomicBool::new(false));
-        async fn function(flag: &Arc<AtomicBool>) -> Result<()> {
-            let mut cx = futures::task::Context::from_waker(futures::task::noop_waker_ref());
-            let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>()?;
-            let conn = ConnectionIp::new_client(server, None, None)?;
-            let tracker_message_id = conn
-                .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
-                .expect("should be able to register type");
-            let sender = conn
-                .register_sender(StaticSenderName(b"Tracker0"))
-                .expect("should be able to register sender");
-            conn.add_handler(
-                TrackerHandler::new(flag),
+        let tracker_message_id = client
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .expect("should be able to register type");
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .expect("should be able to register sender");
+        client
+            .add_handler(
+                TrackerHandler::new(&flag),
                 Some(tracker_message_id),
                 Some(sender),
-            )?;
-            while conn.status() == ConnectionStatus::ClientConnecting {
-                let _ = conn.poll_endpoints(&mut cx)?;
-            }
-            for _ in 0..4 {
-                let _ = conn.poll_endpoints(&mut cx)?;
+            )
+            .unwrap();
+
+        send_reports_until(
+            &server,
+            &client,
+            server_sender,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        );
+    }
+
+    #[test]
+    fn loopback_shutdown() {
+        let (server, client, server_sender) = loopback_pair();
+        send_reports_until(
+            &server,
+            &client,
+            server_sender,
+            "client should connect",
+            || server.status() == ConnectionStatus::Server(1),
+        );
+
+        // The client hears the disconnect message and closes.
+        server.shutdown().unwrap();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        for _ in 0..100 {
+            let _ = server.poll_endpoints(&mut cx);
+            if client.poll_endpoints(&mut cx).is_ready() {
+                break;
             }
-            Ok(())
         }
-        futures::executor::block_on(function(&flag)).unwrap();
-        assert!(flag.load(Ordering::SeqCst));
+        assert_eq!(client.status(), ConnectionStatus::Closed);
+        assert!(server.endpoints().lock().unwrap().is_empty());
     }
 }
@@ -0,0 +1,145 @@
+// Copyright 2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+//! An in-memory, bidirectional byte stream, for connecting two ends without sockets.
+
+use bytes::{Buf, BytesMut};
+use futures::{AsyncRead, AsyncWrite};
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// One direction of a duplex stream.
+#[derive(Debug, Default)]
+struct Pipe {
+    data: BytesMut,
+    /// Set once the writing end has closed.
+    closed: bool,
+    /// Wakes the reading end, when there is something new to read.
+    waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Lock a pipe, treating one poisoned by a panic on the other end as broken.
+fn lock(pipe: &Mutex<Pipe>) -> io::Result<std::sync::MutexGuard<'_, Pipe>> {
+    pipe.lock()
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+}
+
+/// One end of an in-memory duplex stream: see `duplex`.
+///
+/// Writes never wait: the buffer grows as needed.
+/// Closes its outgoing direction when closed, so the peer reads the end of the stream,
+/// and both directions when dropped, so the peer can't write to it either.
+#[derive(Debug)]
+pub(crate) struct DuplexStream {
+    rx: Arc<Mutex<Pipe>>,
+    tx: Arc<Mutex<Pipe>>,
+}
+
+/// Make a pair of connected in-memory streams: what is written to one is read from the other.
+pub(crate) fn duplex() -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    (
+        DuplexStream {
+            rx: Arc::clone(&b_to_a),
+            tx: Arc::clone(&a_to_b),
+        },
+        DuplexStream {
+            rx: a_to_b,
+            tx: b_to_a,
+        },
+    )
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut rx = lock(&self.rx)?;
+        if rx.data.is_empty() && !rx.closed {
+            rx.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(rx.data.len());
+        buf[..n].copy_from_slice(&rx.data[..n]);
+        rx.data.advance(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut tx = lock(&self.tx)?;
+        if tx.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        tx.data.extend_from_slice(buf);
+        tx.wake();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut tx = lock(&self.tx)?;
+        tx.closed = true;
+        tx.wake();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        for pipe in &[&self.tx, &self.rx] {
+            if let Ok(mut pipe) = lock(pipe) {
+                pipe.closed = true;
+                pipe.wake();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn both_ways_then_close() {
+        block_on(async {
+            let (mut a, mut b) = duplex();
+            a.write_all(b"ping").await.unwrap();
+            b.write_all(b"pong").await.unwrap();
+            let mut buf = [0u8; 4];
+            b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            a.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+
+            drop(a);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+            assert!(b.write_all(b"gone").await.is_err());
+        });
+    }
+}
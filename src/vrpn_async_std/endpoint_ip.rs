@@ -2,162 +2,346 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use super::{
-    endpoints::{merge_status, poll_and_dispatch, EndpointRx, EndpointStatus, ToEndpointStatus},
-    UnboundedMessageSender,
-};
+use super::{endpoints::DatagramStream, UnboundedDatagramSender};
 use crate::{
-    data_types::{ClassOfService, GenericMessage},
+    data_types::{
+        ClassOfService, Disconnect, GenericMessage, LogFileNames, LogMode, TypedMessage,
+        UdpDescription,
+    },
     endpoint::*,
-    error::to_other_error,
-    vrpn_async::MessageStream,
-    Result, TranslationTables, TypeDispatcher,
+    log_file::EndpointLog,
+    protocol::ProtocolEndpoint,
+    Result, TranslationTables, TypeDispatcher, VrpnError,
 };
 use async_std::net::{TcpStream, UdpSocket};
-use futures::{channel::mpsc, ready, Future, Stream, StreamExt};
-
-use std::{
-    ops::DerefMut,
-    sync::{Arc, Mutex},
-};
+use futures::{ready, AsyncRead, AsyncWrite, Future, StreamExt};
 use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     pin::Pin,
-    task::{Context, Poll},
+    sync::Arc,
+    task::{Context, Poll, Waker},
 };
 
-/// mock so we can have the member.
+/// The reliable channel to the peer: a TCP stream, or an in-memory one for a loopback connection.
+pub(crate) trait ReliableStream: AsyncRead + AsyncWrite + Debug + Send + Unpin {}
 
-#[derive(Debug)]
-struct MessageFramedUdp(UdpSocket);
+impl<T: AsyncRead + AsyncWrite + Debug + Send + Unpin> ReliableStream for T {}
 
+/// An endpoint over a TCP stream whose handshake is done,
+/// with an optional UDP socket for low-latency messages,
+/// or over any other reliable stream, without one.
+///
+/// The protocol itself is left to `ProtocolEndpoint`:
+/// this just moves bytes between it and the sockets.
 #[derive(Debug)]
 pub struct EndpointIp {
-    translation: TranslationTables,
-    reliable_tx: Pin<Box<UnboundedMessageSender>>,
-    reliable_rx: Arc<Mutex<EndpointRx<MessageStream<TcpStream>>>>,
-    low_latency_channel: Option<MessageFramedUdp>,
-    system_rx: Option<Pin<Box<mpsc::UnboundedReceiver<SystemCommand>>>>,
-    system_tx: Option<Pin<Box<mpsc::UnboundedSender<SystemCommand>>>>,
+    protocol: ProtocolEndpoint,
+    stream: Box<dyn ReliableStream>,
+    /// Where the peer should send us datagrams, if we have an inbound UDP socket.
+    udp_description: Option<UdpDescription>,
+    low_latency_tx: Option<Pin<Box<UnboundedDatagramSender>>>,
+    low_latency_rx: Option<DatagramStream>,
+    /// Records of the messages we receive and send, if requested.
+    log: EndpointLog,
+    /// The logging the peer asked us to do on its behalf, named in its log description.
+    peer_log_request: LogMode,
+    /// Where we keep logs for the peer, if we allow it to ask for them at all.
+    peer_log_dir: Option<PathBuf>,
+    /// Records of the messages we receive and send, kept for the peer.
+    peer_log: EndpointLog,
+    /// Wakes the task that last polled us, when something new is queued to send.
+    waker: Option<Waker>,
 }
 
 impl EndpointIp {
     pub(crate) fn new(reliable_stream: TcpStream, udp: Option<UdpSocket>) -> EndpointIp {
-        let reliable_tx = UnboundedMessageSender::new(reliable_stream.clone());
-        let reliable_rx = EndpointRx::from_reader(reliable_stream);
-        let (system_tx, system_rx) = mpsc::unbounded();
+        // Advertise the address the peer reaches us at over TCP, with our inbound UDP port.
+        let udp_description = match (&udp, reliable_stream.local_addr()) {
+            (Some(udp), Ok(tcp_addr)) => udp.local_addr().ok().map(|udp_addr| {
+                UdpDescription::new(SocketAddr::new(tcp_addr.ip(), udp_addr.port()))
+            }),
+            _ => None,
+        };
+        let mut endpoint = EndpointIp::from_stream(reliable_stream);
+        endpoint.udp_description = udp_description;
+        endpoint.low_latency_rx = udp.map(|socket| DatagramStream::new(Arc::new(socket)));
+        endpoint
+    }
+
+    /// Wrap a reliable stream whose handshake is done, with no low-latency channel.
+    pub(crate) fn from_stream(stream: impl ReliableStream + 'static) -> EndpointIp {
+        // The cookies were exchanged while connecting:
+        // the logging the peer asked for in its cookie is noted by `set_peer_log_request`.
+        let mut protocol = ProtocolEndpoint::established(LogMode::NONE);
+        protocol.record_incoming();
         EndpointIp {
-            translation: TranslationTables::new(),
-            reliable_tx,
-            reliable_rx,
-            low_latency_channel: udp.map(MessageFramedUdp),
-            system_tx: Some(Box::pin(system_tx)),
-            system_rx: Some(Box::pin(system_rx)),
+            protocol,
+            stream: Box::new(stream),
+            udp_description: None,
+            low_latency_tx: None,
+            low_latency_rx: None,
+            log: EndpointLog::default(),
+            peer_log_request: LogMode::NONE,
+            peer_log_dir: None,
+            peer_log: EndpointLog::default(),
+            waker: None,
+        }
+    }
+
+    /// Start recording messages to the given logs.
+    ///
+    /// Set this before sending anything, so the logs include our descriptions.
+    pub(crate) fn set_log(&mut self, log: EndpointLog) {
+        self.log = log;
+    }
+
+    /// Note the logging the peer asked for in its handshake cookie,
+    /// and the directory we keep such logs in, if we allow them.
+    ///
+    /// The logs are opened once the peer sends a log description naming the files.
+    pub(crate) fn set_peer_log_request(&mut self, mode: LogMode, dir: Option<PathBuf>) {
+        self.peer_log_request = mode;
+        self.peer_log_dir = dir;
+    }
+
+    /// Send the peer the names of the logs we asked it to keep in our handshake cookie, if any.
+    pub(crate) fn send_log_description(&mut self, names: &LogFileNames) -> Result<()> {
+        if !names.log_mode().is_empty() {
+            self.buffer_message(TypedMessage::from(names.clone()), ClassOfService::RELIABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Open the logs the peer asked for, which start with everything described so far.
+    ///
+    /// A request we refuse is reported, but leaves the connection open.
+    fn open_peer_log(&mut self, names: &LogFileNames, dispatcher: &TypeDispatcher) -> Result<()> {
+        let dir = self.peer_log_dir.as_deref();
+        let mut log = match EndpointLog::open_requested(names, self.peer_log_request, dir) {
+            Ok(log) => log,
+            Err(e) => {
+                eprintln!(
+                    "Not keeping the logs {:?} requested by the peer: {}",
+                    names, e
+                );
+                return Ok(());
+            }
+        };
+        log.log_descriptions(dispatcher, self.protocol.translation_tables())?;
+        self.peer_log = log;
+        Ok(())
+    }
+
+    /// Tell the peer where to send us low-latency messages, if we have an inbound UDP socket.
+    ///
+    /// Clients send this right after the handshake.
+    pub(crate) fn send_udp_description(&mut self) -> Result<()> {
+        if let Some(desc) = self.udp_description.clone() {
+            self.buffer_message(TypedMessage::from(desc), ClassOfService::RELIABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Start sending low-latency messages as datagrams to `peer`,
+    /// from a new UDP socket connected to it.
+    pub(crate) fn set_udp_destination(&mut self, peer: SocketAddr) -> Result<()> {
+        let any: IpAddr = if peer.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(any, 0))?;
+        socket.connect(peer)?;
+        if let Some(tx) = self.low_latency_tx.as_mut() {
+            tx.close();
+        }
+        self.low_latency_tx = Some(UnboundedDatagramSender::new(UdpSocket::from(socket), peer));
+        self.protocol.enable_datagrams();
+        Ok(())
+    }
+
+    fn handle_extended_command(
+        &mut self,
+        cmd: ExtendedSystemCommand,
+        dispatcher: &TypeDispatcher,
+    ) -> Result<()> {
+        match cmd {
+            ExtendedSystemCommand::UdpDescription(desc) => {
+                self.set_udp_destination(desc.socket_address)?;
+            }
+            ExtendedSystemCommand::LogDescription(names) => {
+                self.open_peer_log(&names, dispatcher)?;
+            }
+            // The protocol handles this one itself.
+            ExtendedSystemCommand::DisconnectMessage => {}
+        }
+        Ok(())
+    }
+
+    /// Hand the protocol whatever the peer has sent over TCP so far.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(0)) => {
+                    self.protocol.handle_eof();
+                    return Ok(());
+                }
+                Poll::Ready(Ok(n)) => self.protocol.handle_bytes(&buf[..n]),
+                Poll::Ready(Err(e)) => return Err(e.into()),
+                Poll::Pending => return Ok(()),
+            }
         }
     }
 
-    fn poll_system_rx(
+    /// Hand the protocol whatever datagrams the peer has sent us.
+    ///
+    /// Datagrams are best-effort: one that fails is dropped,
+    /// and losing the low-latency channel doesn't close us.
+    fn poll_read_datagrams(
         &mut self,
-        mut dispatcher: &mut TypeDispatcher,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        extended: &mut Vec<ExtendedSystemCommand>,
         cx: &mut Context<'_>,
-    ) -> Poll<Result<EndpointStatus>> {
-        match self.system_rx.as_mut() {
-            Some(rx) => match ready!(rx.as_mut().poll_next(cx)) {
-                None => Poll::Ready(Ok(EndpointStatus::Closed)),
-                Some(cmd) => {
-                    if let Some(cmd) =
-                        handle_system_command(&mut dispatcher, self.translation_tables_mut(), cmd)?
-                    {
-                        match cmd {
-                            ExtendedSystemCommand::UdpDescription(desc) => {
-                                eprintln!("UdpDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::LogDescription(desc) => {
-                                eprintln!("LogDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::DisconnectMessage => {
-                                eprintln!("DisconnectMessage");
-                            }
+    ) {
+        if let Some(mut datagrams) = self.low_latency_rx.take() {
+            loop {
+                match datagrams.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Ok((datagram, addr)))) => {
+                        if let Err(e) = self
+                            .protocol
+                            .handle_datagram(&datagram, dispatcher, received, extended)
+                        {
+                            eprintln!("Dropping low-latency messages from {}: {}", addr, e);
                         }
                     }
-                    Poll::Ready(Ok(EndpointStatus::Open))
+                    Poll::Ready(Some(Err(e))) => {
+                        eprintln!("Low-latency channel failed: {}", e);
+                        break;
+                    }
+                    Poll::Ready(None) => break,
+                    Poll::Pending => {
+                        self.low_latency_rx = Some(datagrams);
+                        break;
+                    }
                 }
-            },
-            None => Poll::Ready(Ok(EndpointStatus::Closed)),
+            }
         }
     }
 
+    /// Write as much of what the protocol has queued as the stream will take, and flush it.
+    ///
+    /// Ready once everything queued has been flushed.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if let Some(mut tx) = self.low_latency_tx.take() {
+            // Datagrams are best-effort, so losing the low-latency channel doesn't close us.
+            let mut failed = false;
+            while let Some(datagram) = self.protocol.take_datagram() {
+                failed |= tx.as_mut().unbounded_send(datagram).is_err();
+            }
+            if let Poll::Ready(Err(e)) = tx.as_mut().poll(cx) {
+                eprintln!("Low-latency channel failed: {}", e);
+                failed = true;
+            }
+            if !failed {
+                self.low_latency_tx = Some(tx);
+            }
+        }
+        while !self.protocol.output().is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, self.protocol.output()))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+            }
+            self.protocol.consume_output(n);
+        }
+        Pin::new(&mut self.stream)
+            .poll_flush(cx)
+            .map_err(VrpnError::from)
+    }
+
+    /// Poll the endpoint, handling system messages and appending other received messages
+    /// to `received` for the caller to dispatch.
+    ///
+    /// Ready when the endpoint has closed, or finished closing after a disconnect.
     pub(crate) fn poll_endpoint(
         &mut self,
         dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<()>> {
-        let channel_rx_arc = Arc::clone(&self.reliable_rx);
-        let mut channel_rx = channel_rx_arc.lock().map_err(to_other_error)?;
+        self.waker = Some(cx.waker().clone());
+        let mut extended = Vec::new();
+        self.poll_read(cx)?;
+        self.protocol.process(dispatcher, received, &mut extended)?;
+        self.poll_read_datagrams(dispatcher, received, &mut extended, cx);
+        for msg in self.protocol.take_recorded() {
+            self.log.log_incoming(&msg)?;
+            self.peer_log.log_incoming(&msg)?;
+        }
+        for cmd in extended {
+            self.handle_extended_command(cmd, dispatcher)?;
+        }
 
-        //
-        let mut endpoint_status =
-            poll_and_dispatch(self, channel_rx.deref_mut(), dispatcher, cx).to_endpoint_status();
+        // Flush anything queued, including replies to what we just handled.
+        let sent = self.poll_write(cx)?;
+        self.log.flush()?;
+        self.peer_log.flush()?;
 
-        match self.reliable_tx.as_mut().poll(cx) {
-            Poll::Ready(Ok(())) => {
-                println!("Remote end of reliable connection has shut down.");
-                endpoint_status = merge_status(endpoint_status, EndpointStatus::Closed);
+        if self.protocol.is_closed() {
+            if sent.is_ready() {
+                let _ = ready!(Pin::new(&mut self.stream).poll_close(cx));
             }
-            Poll::Ready(Err(e)) => endpoint_status = EndpointStatus::ClosedError(e),
-            Poll::Pending => {}
-        }
-        // todo UDP here.
-
-        // Now, process the messages we sent ourself.
-        loop {
-            match self.poll_system_rx(dispatcher, cx) {
-                Poll::Ready(Ok(new_status)) => {
-                    endpoint_status = merge_status(endpoint_status, new_status)
-                }
-                Poll::Ready(Err(_e)) => {}
-                Poll::Pending => break,
+            if let Some(tx) = self.low_latency_tx.as_mut() {
+                tx.close();
             }
+            return Poll::Ready(Ok(()));
         }
-        if endpoint_status.is_closed() {
-            self.reliable_tx.close();
-        }
+        Poll::Pending
+    }
 
-        endpoint_status.into()
+    /// Wake the task polling us, to send what was just queued.
+    fn wake(&self) {
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
     }
 }
 
 impl Endpoint for EndpointIp {
     fn translation_tables(&self) -> &TranslationTables {
-        &self.translation
+        self.protocol.translation_tables()
     }
 
     fn translation_tables_mut(&mut self) -> &mut TranslationTables {
-        &mut self.translation
+        self.protocol.translation_tables_mut()
     }
 
     fn send_system_change(&self, message: SystemCommand) -> Result<()> {
-        println!("send_system_change {:?}", message);
-        if let Some(tx) = self.system_tx.clone().as_deref_mut() {
-            tx.unbounded_send(message).map_err(to_other_error)?;
-        }
+        self.protocol.send_system_change(message)?;
+        self.wake();
         Ok(())
     }
 
-    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
-        if class.contains(ClassOfService::RELIABLE) || self.low_latency_channel.is_none() {
-            // We either need reliable, or don't have low-latency
-            self.reliable_tx.as_mut().unbounded_send(msg)
-        } else {
-            // have and can use low-latency
-            unimplemented!()
-        }
+    fn disconnect(&mut self) -> Result<()> {
+        // The protocol queues the disconnect message itself, bypassing our logging.
+        let msg = GenericMessage::try_from(TypedMessage::from(Disconnect))?;
+        self.log.log_outgoing(&msg)?;
+        self.peer_log.log_outgoing(&msg)?;
+        self.protocol.disconnect()?;
+        self.wake();
+        Ok(())
     }
 
-    fn send_all_descriptions(&mut self, dispatcher: &TypeDispatcher) -> Result<()> {
-        let messages = dispatcher.pack_all_descriptions()?;
-        for msg in messages.into_iter() {
-            self.buffer_generic_message(msg, crate::data_types::ClassOfService::RELIABLE)?;
-        }
+    fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
+        self.log.log_outgoing(&msg)?;
+        self.peer_log.log_outgoing(&msg)?;
+        self.protocol.buffer_generic_message(msg, class)?;
+        self.wake();
         Ok(())
     }
 }
@@ -165,16 +349,104 @@ impl Endpoint for EndpointIp {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{vrpn_async::cookie, ServerInfo, VrpnError};
-    use async_std::net::TcpStream;
+    use crate::{
+        data_types::{GenericBody, MessageHeader, StaticMessageTypeName, StaticSenderName},
+        vrpn_async::cookie,
+        ServerInfo,
+    };
+    use async_std::net::{TcpListener, TcpStream};
+    use bytes::Bytes;
     use futures::executor::block_on;
+    use std::time::Duration;
+
+    #[test]
+    fn udp_description_opens_low_latency_channel() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut client = EndpointIp::new(tcp, Some(udp));
+            let mut server = EndpointIp::new(accepted, None);
+
+            let mut server_disp = TypeDispatcher::new();
+            let sender = server_disp
+                .register_sender(StaticSenderName(b"Tracker0"))
+                .unwrap()
+                .into_inner();
+            let message_type = server_disp
+                .register_type(StaticMessageTypeName(b"Test Type"))
+                .unwrap()
+                .into_inner();
+            server.send_all_descriptions(&server_disp).unwrap();
+            client.send_udp_description().unwrap();
+
+            let mut client_disp = TypeDispatcher::new();
+            let mut client_received = Vec::new();
+            let mut server_received = Vec::new();
+            let mut poll_both =
+                |cx: &mut Context<'_>, client: &mut EndpointIp, server: &mut EndpointIp| {
+                    let _ = client.poll_endpoint(&mut client_disp, &mut client_received, cx);
+                    let _ = server.poll_endpoint(&mut server_disp, &mut server_received, cx);
+                    client_received.len()
+                };
+
+            // Wait for the server to learn our UDP address.
+            async_std::future::timeout(
+                Duration::from_secs(5),
+                futures::future::poll_fn(|cx| {
+                    poll_both(cx, &mut client, &mut server);
+                    if server.low_latency_tx.is_some() {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                }),
+            )
+            .await
+            .expect("should get the UDP description");
+
+            server
+                .buffer_generic_message(
+                    GenericMessage {
+                        header: MessageHeader::new(None, message_type, sender),
+                        body: GenericBody::new(Bytes::from_static(b"12345678")),
+                    },
+                    ClassOfService::LOW_LATENCY,
+                )
+                .unwrap();
+            assert!(
+                server.protocol.output().is_empty(),
+                "should not go over TCP"
+            );
+
+            async_std::future::timeout(
+                Duration::from_secs(5),
+                futures::future::poll_fn(|cx| {
+                    if poll_both(cx, &mut client, &mut server) == 0 {
+                        Poll::Pending
+                    } else {
+                        Poll::Ready(())
+                    }
+                }),
+            )
+            .await
+            .expect("should receive the message over UDP");
+            assert_eq!(
+                &client_received[0].body.clone().into_inner()[..],
+                &b"12345678"[..]
+            );
+        });
+    }
 
     async fn connect_and_handshake(server_info: ServerInfo) -> crate::Result<TcpStream> {
         let mut stream = TcpStream::connect(server_info.socket_addr).await?;
         stream.set_nodelay(true)?;
 
         // We first write our cookie, then read and check the server's cookie, before the loop.
-        cookie::send_nonfile_cookie(&mut stream).await?;
+        cookie::send_nonfile_cookie(&mut stream, LogMode::NONE).await?;
         cookie::read_and_check_nonfile_cookie(&mut stream).await?;
         Ok(stream)
     }
@@ -196,16 +468,24 @@ mod tests {
         let result: Result<()> = block_on(async {
             let tcp = connect_and_handshake(server).await.unwrap();
 
-            let ep = EndpointIp::new(tcp, None);
-            let rx = Arc::clone(&ep.reliable_rx);
-            for _i in 0..4 {
-                let msg = rx
-                    .lock()?
-                    .next()
-                    .await
-                    .ok_or(VrpnError::GenericErrorReturn)?;
-                eprintln!("Received message {:?}", msg);
+            let mut ep = EndpointIp::new(tcp, None);
+            let mut disp = TypeDispatcher::new();
+            let mut received = Vec::new();
+            while received.len() < 4 {
+                let num_received = received.len();
+                futures::future::poll_fn(|cx| {
+                    if let Poll::Ready(result) = ep.poll_endpoint(&mut disp, &mut received, cx) {
+                        return Poll::Ready(result.and(Err(VrpnError::EndpointClosed)));
+                    }
+                    if received.len() > num_received {
+                        Poll::Ready(Ok(()))
+                    } else {
+                        Poll::Pending
+                    }
+                })
+                .await?;
             }
+            eprintln!("Received messages {:?}", received);
             Ok(())
         });
         result.unwrap();
@@ -6,8 +6,9 @@ extern crate pin_project_lite;
 
 pub mod connect;
 pub mod connection_ip;
+mod duplex;
 pub mod endpoint_ip;
 mod endpoints;
-mod unbounded_message_sender;
+mod unbounded_datagram_sender;
 
-pub(crate) use unbounded_message_sender::UnboundedMessageSender;
+pub(crate) use unbounded_datagram_sender::UnboundedDatagramSender;
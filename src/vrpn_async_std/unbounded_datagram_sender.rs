@@ -0,0 +1,96 @@
+// Copyright 2018-2022, Collabora, Ltd.
+// SPDX-License-Identifier: BSL-1.0
+// Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
+
+use crate::{error::to_other_error, Result, VrpnError};
+use async_std::net::UdpSocket;
+use bytes::Bytes;
+use futures::{channel::mpsc, future::FusedFuture, Future, FutureExt, StreamExt};
+use std::{
+    fmt::Debug,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The actual async function underlying UnboundedDatagramSender
+async fn sender(
+    socket: UdpSocket,
+    peer: SocketAddr,
+    channel_rx: mpsc::UnboundedReceiver<Bytes>,
+) -> Result<()> {
+    let mut channel_rx = channel_rx;
+    while let Some(datagram) = channel_rx.next().await {
+        // Datagrams are best-effort: one that fails to send is dropped.
+        if let Err(e) = socket.send(&datagram).await {
+            eprintln!("Dropping datagram to {}: {}", peer, e);
+        }
+    }
+    Ok(())
+}
+
+type FusedBoxFuture<'a, T> = Pin<Box<dyn FusedFuture<Output = T> + Send + 'a>>;
+
+/// A structure that lets you send datagrams to a peer
+/// just like an unbounded channel.
+pub(crate) struct UnboundedDatagramSender {
+    channel_tx: mpsc::UnboundedSender<Bytes>,
+    send_future: FusedBoxFuture<'static, Result<()>>,
+}
+
+impl UnboundedDatagramSender {
+    /// Create a future that pumps transmission of datagrams over a UDP socket
+    /// connected to `peer`.
+    pub(crate) fn new(socket: UdpSocket, peer: SocketAddr) -> Pin<Box<UnboundedDatagramSender>> {
+        let (channel_tx, channel_rx) = mpsc::unbounded();
+        Box::pin(UnboundedDatagramSender {
+            channel_tx,
+            send_future: Box::pin(sender(socket, peer, channel_rx).fuse()),
+        })
+    }
+}
+
+impl UnboundedDatagramSender {
+    /// Queues a datagram to be sent.
+    pub(crate) fn unbounded_send(self: Pin<&mut Self>, datagram: Bytes) -> Result<()> {
+        if self.is_terminated() {
+            return Err(VrpnError::EndpointClosed);
+        }
+        self.channel_tx
+            .unbounded_send(datagram)
+            .map_err(to_other_error)?;
+        Ok(())
+    }
+
+    /// Closes the channel feeding this this sender
+    pub(crate) fn close(&mut self) {
+        if !self.is_terminated() {
+            self.channel_tx.close_channel()
+        }
+    }
+}
+
+impl Debug for UnboundedDatagramSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("UnboundedDatagramSender")
+            .field("channel_tx", &self.channel_tx)
+            .field("send_future", &!self.send_future.is_terminated())
+            .finish()
+    }
+}
+
+impl Future for UnboundedDatagramSender {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.send_future.as_mut().poll(cx)
+    }
+}
+
+impl Unpin for UnboundedDatagramSender {}
+
+impl FusedFuture for UnboundedDatagramSender {
+    fn is_terminated(&self) -> bool {
+        self.send_future.is_terminated() || self.channel_tx.is_closed()
+    }
+}
@@ -3,8 +3,11 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    buffer_unbuffer::BufferSize, codec::maybe_decode_one,
-    data_types::message::SequencedGenericMessage, Result, VrpnError,
+    buffer_unbuffer::BufferSize,
+    codec::maybe_decode_one,
+    data_types::message::{GenericMessage, SequencedGenericMessage},
+    log_file::maybe_decode_record,
+    Result, VrpnError,
 };
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder, Framed};
@@ -50,6 +53,18 @@ impl Encoder<SequencedGenericMessage> for FramedMessageCodec {
     }
 }
 
+/// Codec reading the records of a log file, laid out as described in `log_file`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LogRecordCodec;
+
+impl Decoder for LogRecordCodec {
+    type Item = GenericMessage;
+    type Error = VrpnError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        maybe_decode_record(src)
+    }
+}
+
 pub type MessageFramed<T> = Framed<T, FramedMessageCodec>;
 
 pub fn apply_message_framing<T: tokio::io::AsyncRead + tokio::io::AsyncWrite>(
@@ -64,7 +79,6 @@ mod tests {
     use crate::data_types::{
         descriptions::InnerDescription, id_types::SenderId, message::TypedMessage,
     };
-    use bytes::BufMut;
     type SenderInnerDesc = TypedMessage<InnerDescription<SenderId>>;
     use std::convert::TryFrom;
 
@@ -87,7 +101,7 @@ mod tests {
             assert!(decoded.is_ok());
             let decoded = decoded.unwrap();
             assert!(decoded.is_some());
-            assert_eq!(data.remaining_mut(), 0);
+            assert!(data.is_empty());
         }
     }
 
@@ -98,10 +112,11 @@ mod tests {
             all_bytes.append(&mut msg_bytes.clone());
         }
         let mut data = BytesMut::from(&all_bytes[..]);
-        let mut decoded = Vec::new();
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
-        decoded.push(FramedMessageCodec.decode(&mut data).unwrap().unwrap());
+        let decoded = [
+            FramedMessageCodec.decode(&mut data).unwrap().unwrap(),
+            FramedMessageCodec.decode(&mut data).unwrap().unwrap(),
+            FramedMessageCodec.decode(&mut data).unwrap().unwrap(),
+        ];
 
         assert_eq!(
             &to_sender_inner_desc(&decoded[0]).body.name[..],
@@ -1,9 +1,676 @@
-// Copyright 2018, Collabora, Ltd.
+// Copyright 2018-2022, Collabora, Ltd.
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
+//! Playback of recorded log files, like mainline's `vrpn_File_Connection`.
+
+use crate::{
+    buffer_unbuffer::UnbufferFrom,
+    connection::*,
+    data_types::{
+        id_types::{LocalId, SenderId},
+        GenericMessage, TimeVal, TypedMessage, TypedMessageBody,
+    },
+    file_controller::{JumpToTime, PlayToTime, Reset, SetReplayRate, FILE_CONTROLLER},
+    handler::{HandlerCode, TypedHandler},
+    vrpn_tokio::endpoint_file::EndpointFile,
+    Result, TypeDispatcher,
+};
+use futures::{ready, Future, Stream};
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex},
+    fmt,
+    marker::PhantomData,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::{Duration, SystemTime},
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Upper limit on messages dispatched per poll, so playing as fast as possible
+/// doesn't starve other tasks or read the whole file into memory.
+const MAX_MESSAGES_PER_POLL: usize = 64;
+
+/// How a file connection paces the messages it plays back.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum PlaybackMode {
+    /// Dispatch messages with the same spacing in time as when they were recorded.
+    OriginalTiming,
+    /// Dispatch messages as fast as they can be read.
+    AsFastAsPossible,
+    /// Only dispatch messages when stepped with `ConnectionFile::step`.
+    Manual,
+}
+
+#[derive(Debug)]
+struct Playback {
+    mode: PlaybackMode,
+    /// Speed multiplier for original timing: 0 or less pauses.
+    rate: f32,
+    /// Whether to go back to the start at the end of the file.
+    looping: bool,
+    /// Play everything up to this long after the first message right away.
+    play_to: Option<Duration>,
+    /// The wall-clock instant that the recorded time corresponds to, once playing in time.
+    origin: Option<(Instant, TimeVal)>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// Messages that may be dispatched in manual mode.
+    steps: usize,
+    waker: Option<Waker>,
+}
+
+impl Playback {
+    fn new(mode: PlaybackMode) -> Playback {
+        Playback {
+            mode,
+            rate: 1.0,
+            looping: false,
+            play_to: None,
+            origin: None,
+            sleep: None,
+            steps: 0,
+            waker: None,
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+    }
+
+    /// Forget where we were in time, so playing in time continues from the next message.
+    fn restart_timing(&mut self) {
+        self.origin = None;
+        self.sleep = None;
+    }
+
+    /// Whether a message recorded at `time` is due to be played, registering to be woken if not.
+    fn poll_due(&mut self, time: TimeVal, cx: &mut Context<'_>) -> Poll<()> {
+        match self.mode {
+            PlaybackMode::AsFastAsPossible => Poll::Ready(()),
+            PlaybackMode::Manual => {
+                if self.steps == 0 {
+                    return Poll::Pending;
+                }
+                self.steps -= 1;
+                Poll::Ready(())
+            }
+            PlaybackMode::OriginalTiming => {
+                if self.rate.is_nan() || self.rate <= 0.0 {
+                    self.sleep = None;
+                    return Poll::Pending;
+                }
+                let now = Instant::now();
+                let (start, first) = *self.origin.get_or_insert((now, time));
+                let offset = SystemTime::from(time)
+                    .duration_since(SystemTime::from(first))
+                    .unwrap_or_default();
+                let due = start + offset.div_f32(self.rate);
+                if due <= now {
+                    self.sleep = None;
+                    return Poll::Ready(());
+                }
+                let mut sleep = Box::pin(sleep_until(due));
+                let result = sleep.as_mut().poll(cx);
+                self.sleep = Some(sleep);
+                result
+            }
+        }
+    }
+
+    /// Whether a message recorded at `time` should be played right away to reach a `play_to` time.
+    fn playing_to(&mut self, time: TimeVal, endpoint: &EndpointFile) -> bool {
+        if let (Some(until), Some(start)) = (self.play_to, endpoint.start_time()) {
+            if SystemTime::from(time) <= SystemTime::from(start) + until {
+                return true;
+            }
+            self.play_to = None;
+            self.restart_timing();
+        }
+        false
+    }
+
+    /// Collect the messages from the file that are due to be played.
+    ///
+    /// Resolves to `None` at the end of the file.
+    fn poll_collect(
+        &mut self,
+        endpoint: &mut EndpointFile,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<()>>> {
+        self.waker = Some(cx.waker().clone());
+        while let Poll::Ready(Some(msg)) = endpoint.poll_local(cx) {
+            received.push(msg);
+        }
+        if !received.is_empty() {
+            // These may be controls, so dispatch them before reading any further.
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        while received.len() < MAX_MESSAGES_PER_POLL {
+            let peeked = ready!(endpoint.poll_peek(dispatcher, cx))?.map(|msg| msg.header.time);
+            let time = match peeked {
+                Some(time) => time,
+                // Only loop if there is something to play, or we'd never stop.
+                None if self.looping && endpoint.start_time().is_some() => {
+                    endpoint.rewind();
+                    self.play_to = None;
+                    self.restart_timing();
+                    continue;
+                }
+                None => return Poll::Ready(Ok(None)),
+            };
+            if !self.playing_to(time, endpoint) && self.poll_due(time, cx).is_pending() {
+                return Poll::Pending;
+            }
+            received.extend(endpoint.take_next());
+        }
+        // Come back for the rest after letting others run.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// A connection that plays back the messages recorded in a log file.
+pub struct ConnectionFile {
+    core: ConnectionCore<EndpointFile>,
+    playback: Mutex<Playback>,
+}
+
+impl ConnectionFile {
+    /// Create a connection playing back an open log file.
+    ///
+    /// The connection also obeys file controller messages sent on it.
+    pub async fn new(file: std::fs::File, mode: PlaybackMode) -> Result<Arc<ConnectionFile>> {
+        let endpoint = EndpointFile::new(file).await?;
+        let connection = Arc::new(ConnectionFile {
+            core: ConnectionCore::new(vec![Some(endpoint)], None, None),
+            playback: Mutex::new(Playback::new(mode)),
+        });
+        let controller = connection.register_sender(FILE_CONTROLLER)?;
+        ControlHandler::<SetReplayRate>::add(&connection, controller)?;
+        ControlHandler::<Reset>::add(&connection, controller)?;
+        ControlHandler::<PlayToTime>::add(&connection, controller)?;
+        ControlHandler::<JumpToTime>::add(&connection, controller)?;
+        Ok(connection)
+    }
+
+    /// Open a log file and create a connection playing it back.
+    pub async fn open(path: impl AsRef<Path>, mode: PlaybackMode) -> Result<Arc<ConnectionFile>> {
+        ConnectionFile::new(std::fs::File::open(path)?, mode).await
+    }
+
+    /// Get how messages are being paced.
+    pub fn mode(&self) -> Result<PlaybackMode> {
+        Ok(self.playback.lock()?.mode)
+    }
+
+    /// Change how messages are paced.
+    ///
+    /// Switching to original timing continues from the next message, without catching up.
+    pub fn set_mode(&self, mode: PlaybackMode) -> Result<()> {
+        let mut playback = self.playback.lock()?;
+        playback.mode = mode;
+        playback.origin = None;
+        playback.sleep = None;
+        playback.wake();
+        Ok(())
+    }
+
+    /// In manual mode, allow one more message to be dispatched on the next poll.
+    pub fn step(&self) -> Result<()> {
+        let mut playback = self.playback.lock()?;
+        playback.steps += 1;
+        playback.wake();
+        Ok(())
+    }
+
+    /// Get the speed multiplier for original timing.
+    pub fn replay_rate(&self) -> Result<f32> {
+        Ok(self.playback.lock()?.rate)
+    }
+
+    /// Change the speed of original timing: 1.0 is as recorded, 0 or less pauses.
+    ///
+    /// Continues from the next message, without catching up.
+    pub fn set_replay_rate(&self, rate: f32) -> Result<()> {
+        let mut playback = self.playback.lock()?;
+        playback.rate = rate;
+        playback.restart_timing();
+        playback.wake();
+        Ok(())
+    }
+
+    /// Get whether playback goes back to the start at the end of the file.
+    pub fn looping(&self) -> Result<bool> {
+        Ok(self.playback.lock()?.looping)
+    }
+
+    /// Change whether playback goes back to the start at the end of the file.
+    pub fn set_looping(&self, looping: bool) -> Result<()> {
+        let mut playback = self.playback.lock()?;
+        playback.looping = looping;
+        playback.wake();
+        Ok(())
+    }
+
+    /// Go back to the start of the file.
+    pub fn reset(&self) -> Result<()> {
+        self.reposition(EndpointFile::rewind)
+    }
+
+    /// Play every message up to `elapsed` after the first one right away,
+    /// whatever the mode, then carry on from there.
+    pub fn play_to_time(&self, elapsed: Duration) -> Result<()> {
+        let mut playback = self.playback.lock()?;
+        playback.play_to = Some(elapsed);
+        playback.wake();
+        Ok(())
+    }
+
+    /// Skip to the first message at least `elapsed` after the first one,
+    /// without playing the messages in between.
+    ///
+    /// Parts of the file already played are indexed, so jumping back into them
+    /// doesn't re-read the file.
+    pub fn jump_to_time(&self, elapsed: Duration) -> Result<()> {
+        self.reposition(|endpoint| endpoint.jump_to(elapsed))
+    }
+
+    fn reposition(&self, f: impl Fn(&mut EndpointFile)) -> Result<()> {
+        let endpoints = self.endpoints();
+        let mut endpoints = endpoints.lock()?;
+        let mut playback = self.playback.lock()?;
+        for endpoint in endpoints.iter_mut().flatten() {
+            f(endpoint);
+        }
+        playback.play_to = None;
+        playback.restart_timing();
+        playback.wake();
+        Ok(())
+    }
+
+    /// Read from the file and dispatch the messages that are due.
+    ///
+    /// Resolves to `None` once the whole file has been played.
+    pub fn poll_endpoints(&self, cx: &mut Context<'_>) -> Poll<Result<Option<()>>> {
+        let dispatcher = self.dispatcher();
+        let mut dispatcher = dispatcher.lock()?;
+        let mut received = Vec::new();
+        let result = {
+            let endpoints = self.endpoints();
+            let mut endpoints = endpoints.lock()?;
+            let mut playback = self.playback.lock()?;
+            match endpoints.iter_mut().flatten().next() {
+                Some(endpoint) => {
+                    playback.poll_collect(endpoint, &mut dispatcher, &mut received, cx)
+                }
+                None => Poll::Ready(Ok(None)),
+            }
+        };
+
+        // Dispatch with the endpoints unlocked, so handlers can use the connection.
+        for msg in &received {
+            dispatcher.call(msg)?;
+        }
+        result
+    }
+}
+
+impl Connection for ConnectionFile {
+    type SpecificEndpoint = EndpointFile;
+    fn connection_core(&self) -> &ConnectionCore<Self::SpecificEndpoint> {
+        &self.core
+    }
+
+    /// A file connection acts like a client connected to the recorded server.
+    fn status(&self) -> ConnectionStatus {
+        ConnectionStatus::ClientConnected
+    }
+}
+
+/// File controller messages, and how each is carried out on a file connection.
+trait FileControl: TypedMessageBody + UnbufferFrom + fmt::Debug + Send + Sync + 'static {
+    fn apply(&self, connection: &ConnectionFile) -> Result<()>;
+}
+
+impl FileControl for SetReplayRate {
+    fn apply(&self, connection: &ConnectionFile) -> Result<()> {
+        connection.set_replay_rate(self.rate)
+    }
+}
+
+impl FileControl for Reset {
+    fn apply(&self, connection: &ConnectionFile) -> Result<()> {
+        connection.reset()
+    }
+}
+
+impl FileControl for PlayToTime {
+    fn apply(&self, connection: &ConnectionFile) -> Result<()> {
+        connection.play_to_time(self.elapsed())
+    }
+}
+
+impl FileControl for JumpToTime {
+    fn apply(&self, connection: &ConnectionFile) -> Result<()> {
+        connection.jump_to_time(self.elapsed())
+    }
+}
+
+struct ControlHandler<T> {
+    connection: Weak<ConnectionFile>,
+    control: PhantomData<fn() -> T>,
+}
+
+impl<T: FileControl> ControlHandler<T> {
+    fn add(connection: &Arc<ConnectionFile>, controller: LocalId<SenderId>) -> Result<()> {
+        let _ = connection.add_typed_handler(
+            Box::new(ControlHandler::<T> {
+                connection: Arc::downgrade(connection),
+                control: PhantomData,
+            }),
+            Some(controller),
+        )?;
+        Ok(())
+    }
+}
+
+impl<T> fmt::Debug for ControlHandler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ControlHandler").finish()
+    }
+}
+
+impl<T: FileControl> TypedHandler for ControlHandler<T> {
+    type Item = T;
+    fn handle_typed(&mut self, msg: &TypedMessage<T>) -> Result<HandlerCode> {
+        match self.connection.upgrade() {
+            Some(connection) => {
+                msg.body.apply(&connection)?;
+                Ok(HandlerCode::ContinueProcessing)
+            }
+            None => Ok(HandlerCode::RemoveThisHandler),
+        }
+    }
+}
+
+/// A stream that polls a file connection, for running it on an executor.
+pub struct ConnectionFileStream {
+    connection: Arc<ConnectionFile>,
+}
+
+impl ConnectionFileStream {
+    pub fn new(connection: Arc<ConnectionFile>) -> ConnectionFileStream {
+        ConnectionFileStream { connection }
+    }
+}
+
+impl Stream for ConnectionFileStream {
+    type Item = Result<()>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.connection.poll_endpoints(cx).map(|x| x.transpose())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{
+            id_types::Sensor, MessageTypeIdentifier, Quat, StaticSenderName, TypedMessage,
+            TypedMessageBody, Vec3,
+        },
+        file_controller::Controller,
+        handler::{HandlerCode, TypedHandler},
+        log_file::LogWriter,
+        tracker::*,
+        vrpn_tokio::util::StreamExtras,
+    };
+    use std::{convert::TryFrom, path::PathBuf, time::Duration};
+
+    /// Records the sensor of every pose report, for checking the order things played in.
+    #[derive(Debug)]
+    struct RecordingHandler {
+        sensors: Arc<Mutex<Vec<i32>>>,
+    }
+    impl TypedHandler for RecordingHandler {
+        type Item = PoseReport;
+        fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
+            self.sensors.lock()?.push(msg.body.sensor.0);
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    /// Write a log of `count` pose reports from "Tracker0", `spacing` apart.
+    fn write_log(name: &str, count: usize, spacing: Duration) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vrpn-rs-{}-{}.vrpn", name, std::process::id()));
+        let mut dispatcher = TypeDispatcher::new();
+        let sender = dispatcher
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap()
+            .into_inner();
+        let type_name = match PoseReport::MESSAGE_IDENTIFIER {
+            MessageTypeIdentifier::UserMessageName(name) => name,
+            MessageTypeIdentifier::SystemMessageId(_) => unreachable!(),
+        };
+        let message_type = dispatcher.register_type(type_name).unwrap().into_inner();
+        let mut log = LogWriter::create(&path).unwrap();
+        for desc in dispatcher.pack_all_descriptions().unwrap() {
+            log.write_message(&desc).unwrap();
+        }
+        let start = SystemTime::now();
+        for i in 0..count {
+            let msg = TypedMessage::new(
+                Some(TimeVal::from(start + spacing * i as u32)),
+                message_type,
+                sender,
+                PoseReport {
+                    sensor: Sensor(i as i32),
+                    pos: Vec3::new(0.0, 0.0, 0.0),
+                    quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+                },
+            );
+            log.write_message(&GenericMessage::try_from(msg).unwrap())
+                .unwrap();
+        }
+        log.flush().unwrap();
+        path
+    }
+
+    async fn open_recording(
+        path: &Path,
+        mode: PlaybackMode,
+    ) -> (Arc<ConnectionFile>, Arc<Mutex<Vec<i32>>>) {
+        let conn = ConnectionFile::open(path, mode).await.unwrap();
+        let sensors = Arc::new(Mutex::new(Vec::new()));
+        let sender = conn.register_sender(StaticSenderName(b"Tracker0")).unwrap();
+        conn.add_typed_handler(
+            Box::new(RecordingHandler {
+                sensors: Arc::clone(&sensors),
+            }),
+            Some(sender),
+        )
+        .unwrap();
+        (conn, sensors)
+    }
+
+    /// Poll the connection until `expected` sensors have been recorded,
+    /// then a bit more to catch any extras, and take what was recorded.
+    async fn play_until(
+        conn: &ConnectionFile,
+        sensors: &Mutex<Vec<i32>>,
+        expected: usize,
+    ) -> Vec<i32> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sensors.lock().unwrap().len() < expected {
+                poll_once(conn).await;
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("should play the expected messages");
+        poll_once(conn).await;
+        std::mem::take(&mut *sensors.lock().unwrap())
+    }
+
+    /// Poll the connection once, whether or not anything is ready.
+    async fn poll_once(conn: &ConnectionFile) {
+        futures::future::poll_fn(|cx| {
+            let _ = conn.poll_endpoints(cx);
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn play_as_fast_as_possible() {
+        let path = write_log("fast", 200, Duration::from_secs(1));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::AsFastAsPossible).await;
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            ConnectionFileStream::new(Arc::clone(&conn)).drain(),
+        )
+        .await
+        .expect("should play the whole file quickly")
+        .unwrap();
+        assert_eq!(*sensors.lock().unwrap(), (0..200).collect::<Vec<_>>());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn play_original_timing() {
+        let path = write_log("timing", 3, Duration::from_millis(100));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::OriginalTiming).await;
+        let start = Instant::now();
+        ConnectionFileStream::new(Arc::clone(&conn))
+            .drain()
+            .await
+            .unwrap();
+        assert_eq!(*sensors.lock().unwrap(), vec![0, 1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn play_manual_steps() {
+        let path = write_log("manual", 3, Duration::from_secs(1));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::Manual).await;
+        poll_once(&conn).await;
+        assert!(sensors.lock().unwrap().is_empty());
+
+        conn.step().unwrap();
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 2).await, vec![0, 1]);
+
+        conn.set_mode(PlaybackMode::AsFastAsPossible).unwrap();
+        ConnectionFileStream::new(Arc::clone(&conn))
+            .drain()
+            .await
+            .unwrap();
+        assert_eq!(*sensors.lock().unwrap(), vec![2]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_rate_speeds_up_original_timing() {
+        let path = write_log("rate", 5, Duration::from_millis(500));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::OriginalTiming).await;
+        conn.set_replay_rate(10.0).unwrap();
+        let start = Instant::now();
+        ConnectionFileStream::new(Arc::clone(&conn))
+            .drain()
+            .await
+            .unwrap();
+        assert_eq!(*sensors.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(1500));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn loop_playback() {
+        let path = write_log("loop", 5, Duration::from_secs(1));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::AsFastAsPossible).await;
+        conn.set_looping(true).unwrap();
+        let played = play_until(&conn, &sensors, 12).await;
+        let expected: Vec<i32> = (0..5).cycle().take(12).collect();
+        assert_eq!(played[..12], expected[..]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn play_to_jump_and_reset() {
+        let path = write_log("seek", 10, Duration::from_secs(1));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::Manual).await;
+
+        conn.play_to_time(Duration::from_secs(3)).unwrap();
+        assert_eq!(play_until(&conn, &sensors, 4).await, vec![0, 1, 2, 3]);
+
+        // Forward, past what has been read so far.
+        conn.jump_to_time(Duration::from_secs(7)).unwrap();
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 1).await, vec![7]);
+
+        // Back, into the part of the file that is indexed.
+        conn.jump_to_time(Duration::from_millis(1500)).unwrap();
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 1).await, vec![2]);
+
+        conn.reset().unwrap();
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 1).await, vec![0]);
+
+        // Past the end: nothing left to play.
+        conn.jump_to_time(Duration::from_secs(60)).unwrap();
+        conn.set_mode(PlaybackMode::AsFastAsPossible).unwrap();
+        ConnectionFileStream::new(Arc::clone(&conn))
+            .drain()
+            .await
+            .unwrap();
+        assert!(sensors.lock().unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn control_messages() {
+        let path = write_log("control", 10, Duration::from_secs(1));
+        let (conn, sensors) = open_recording(&path, PlaybackMode::Manual).await;
+        let controller = Controller::new(Arc::clone(&conn)).unwrap();
+
+        controller.play_to_time(Duration::from_secs(2)).unwrap();
+        assert_eq!(play_until(&conn, &sensors, 3).await, vec![0, 1, 2]);
+
+        controller.jump_to_time(Duration::from_secs(5)).unwrap();
+        poll_once(&conn).await;
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 1).await, vec![5]);
+
+        controller.reset().unwrap();
+        poll_once(&conn).await;
+        conn.step().unwrap();
+        assert_eq!(play_until(&conn, &sensors, 1).await, vec![0]);
+
+        controller.set_replay_rate(0.5).unwrap();
+        poll_once(&conn).await;
+        assert_eq!(conn.replay_rate().unwrap(), 0.5);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_non_log_file() {
+        let path = std::env::temp_dir().join(format!("vrpn-rs-notlog-{}.vrpn", std::process::id()));
+        std::fs::write(&path, vec![0u8; 64]).unwrap();
+        assert!(ConnectionFile::open(&path, PlaybackMode::AsFastAsPossible)
+            .await
+            .is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -4,51 +4,102 @@
 
 use crate::{
     connection::*,
-    data_types::id_types::Id,
-    data_types::log::LogFileNames,
+    constants::UDP_BUFLEN,
+    data_types::{LogFileNames, LogMode},
+    log_file::EndpointLog,
     vrpn_tokio::{
-        // codec::FramedMessageCodec,
-        connect::{incoming_handshake, ConnectionIpInfo},
+        connect::{
+            connect, incoming_handshake, outgoing_handshake, outgoing_tcp_connect,
+            parse_lobbed_buf, ConnectResults,
+        },
         endpoint_ip::EndpointIp,
     },
-    Result, ServerInfo, VrpnError,
+    Endpoint, Result, ServerInfo, VrpnError,
 };
-use futures::{ready, Future, FutureExt, Stream};
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, Stream, StreamExt};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::{Arc, Mutex, Weak},
-    task::Poll,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
 };
-use tokio::net::TcpListener;
+use tokio::{
+    io::{DuplexStream, ReadBuf},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+pub(crate) enum ConnectionIpInfo {
+    /// This marks us as a client that has connected: `Reconnect` keeps the server info
+    ClientConnected,
+    /// This stores the future that connects
+    ClientConnectionSetupFuture(BoxFuture<'static, Result<ConnectResults>>),
+    /// This just marks us as a server
+    Server,
+    /// This stores the handshake of one end of a loopback connection
+    LoopbackSetupFuture {
+        handshake: BoxFuture<'static, Result<(DuplexStream, LogMode)>>,
+        server: bool,
+    },
+    /// This marks us as the client end of a loopback connection, which never reconnects
+    ClientLoopback,
+}
+
+impl ConnectionIpInfo {
+    pub(crate) fn status(&self, num_endpoints: usize) -> ConnectionStatus {
+        match self {
+            ConnectionIpInfo::ClientConnectionSetupFuture(_) => ConnectionStatus::ClientConnecting,
+            ConnectionIpInfo::ClientConnected if num_endpoints > 0 => {
+                ConnectionStatus::ClientConnected
+            }
+            ConnectionIpInfo::ClientConnected => ConnectionStatus::Closed,
+            ConnectionIpInfo::Server => ConnectionStatus::Server(num_endpoints),
+            ConnectionIpInfo::LoopbackSetupFuture { server: true, .. } => {
+                ConnectionStatus::Server(num_endpoints)
+            }
+            ConnectionIpInfo::LoopbackSetupFuture { server: false, .. } => {
+                ConnectionStatus::ClientConnecting
+            }
+            ConnectionIpInfo::ClientLoopback if num_endpoints > 0 => {
+                ConnectionStatus::ClientConnected
+            }
+            ConnectionIpInfo::ClientLoopback => ConnectionStatus::Closed,
+        }
+    }
+}
 
-#[derive(Debug)]
 pub struct ConnectionIp {
     core: ConnectionCore<EndpointIp>,
-    // server_tcp: Option<Mutex<TcpListener>>,
-    server_acceptor: Arc<Mutex<Option<ConnectionIpAcceptor>>>,
+    server_acceptor: Option<Mutex<ConnectionIpAcceptor>>,
     client_info: Mutex<ConnectionIpInfo>,
+    /// Only used by a client.
+    reconnect: Option<Mutex<Reconnect>>,
+    /// Where we keep logs that peers ask for, if we allow them.
+    remote_log_dir: Mutex<Option<PathBuf>>,
 }
 
 const DEFAULT_PORT: u16 = 3883;
 
+/// How much each direction of a loopback connection buffers before a writer has to wait.
+const LOOPBACK_BUFFER_SIZE: usize = 64 * 1024;
+
 impl ConnectionIp {
-    /// Create a new ConnectionIp that is a server.
+    /// Create a new ConnectionIp that is a server, listening on `addr`,
+    /// or on all interfaces at the default port if not specified.
+    ///
+    /// Incoming connections are accepted while polling the connection.
     pub fn new_server(
         local_log_names: Option<LogFileNames>,
-        _addr: Option<SocketAddr>,
+        addr: Option<SocketAddr>,
     ) -> Result<Arc<ConnectionIp>> {
-        let conn = Arc::new(ConnectionIp {
+        let acceptor = ConnectionIpAcceptor::new(addr)?;
+        Ok(Arc::new(ConnectionIp {
             core: ConnectionCore::new(Vec::new(), local_log_names, None),
-            server_acceptor: Arc::new(Mutex::new(None)),
-            // server_tcp: Some(Mutex::new(server_tcp)),
+            server_acceptor: Some(Mutex::new(acceptor)),
             client_info: Mutex::new(ConnectionIpInfo::Server),
-        });
-        // {
-        //     let accepter = ConnectionIpAcceptor::new(Arc::downgrade(&conn), addr)?;
-        //     let mut locked_acceptor = conn.server_acceptor.lock()?;
-        //     *locked_acceptor = Some(accepter);
-        // }
-        Ok(conn)
+            reconnect: None,
+            remote_log_dir: Mutex::new(None),
+        }))
     }
 
     /// Create a new ConnectionIp that is a client.
@@ -58,103 +109,267 @@ impl ConnectionIp {
         remote_log_names: Option<LogFileNames>,
     ) -> Result<Arc<ConnectionIp>> {
         let endpoints: Vec<Option<EndpointIp>> = Vec::new();
-        // let connect = Connect::new(server)?;
-        let ret = Arc::new(ConnectionIp {
+        let remote_log_mode = LogFileNames::from(remote_log_names.clone()).log_mode();
+        Ok(Arc::new(ConnectionIp {
             core: ConnectionCore::new(endpoints, local_log_names, remote_log_names),
-            server_acceptor: Arc::new(Mutex::new(None)),
-            client_info: Mutex::new(ConnectionIpInfo::new_client(server)?),
+            server_acceptor: None,
+            client_info: Mutex::new(ConnectionIpInfo::ClientConnectionSetupFuture(
+                connect(server.clone(), remote_log_mode).boxed(),
+            )),
+            reconnect: Some(Mutex::new(Reconnect::new(server))),
+            remote_log_dir: Mutex::new(None),
+        }))
+    }
+
+    /// Create a server and a client connected to it in memory, in that order, without sockets.
+    ///
+    /// The two exchange cookies and descriptions as over TCP, once each is polled,
+    /// so this suits hermetic tests, or device servers and clients in one process.
+    /// There is no low-latency channel, and the client never reconnects.
+    pub fn new_loopback_pair() -> Result<(Arc<ConnectionIp>, Arc<ConnectionIp>)> {
+        let (mut server_stream, mut client_stream) = tokio::io::duplex(LOOPBACK_BUFFER_SIZE);
+        let new_end = |client_info| ConnectionIp {
+            core: ConnectionCore::new(Vec::new(), None, None),
+            server_acceptor: None,
+            client_info: Mutex::new(client_info),
+            reconnect: None,
+            remote_log_dir: Mutex::new(None),
+        };
+        let server = new_end(ConnectionIpInfo::LoopbackSetupFuture {
+            handshake: async move {
+                let log_requested = incoming_handshake(&mut server_stream, LogMode::NONE).await?;
+                Ok((server_stream, log_requested))
+            }
+            .boxed(),
+            server: true,
         });
-        ret.send_all_descriptions()?;
-        Ok(ret)
-    }
-
-    pub fn poll_endpoints(&self, cx: &mut std::task::Context<'_>) -> Poll<Result<Option<()>>> {
-        // eprintln!("in <ConnectionIp as Future>::poll");
-        // if let Some(listener_mutex) = &self.server_tcp {
-        //     let listener = listener_mutex.lock()?;
-        //     match listener.incoming().poll()? {
-        //         Async::Ready(Some(sock)) => {
-        //             // OK, we got a new one.
-        //             let endpoints = self.endpoints();
-        //             tokio::spawn(
-        //                 incoming_handshake(sock)
-        //                     .and_then(move |stream| {
-        //                         if let Ok(mut epoints) = endpoints.lock() {
-        //                             epoints.push(Some(EndpointIp::new(stream)));
-        //                         }
-        //                         Ok(())
-        //                     })
-        //                     .map_err(|e| {
-        //                         eprintln!("err: {:?}", e);
-        //                     }),
-        //             );
-        //         }
-        //         Async::Ready(None) => return Ok(Async::Ready(None)),
-        //         Async::NotReady => (),
-        //     }
-        // }
-
-        // Connect/reconnect if needed.
-        {
+        let client = new_end(ConnectionIpInfo::LoopbackSetupFuture {
+            handshake: async move {
+                let log_requested = outgoing_handshake(&mut client_stream, LogMode::NONE).await?;
+                Ok((client_stream, log_requested))
+            }
+            .boxed(),
+            server: false,
+        });
+        Ok((Arc::new(server), Arc::new(client)))
+    }
+
+    /// Set how a client connection tries to reconnect after losing its server,
+    /// or failing to reach it in the first place.
+    ///
+    /// With `None`, the default, it gives up instead:
+    /// `poll_endpoints` resolves and the status becomes `Closed`, so a lost server is noticed.
+    /// Mainline clients silently reconnect: pass `Some(ReconnectPolicy::default())` to do the same.
+    /// Has no effect on a server connection.
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) -> Result<()> {
+        if let Some(reconnect) = &self.reconnect {
+            reconnect.lock()?.set_policy(policy);
+        }
+        Ok(())
+    }
+
+    /// Allow peers to ask us to keep logs of the connection, in files inside `dir`.
+    ///
+    /// With `None`, the default, such requests are refused.
+    /// Peers name the files, so only relative names that stay inside `dir` are accepted.
+    /// A refused request leaves the connection open.
+    pub fn set_remote_log_dir(&self, dir: Option<PathBuf>) -> Result<()> {
+        *self.remote_log_dir.lock()? = dir;
+        Ok(())
+    }
+
+    /// Get a future for the next attempt to connect, after the policy's delay,
+    /// if the reconnect policy says to make one.
+    fn next_attempt(&self) -> Result<Option<BoxFuture<'static, Result<ConnectResults>>>> {
+        let attempt = match &self.reconnect {
+            Some(reconnect) => reconnect.lock()?.next_attempt(),
+            None => None,
+        };
+        let remote_log_mode = self.core.remote_log_names().log_mode();
+        Ok(attempt.map(|(server, delay)| {
+            async move {
+                tokio::time::sleep(delay).await;
+                connect(server, remote_log_mode).await
+            }
+            .boxed()
+        }))
+    }
+
+    /// Get the address a server connection is listening on.
+    ///
+    /// Returns `None` for a client connection.
+    pub fn server_addr(&self) -> Result<Option<SocketAddr>> {
+        match &self.server_acceptor {
+            Some(acceptor) => Ok(Some(acceptor.lock()?.local_addr()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Limit how many clients a server connection serves at once, or `None` for no limit.
+    ///
+    /// Once at the limit, new clients are disconnected right after the handshake,
+    /// until one of the existing clients leaves.
+    /// Has no effect on a client connection.
+    pub fn set_max_clients(&self, max_clients: Option<usize>) -> Result<()> {
+        if let Some(acceptor) = &self.server_acceptor {
+            acceptor.lock()?.max_clients = max_clients;
+        }
+        Ok(())
+    }
+
+    /// Connect or accept as needed, then poll each endpoint and dispatch the messages received.
+    ///
+    /// Resolves to `None` once a client connection has lost its connection to the server,
+    /// unless its reconnect policy says to try again,
+    /// or once the connection has been shut down and all its endpoints have closed.
+    pub fn poll_endpoints(&self, cx: &mut Context<'_>) -> Poll<Result<Option<()>>> {
+        let mut new_endpoints = Vec::new();
+        let shut_down = self.core.is_shut_down();
+
+        // Connect if needed.
+        if !shut_down {
             let mut client_info = self.client_info.lock()?;
-            let ep_arc = self.endpoints();
-            let mut endpoints = ep_arc.lock()?;
-            let num_endpoints = endpoints.len();
-            if let Some(results) = ready!(client_info.poll(num_endpoints))? {
-                todo!();
-                // OK, we finished a connection setup.
-                endpoints.push(Some(EndpointIp::new(
-                    results.tcp.unwrap(),
-                    results.udp, // .map(|sock| UdpFramed::new(sock, FramedMessageCodec)),
-                )));
-            };
+            while let ConnectionIpInfo::ClientConnectionSetupFuture(f) = &mut *client_info {
+                match f.as_mut().poll(cx) {
+                    Poll::Ready(Ok(results)) => {
+                        let mut endpoint = EndpointIp::new(results.tcp, results.udp);
+                        endpoint.set_log(EndpointLog::open(self.core.local_log_names())?);
+                        endpoint.set_peer_log_request(
+                            results.log_requested,
+                            self.remote_log_dir.lock()?.clone(),
+                        );
+                        endpoint.send_udp_description()?;
+                        endpoint.send_log_description(self.core.remote_log_names())?;
+                        new_endpoints.push(endpoint);
+                        if let Some(reconnect) = &self.reconnect {
+                            reconnect.lock()?.connected();
+                        }
+                        *client_info = ConnectionIpInfo::ClientConnected
+                    }
+                    Poll::Ready(Err(e)) => match self.next_attempt()? {
+                        Some(attempt) => {
+                            eprintln!("Failed to connect, will try again: {:?}", e);
+                            *client_info = ConnectionIpInfo::ClientConnectionSetupFuture(attempt);
+                        }
+                        None => return Poll::Ready(Err(e)),
+                    },
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            if let ConnectionIpInfo::LoopbackSetupFuture { handshake, server } = &mut *client_info {
+                let (stream, log_requested) = match handshake.as_mut().poll(cx) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => return Poll::Pending,
+                };
+                let mut endpoint = EndpointIp::from_stream(stream);
+                endpoint.set_log(EndpointLog::open(self.core.local_log_names())?);
+                endpoint.set_peer_log_request(log_requested, self.remote_log_dir.lock()?.clone());
+                if *server {
+                    *client_info = ConnectionIpInfo::Server;
+                } else {
+                    endpoint.send_log_description(self.core.remote_log_names())?;
+                    *client_info = ConnectionIpInfo::ClientLoopback;
+                }
+                new_endpoints.push(endpoint);
+            }
         }
 
-        let mut acceptor = self.server_acceptor.lock()?;
-        match &mut (*acceptor) {
-            Some(a) => loop {
-                let poll_result = a.poll()?;
-                match poll_result {
-                    Poll::Pending => break,
-                    Poll::Ready(Some(_)) => (),
-                    Poll::Ready(None) => return Poll::Ready(Ok(None)),
+        // Accept any new clients if we are a server.
+        if let Some(acceptor) = &self.server_acceptor {
+            let mut acceptor = acceptor.lock()?;
+            if shut_down {
+                acceptor.close();
+            }
+            let mut num_clients = self.endpoints().lock()?.len();
+            while let Poll::Ready(accepted) = acceptor.poll_accept(cx) {
+                let (stream, log_requested) = accepted?;
+                if !acceptor.has_room_for(num_clients) {
+                    // Dropping the stream closes it.
+                    eprintln!(
+                        "Refusing connection from {:?}: already serving {} clients",
+                        stream.peer_addr(),
+                        num_clients
+                    );
+                    continue;
                 }
-            },
-            None => (),
+                num_clients += 1;
+                let mut endpoint = EndpointIp::new(stream, None);
+                endpoint.set_log(EndpointLog::open(self.core.local_log_names())?);
+                endpoint.set_peer_log_request(log_requested, self.remote_log_dir.lock()?.clone());
+                new_endpoints.push(endpoint);
+            }
         }
+
         let endpoints = self.endpoints();
         let dispatcher = self.dispatcher();
-        {
+        let mut dispatcher = dispatcher.lock()?;
+        let mut received = Vec::new();
+        let mut connected = Vec::new();
+        let mut dropped = Vec::new();
+        let got_not_ready = {
             let mut endpoints = endpoints.lock()?;
-            let mut dispatcher = dispatcher.lock()?;
+            for mut endpoint in new_endpoints {
+                endpoint.send_all_descriptions(&dispatcher)?;
+                got_connection_events(&mut dispatcher, endpoints.is_empty(), &mut connected)?;
+                endpoints.push(Some(endpoint));
+            }
             let mut got_not_ready = false;
+            let mut num_dropped = 0;
             // Go through and poll each endpoint, "taking" the ones that are closed.
             for ep in endpoints.iter_mut() {
                 let ready = match ep {
-                    Some(endpoint) => match endpoint.poll_endpoint(&mut dispatcher) {
-                        Poll::Ready(Err(e)) => {
-                            println!("Got endpoint error: {:?}", e);
-                            true
+                    Some(endpoint) => {
+                        match endpoint.poll_endpoint(&mut dispatcher, &mut received, cx) {
+                            Poll::Ready(Err(e)) => {
+                                eprintln!("Got endpoint error: {:?}", e);
+                                true
+                            }
+                            Poll::Ready(Ok(())) => true,
+                            Poll::Pending => false,
                         }
-                        Poll::Ready(_) => true,
-                        Poll::Pending => false,
-                    },
+                    }
                     _ => true,
                 };
                 if ready {
-                    let _ = ep.take();
+                    if ep.take().is_some() {
+                        num_dropped += 1;
+                    }
                 } else {
                     got_not_ready = true;
                 }
             }
             // Now, retain only the non-taken endpoints in the vector.
             endpoints.retain(|ep| ep.is_some());
+            for i in 1..=num_dropped {
+                let last = i == num_dropped && endpoints.is_empty();
+                dropped_connection_events(&mut dispatcher, last, &mut dropped)?;
+            }
+            got_not_ready
+        };
+
+        // Dispatch with the endpoints unlocked, so handlers can pack replies.
+        for msg in connected.iter().chain(&received).chain(&dropped) {
+            dispatcher.call(msg)?;
+        }
+        drop(dispatcher);
 
-            if got_not_ready {
+        if got_not_ready || (self.server_acceptor.is_some() && !shut_down) {
+            return Poll::Pending;
+        }
+        if shut_down {
+            return Poll::Ready(Ok(None));
+        }
+        match self.next_attempt()? {
+            Some(attempt) => {
+                // The new endpoint starts with empty translation tables,
+                // and gets all our descriptions once connected.
+                // Handlers stay registered with the dispatcher.
+                eprintln!("Lost connection to server, will reconnect");
+                *self.client_info.lock()? = ConnectionIpInfo::ClientConnectionSetupFuture(attempt);
+                cx.waker().wake_by_ref();
                 Poll::Pending
-            } else {
-                Poll::Ready(Ok(Some(())))
             }
+            None => Poll::Ready(Ok(None)),
         }
     }
 }
@@ -166,14 +381,22 @@ impl Connection for ConnectionIp {
     }
 
     fn status(&self) -> ConnectionStatus {
-        let ep = self.endpoints();
-        let endpoints = ep.lock().unwrap();
+        if self.core.is_shut_down() {
+            return ConnectionStatus::Closed;
+        }
+        let num_endpoints = self.endpoints().lock().unwrap().len();
         let info = self.client_info.lock().unwrap();
-        info.status(endpoints.len())
+        info.status(num_endpoints)
+    }
+}
+
+impl ClientConnection for ConnectionIp {
+    fn new_client_connection(server: ServerInfo) -> Result<Arc<ConnectionIp>> {
+        ConnectionIp::new_client(server, None, None)
     }
 }
 
-#[derive(Debug)]
+/// A stream that polls a connection, for running it on an executor.
 pub struct ConnectionIpStream {
     connection: Arc<ConnectionIp>,
 }
@@ -187,199 +410,841 @@ impl ConnectionIpStream {
 impl Stream for ConnectionIpStream {
     type Item = Result<()>;
 
-    fn poll_next(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        // eprintln!("in <ConnectionIpStream as Stream>::poll");
-        self.connection.poll_endpoints(cx)
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.connection.poll_endpoints(cx).map(|x| x.transpose())
     }
 }
 
-#[derive(Debug)]
-pub struct ConnectionIpAcceptor {
-    connection: Weak<ConnectionIp>,
-    // server_tcp: Mutex<Incoming<'a>>,
+/// Accepts incoming TCP connections for a server and performs the handshake on them.
+///
+/// Also listens for UDP "lobbed" connection requests on the same port,
+/// from clients that want us to connect back to them.
+pub(crate) struct ConnectionIpAcceptor {
+    /// The listener as bound, before it has been registered with the runtime on first poll.
+    std_listener: Option<std::net::TcpListener>,
+    listener: Option<TcpListener>,
+    /// The lobbing socket as bound, before it has been registered with the runtime on first poll.
+    std_udp: Option<std::net::UdpSocket>,
+    udp: Option<UdpSocket>,
+    /// Each resolves to the stream and the logging the client asked of us.
+    handshakes: FuturesUnordered<BoxFuture<'static, Result<(TcpStream, LogMode)>>>,
+    max_clients: Option<usize>,
 }
+
 impl ConnectionIpAcceptor {
-    pub fn new(
-        connection: Weak<ConnectionIp>,
-        addr: Option<SocketAddr>,
-    ) -> Result<ConnectionIpAcceptor> {
+    fn new(addr: Option<SocketAddr>) -> Result<ConnectionIpAcceptor> {
         let addr = addr.unwrap_or_else(|| {
             SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), DEFAULT_PORT)
         });
-        // let server_tcp = Mutex::new(TcpListener::bind(&addr)?.incoming());
+        let std_listener = std::net::TcpListener::bind(addr)?;
+        std_listener.set_nonblocking(true)?;
+        // Use the port we actually got, in case we were asked for any port.
+        let std_udp = std::net::UdpSocket::bind(std_listener.local_addr()?)?;
+        std_udp.set_nonblocking(true)?;
         Ok(ConnectionIpAcceptor {
-            connection,
-            // server_tcp,
+            std_listener: Some(std_listener),
+            listener: None,
+            std_udp: Some(std_udp),
+            udp: None,
+            handshakes: FuturesUnordered::new(),
+            max_clients: None,
         })
     }
-}
-impl Stream for ConnectionIpAcceptor {
-    type Item = Result<()>;
 
-    fn poll_next(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
-        let mut incoming = self.server_tcp.lock()?;
+    /// Whether we can take another client, when already serving `num_clients`.
+    fn has_room_for(&self, num_clients: usize) -> bool {
+        !matches!(self.max_clients, Some(max) if num_clients >= max)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match (&self.std_listener, &self.listener) {
+            (Some(l), _) => Ok(l.local_addr()?),
+            (None, Some(l)) => Ok(l.local_addr()?),
+            (None, None) => Err(VrpnError::OtherMessage(
+                "Server connection has shut down".to_owned(),
+            )),
+        }
+    }
+
+    /// Stop listening, and drop any connections still in their handshake.
+    fn close(&mut self) {
+        self.std_listener = None;
+        self.listener = None;
+        self.std_udp = None;
+        self.udp = None;
+        self.handshakes = FuturesUnordered::new();
+    }
+
+    /// Poll for a new connection that has completed its handshake,
+    /// along with the logging the client asked of us.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<(TcpStream, LogMode)>> {
+        if let Some(std_listener) = self.std_listener.take() {
+            self.listener = Some(TcpListener::from_std(std_listener)?);
+        }
+        if let Some(std_udp) = self.std_udp.take() {
+            self.udp = Some(UdpSocket::from_std(std_udp)?);
+        }
+        self.poll_lobbed(cx);
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return Poll::Pending,
+        };
         loop {
-            let connection = match self.connection.upgrade() {
-                Some(c) => c,
-                None => return Ok(Poll::Ready(None)),
-            };
-            let socket = match ready!(incoming.poll()) {
-                Some(s) => s,
-                None => return Ok(Poll::Ready(None)),
-            };
-            // OK, we got a new one.
-            let endpoints = connection.endpoints();
-            tokio::spawn(
-                incoming_handshake(socket)
-                    .and_then(move |stream| {
-                        if let Ok(peer) = stream.peer_addr() {
-                            eprintln!("Got connection from {:?}", peer);
-                        } else {
-                            eprintln!("Got connection from some peer we couldn't identify");
-                        }
-                        if let Ok(mut epoints) = endpoints.lock() {
-                            // TODO set up udp
-                            epoints.push(Some(EndpointIp::new(stream, None)));
+            match listener.poll_accept(cx) {
+                Poll::Ready(Ok((mut stream, peer))) => {
+                    eprintln!("Got connection from {:?}", peer);
+                    stream.set_nodelay(true)?;
+                    self.handshakes.push(
+                        async move {
+                            let log_requested =
+                                incoming_handshake(&mut stream, LogMode::NONE).await?;
+                            Ok((stream, log_requested))
                         }
-                        Ok(())
-                    })
-                    .map_err(|e| {
-                        eprintln!("err: {:?}", e);
-                    }),
-            );
+                        .boxed(),
+                    );
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match self.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(accepted))) => return Poll::Ready(Ok(accepted)),
+                Poll::Ready(Some(Err(e))) => {
+                    // A failed handshake only affects that one client.
+                    eprintln!("Handshake with incoming connection failed: {:?}", e);
+                }
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Connect back to any clients that have lobbed us a connection request.
+    fn poll_lobbed(&mut self, cx: &mut Context<'_>) {
+        let udp = match &self.udp {
+            Some(udp) => udp,
+            None => return,
+        };
+        loop {
+            let mut buf = [0u8; UDP_BUFLEN];
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match udp.poll_recv_from(cx, &mut read_buf) {
+                Poll::Ready(Ok(peer)) => match parse_lobbed_buf(read_buf.filled()) {
+                    Ok(addr) => {
+                        eprintln!("Got connection request from {:?}, calling {:?}", peer, addr);
+                        self.handshakes.push(
+                            async move {
+                                let mut stream = outgoing_tcp_connect(addr).await?;
+                                let log_requested =
+                                    incoming_handshake(&mut stream, LogMode::NONE).await?;
+                                Ok((stream, log_requested))
+                            }
+                            .boxed(),
+                        );
+                    }
+                    Err(e) => eprintln!("Ignoring datagram from {:?}: {}", peer, e),
+                },
+                Poll::Ready(Err(e)) => {
+                    // Clients can still connect over TCP.
+                    eprintln!("Stopped listening for connection requests: {}", e);
+                    self.udp = None;
+                    return;
+                }
+                Poll::Pending => return,
+            }
         }
     }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
-        data_types::{Message, StaticMessageTypeName, StaticSenderName, TypedMessage},
-        handler::{HandlerCode, TypedHandler},
+        data_types::{
+            constants,
+            id_types::{LocalId, SenderId, Sensor},
+            ClassOfService, GenericMessage, MessageTypeId, Quat, StaticMessageTypeName,
+            StaticSenderName, TypedMessage, Vec3,
+        },
+        export::{ExportFormat, ExportHandler, Exporter},
+        handler::{Handler, HandlerCode, TypedHandler},
         tracker::*,
-        Id,
+        vrpn_tokio::{util::StreamExtras, ConnectionFile, ConnectionFileStream, PlaybackMode},
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
     };
-    use futures::future::IntoFuture;
-    use std::sync::{Arc, Mutex};
 
     #[derive(Debug)]
     struct TrackerHandler {
-        flag: Arc<Mutex<bool>>,
+        flag: Arc<AtomicBool>,
+    }
+    impl TrackerHandler {
+        fn new(flag: &Arc<AtomicBool>) -> Box<TrackerHandler> {
+            Box::new(TrackerHandler {
+                flag: Arc::clone(flag),
+            })
+        }
     }
     impl TypedHandler for TrackerHandler {
         type Item = PoseReport;
         fn handle_typed(&mut self, msg: &TypedMessage<PoseReport>) -> Result<HandlerCode> {
             println!("{:?}", msg);
-            let mut flag = self.flag.lock()?;
-            *flag = true;
+            self.flag.store(true, Ordering::SeqCst);
             Ok(HandlerCode::ContinueProcessing)
         }
     }
 
-    #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn tracker_tcp() {
-        let flag = Arc::new(Mutex::new(false));
-        let _ = "tcp://127.0.0.1:3883"
-            .parse::<ServerInfo>()
-            .into_future()
-            .and_then(|server| {
-                let conn = ConnectionIp::new_client(server, None, None)?;
-                let sender = conn
-                    .register_sender(StaticSenderName(b"Tracker0"))
-                    .expect("should be able to register sender");
-                let handler_handle = conn.add_typed_handler(
-                    Box::new(TrackerHandler {
-                        flag: Arc::clone(&flag),
-                    }),
-                    Some(sender),
-                )?;
-                conn.pack_all_descriptions()?;
-                for _ in 0..4 {
-                    let _ = conn.poll_endpoints()?;
-                }
-                conn.remove_handler(handler_handle)
-                    .expect("should be able to remove handler");
-                Ok(Poll::Ready(()))
-            })
-            .wait()
+    fn report() -> PoseReport {
+        PoseReport {
+            sensor: Sensor(0),
+            pos: Vec3::new(0.0, 0.0, 0.0),
+            quat: Quat::new(1.0, 0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Start a server on a free local port, with a "Tracker0" sender.
+    ///
+    /// Returns the server, its address, the sender, and the task polling the server.
+    fn start_server(
+        local_log_names: Option<LogFileNames>,
+    ) -> (
+        Arc<ConnectionIp>,
+        SocketAddr,
+        LocalId<SenderId>,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let server =
+            ConnectionIp::new_server(local_log_names, Some("127.0.0.1:0".parse().unwrap()))
+                .unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        let sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
             .unwrap();
+        let task = tokio::spawn(ConnectionIpStream::new(Arc::clone(&server)).drain());
+        (server, addr, sender, task)
+    }
 
-        assert!(*flag.lock().unwrap());
-    }
-
-    #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn tracker() {
-        let flag = Arc::new(Mutex::new(false));
-
-        let _ = "127.0.0.1:3883"
-            .parse::<ServerInfo>()
-            .into_future()
-            .and_then(|server| {
-                let conn = ConnectionIp::new_client(server, None, None)?;
-                let sender = conn
-                    .register_sender(StaticSenderName(b"Tracker0"))
-                    .expect("should be able to register sender");
-                let handler_handle = conn.add_typed_handler(
-                    Box::new(TrackerHandler {
-                        flag: Arc::clone(&flag),
-                    }),
-                    Some(sender),
-                )?;
-                while conn.status() == ConnectionStatus::ClientConnecting {
-                    let _ = conn.poll_endpoints()?;
-                }
-                for _ in 0..4 {
-                    let _ = conn.poll_endpoints()?;
-                }
-                conn.remove_handler(handler_handle)
-                    .expect("should be able to remove handler");
-                Ok(Poll::Ready(()))
-            })
-            .wait()
+    /// Make a TCP client of the server at `addr`.
+    fn new_client(
+        addr: SocketAddr,
+        local_log_names: Option<LogFileNames>,
+        remote_log_names: Option<LogFileNames>,
+    ) -> Arc<ConnectionIp> {
+        ConnectionIp::new_client(
+            format!("tcp://{}", addr).parse::<ServerInfo>().unwrap(),
+            local_log_names,
+            remote_log_names,
+        )
+        .unwrap()
+    }
+
+    /// Start polling a client, which sets its flag when it gets a pose report from "Tracker0".
+    fn start_tracker_client(
+        client: &Arc<ConnectionIp>,
+    ) -> (Arc<AtomicBool>, tokio::task::JoinHandle<Result<()>>) {
+        let flag = Arc::new(AtomicBool::new(false));
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
             .unwrap();
-        assert!(*flag.lock().unwrap());
-    }
-    #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn tracker_manual() {
-        let flag = Arc::new(Mutex::new(false));
-
-        let _server = "tcp://127.0.0.1:3883"
-            .parse::<ServerInfo>()
-            .into_future()
-            .and_then(|server| {
-                let conn = ConnectionIp::new_client(server, None, None)?;
-                let tracker_message_id = conn
-                    .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
-                    .expect("should be able to register type");
-                let sender = conn
-                    .register_sender(StaticSenderName(b"Tracker0"))
-                    .expect("should be able to register sender");
-                conn.add_handler(
-                    Box::new(TrackerHandler {
-                        flag: Arc::clone(&flag),
-                    }),
-                    Some(tracker_message_id),
-                    Some(sender),
-                )?;
-                while conn.status() == ConnectionStatus::ClientConnecting {
-                    let _ = conn.poll_endpoints()?;
+        client
+            .add_typed_handler(TrackerHandler::new(&flag), Some(sender))
+            .unwrap();
+        let task = tokio::spawn(ConnectionIpStream::new(Arc::clone(client)).drain());
+        (flag, task)
+    }
+
+    /// Wait until `condition` holds, panicking with `what` if it takes too long.
+    async fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+        let result = tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(result.is_ok(), "{}", what);
+    }
+
+    /// Have `server` send pose reports from `sender` until `condition` holds,
+    /// panicking with `what` if it takes too long.
+    async fn send_reports_until(
+        server: &ConnectionIp,
+        sender: LocalId<SenderId>,
+        class: ClassOfService,
+        what: &str,
+        mut condition: impl FnMut() -> bool,
+    ) {
+        wait_until(what, || {
+            if condition() {
+                return true;
+            }
+            server
+                .pack_message_body(None, sender, report(), class)
+                .unwrap();
+            false
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn local_server_and_client() {
+        let (server, addr, server_sender, _) = start_server(None);
+        let client = new_client(addr, None, None);
+        let (flag, _) = start_tracker_client(&client);
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+    }
+
+    #[tokio::test]
+    async fn several_clients() {
+        let (server, addr, server_sender, _) = start_server(None);
+        server.set_max_clients(Some(3)).unwrap();
+        {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                loop {
+                    server
+                        .pack_message_body(None, server_sender, report(), ClassOfService::RELIABLE)
+                        .unwrap();
+                    tokio::time::sleep(Duration::from_millis(10)).await;
                 }
-                for _ in 0..4 {
-                    let _ = conn.poll_endpoints()?;
+            });
+        }
+        let all_received = |clients: &[(Arc<AtomicBool>, _)]| {
+            clients.iter().all(|(flag, _)| flag.load(Ordering::SeqCst))
+        };
+
+        let mut clients: Vec<_> = (0..3)
+            .map(|_| start_tracker_client(&new_client(addr, None, None)))
+            .collect();
+        wait_until("clients should receive reports", || all_received(&clients)).await;
+        assert_eq!(server.status(), ConnectionStatus::Server(3));
+
+        // One too many: it gets disconnected, so its connection resolves.
+        let (flag, task) = start_tracker_client(&new_client(addr, None, None));
+        let result = tokio::time::timeout(Duration::from_secs(5), task).await;
+        assert!(result.is_ok(), "extra client should be turned away");
+        assert!(!flag.load(Ordering::SeqCst));
+        assert_eq!(server.status(), ConnectionStatus::Server(3));
+
+        // When a client leaves, the rest carry on, and there is room for another.
+        let (_, task) = clients.remove(0);
+        task.abort();
+        wait_until("server should drop the client", || {
+            server.status() == ConnectionStatus::Server(2)
+        })
+        .await;
+        for (flag, _) in &clients {
+            flag.store(false, Ordering::SeqCst);
+        }
+        clients.push(start_tracker_client(&new_client(addr, None, None)));
+        wait_until("clients should receive reports", || all_received(&clients)).await;
+        assert_eq!(server.status(), ConnectionStatus::Server(3));
+    }
+
+    #[tokio::test]
+    async fn server_shutdown() {
+        let (server, addr, _, server_task) = start_server(None);
+        let clients: Vec<_> = (0..2)
+            .map(|_| start_tracker_client(&new_client(addr, None, None)))
+            .collect();
+        wait_until("clients should connect", || {
+            server.status() == ConnectionStatus::Server(2)
+        })
+        .await;
+
+        // Each client hears the disconnect message and closes, as does the server.
+        server.shutdown().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Closed);
+        let all_closed = tokio::time::timeout(Duration::from_secs(5), async {
+            for (_, task) in clients {
+                task.await.unwrap().unwrap();
+            }
+            server_task.await.unwrap().unwrap();
+        })
+        .await;
+        assert!(all_closed.is_ok(), "everything should close");
+        assert!(
+            TcpStream::connect(addr).await.is_err(),
+            "should stop listening"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_shutdown() {
+        let (server, addr, _, _) = start_server(None);
+        let client = new_client(addr, None, None);
+        // Shutting down wins over reconnecting.
+        client
+            .set_reconnect_policy(Some(ReconnectPolicy::default()))
+            .unwrap();
+        let (_, client_task) = start_tracker_client(&client);
+
+        wait_until("client should connect", || {
+            server.status() == ConnectionStatus::Server(1)
+        })
+        .await;
+        client.shutdown().unwrap();
+        wait_until("server should drop the client's endpoint", || {
+            server.status() == ConnectionStatus::Server(0)
+        })
+        .await;
+        let result = tokio::time::timeout(Duration::from_secs(5), client_task).await;
+        assert!(result.is_ok(), "client should close");
+        assert_eq!(client.status(), ConnectionStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn local_server_and_lobbing_client() {
+        let (server, addr, server_sender, _) = start_server(None);
+        let client = ConnectionIp::new_client(
+            format!("x-vrpn://{}", addr).parse::<ServerInfo>().unwrap(),
+            None,
+            None,
+        )
+        .unwrap();
+        let (flag, _) = start_tracker_client(&client);
+
+        // Low-latency reports go over UDP once the client has described its UDP port.
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::LOW_LATENCY,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+    }
+
+    /// Play back a log file, checking it has a pose report from "Tracker0".
+    async fn assert_log_has_report(path: &std::path::Path) {
+        let flag = Arc::new(AtomicBool::new(false));
+        let conn = ConnectionFile::open(path, PlaybackMode::AsFastAsPossible)
+            .await
+            .unwrap();
+        let sender = conn.register_sender(StaticSenderName(b"Tracker0")).unwrap();
+        conn.add_typed_handler(TrackerHandler::new(&flag), Some(sender))
+            .unwrap();
+        ConnectionFileStream::new(conn).drain().await.unwrap();
+        assert!(flag.load(Ordering::SeqCst), "log should play back a report");
+    }
+
+    /// A name for a log file unique to this test run.
+    fn log_name(name: &str) -> String {
+        format!("vrpn-rs-{}-{}.vrpn", name, std::process::id())
+    }
+
+    #[tokio::test]
+    async fn local_logging() {
+        let server_out = std::env::temp_dir().join(log_name("server-out"));
+        let client_in = std::env::temp_dir().join(log_name("client-in"));
+        let path_name = |path: &std::path::Path| Some(path.to_str().unwrap().to_owned());
+
+        let (server, addr, server_sender, _) =
+            start_server(Some(LogFileNames::from_names(None, path_name(&server_out))));
+        let client = new_client(
+            addr,
+            Some(LogFileNames::from_names(path_name(&client_in), None)),
+            None,
+        );
+        let (flag, _) = start_tracker_client(&client);
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        // Logs are flushed each time the endpoints are polled.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_log_has_report(&client_in).await;
+        assert_log_has_report(&server_out).await;
+        std::fs::remove_file(client_in).unwrap();
+        std::fs::remove_file(server_out).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_logging() {
+        let server_in = log_name("remote-in");
+        let server_out = log_name("remote-out");
+
+        let (server, addr, server_sender, _) = start_server(None);
+        server
+            .set_remote_log_dir(Some(std::env::temp_dir()))
+            .unwrap();
+
+        // The client names the files, and the server keeps them in its log directory.
+        let client = new_client(
+            addr,
+            None,
+            Some(LogFileNames::from_names(
+                Some(server_in.clone()),
+                Some(server_out.clone()),
+            )),
+        );
+        let (flag, _) = start_tracker_client(&client);
+        let client_sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let client_report = || {
+            client
+                .pack_message_body(None, client_sender, report(), ClassOfService::RELIABLE)
+                .unwrap()
+        };
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || {
+                client_report();
+                flag.load(Ordering::SeqCst)
+            },
+        )
+        .await;
+        // Keep the client reporting a little longer, so the server has opened its logs.
+        for _ in 0..5 {
+            client_report();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let server_in = std::env::temp_dir().join(server_in);
+        let server_out = std::env::temp_dir().join(server_out);
+        assert_log_has_report(&server_in).await;
+        assert_log_has_report(&server_out).await;
+        std::fs::remove_file(server_in).unwrap();
+        std::fs::remove_file(server_out).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_logging_refused() {
+        let log_dir = std::env::temp_dir().join(format!("vrpn-rs-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&log_dir).unwrap();
+        let escaped = log_name("escaped");
+
+        let (server, addr, server_sender, _) = start_server(None);
+        server.set_remote_log_dir(Some(log_dir.clone())).unwrap();
+
+        // The client asks for a log outside the server's log directory.
+        let client = new_client(
+            addr,
+            None,
+            Some(LogFileNames::from_names(
+                None,
+                Some(format!("../{}", escaped)),
+            )),
+        );
+        let (flag, _) = start_tracker_client(&client);
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        // The log description went out before any reports, so it has been refused by now,
+        // without dropping the client.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert!(!std::env::temp_dir().join(escaped).exists());
+        std::fs::remove_dir(log_dir).unwrap();
+    }
+
+    /// Collects what is written to it, for reading while it is still being written.
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Records the type of every message from the "VRPN Control" sender.
+    #[derive(Debug)]
+    struct ControlRecorder {
+        control: LocalId<SenderId>,
+        types: Arc<Mutex<Vec<LocalId<MessageTypeId>>>>,
+    }
+    impl Handler for ControlRecorder {
+        fn handle(&mut self, msg: &GenericMessage) -> Result<HandlerCode> {
+            if LocalId(msg.header.sender) == self.control {
+                self.types.lock()?.push(LocalId(msg.header.message_type));
+            }
+            Ok(HandlerCode::ContinueProcessing)
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_events() {
+        let server = ConnectionIp::new_server(None, Some("127.0.0.1:0".parse().unwrap())).unwrap();
+        let addr = server.server_addr().unwrap().unwrap();
+        let types = Arc::new(Mutex::new(Vec::new()));
+        server
+            .add_handler(
+                Box::new(ControlRecorder {
+                    control: server.register_sender(constants::CONTROL).unwrap(),
+                    types: Arc::clone(&types),
+                }),
+                None,
+                None,
+            )
+            .unwrap();
+        let event_types: Vec<_> = [
+            constants::GOT_FIRST_CONNECTION,
+            constants::GOT_CONNECTION,
+            constants::DROPPED_CONNECTION,
+            constants::DROPPED_LAST_CONNECTION,
+        ]
+        .iter()
+        .map(|name| server.register_type(name.clone()).unwrap())
+        .collect();
+        tokio::spawn(ConnectionIpStream::new(Arc::clone(&server)).drain());
+
+        let wait_for_events = |count: usize| {
+            let types = Arc::clone(&types);
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while types.lock().unwrap().len() < count {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
                 }
-                Ok(Poll::Ready(()))
             })
-            .wait()
+        };
+
+        let connect_client = || start_tracker_client(&new_client(addr, None, None)).1;
+        let first_client = connect_client();
+        assert!(wait_for_events(2).await.is_ok());
+        let second_client = connect_client();
+        assert!(wait_for_events(3).await.is_ok());
+        assert_eq!(
+            *types.lock().unwrap(),
+            vec![event_types[0], event_types[1], event_types[1]]
+        );
+
+        // Dropping a client's task drops its connection, closing its socket.
+        first_client.abort();
+        assert!(wait_for_events(4).await.is_ok());
+        second_client.abort();
+        assert!(wait_for_events(6).await.is_ok());
+        assert_eq!(
+            types.lock().unwrap()[3..],
+            [event_types[2], event_types[2], event_types[3]]
+        );
+        assert_eq!(server.status(), ConnectionStatus::Server(0));
+    }
+
+    #[tokio::test]
+    async fn client_reconnects() {
+        let (server, addr, server_sender, server_task) = start_server(None);
+        let client = new_client(addr, None, None);
+        client
+            .set_reconnect_policy(Some(ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                ..Default::default()
+            }))
             .unwrap();
-        assert!(*flag.lock().unwrap());
+        let (flag, _) = start_tracker_client(&client);
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+
+        // Take the server away: the client should notice and start reconnecting.
+        server_task.abort();
+        let _ = server_task.await;
+        drop(server);
+        // Still trying, so connecting rather than closed.
+        wait_until("client should notice the server is gone", || {
+            client.status() == ConnectionStatus::ClientConnecting
+        })
+        .await;
+
+        // A new server at the same address has new IDs,
+        // but the handler registered before should still get its reports.
+        let server = ConnectionIp::new_server(None, Some(addr)).unwrap();
+        let _ = server
+            .register_sender(StaticSenderName(b"Tracker1"))
+            .unwrap();
+        let server_sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        tokio::spawn(ConnectionIpStream::new(Arc::clone(&server)).drain());
+        flag.store(false, Ordering::SeqCst);
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should reconnect and receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+    }
+
+    #[tokio::test]
+    async fn client_without_policy_closes() {
+        let (server, addr, server_sender, server_task) = start_server(None);
+        let client = new_client(addr, None, None);
+        let (flag, client_task) = start_tracker_client(&client);
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+
+        // Take the server away: with no reconnect policy, the client gives up.
+        server_task.abort();
+        let _ = server_task.await;
+        drop(server);
+        let result = tokio::time::timeout(Duration::from_secs(5), client_task).await;
+        assert!(result.is_ok(), "client should close");
+        assert_eq!(client.status(), ConnectionStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn export_received() {
+        let (server, addr, server_sender, _) = start_server(None);
+        let client = new_client(addr, None, None);
+        let output = SharedBuf::default();
+        ExportHandler::add(
+            &client,
+            Exporter::new(output.clone(), ExportFormat::JsonLines).unwrap(),
+        )
+        .unwrap();
+        tokio::spawn(ConnectionIpStream::new(Arc::clone(&client)).drain());
+
+        let exported = || String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should export a pose report",
+            || !exported().is_empty(),
+        )
+        .await;
+        let exported = exported();
+        let first = exported.lines().next().unwrap();
+        assert!(
+            first.contains(
+                "\"sender\":\"Tracker0\",\"type\":\"vrpn_Tracker Pos_Quat\",\"sensor\":0"
+            ),
+            "unexpected export {}",
+            first
+        );
+    }
+
+    /// Make a loopback pair and start polling the server, which has a "Tracker0" sender.
+    fn start_loopback_server() -> (
+        Arc<ConnectionIp>,
+        Arc<ConnectionIp>,
+        LocalId<SenderId>,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let (server, client) = ConnectionIp::new_loopback_pair().unwrap();
+        assert_eq!(server.status(), ConnectionStatus::Server(0));
+        assert_eq!(client.status(), ConnectionStatus::ClientConnecting);
+        let sender = server
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap();
+        let task = tokio::spawn(ConnectionIpStream::new(Arc::clone(&server)).drain());
+        (server, client, sender, task)
+    }
+
+    #[tokio::test]
+    async fn loopback_tracker() {
+        let (server, client, server_sender, _) = start_loopback_server();
+        let (flag, _) = start_tracker_client(&client);
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::LOW_LATENCY,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+        assert_eq!(client.status(), ConnectionStatus::ClientConnected);
+        assert_eq!(server.status(), ConnectionStatus::Server(1));
+    }
+
+    #[tokio::test]
+    async fn loopback_tracker_manual() {
+        let (server, client, server_sender, _) = start_loopback_server();
+        let flag = Arc::new(AtomicBool::new(false));
+        let tracker_message_id = client
+            .register_type(StaticMessageTypeName(b"vrpn_Tracker Pos_Quat"))
+            .expect("should be able to register type");
+        let sender = client
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .expect("should be able to register sender");
+        client
+            .add_handler(
+                TrackerHandler::new(&flag),
+                Some(tracker_message_id),
+                Some(sender),
+            )
+            .unwrap();
+        tokio::spawn(ConnectionIpStream::new(Arc::clone(&client)).drain());
+
+        send_reports_until(
+            &server,
+            server_sender,
+            ClassOfService::RELIABLE,
+            "client should receive a pose report",
+            || flag.load(Ordering::SeqCst),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn loopback_shutdown() {
+        let (server, client, _, server_task) = start_loopback_server();
+        let (_, client_task) = start_tracker_client(&client);
+        wait_until("client should connect", || {
+            server.status() == ConnectionStatus::Server(1)
+        })
+        .await;
+
+        // The client hears the disconnect message and closes, as does the server.
+        server.shutdown().unwrap();
+        let all_closed = tokio::time::timeout(Duration::from_secs(5), async {
+            client_task.await.unwrap().unwrap();
+            server_task.await.unwrap().unwrap();
+        })
+        .await;
+        assert!(all_closed.is_ok(), "everything should close");
+        assert_eq!(client.status(), ConnectionStatus::Closed);
     }
 }
@@ -4,7 +4,10 @@
 
 use crate::{
     buffer_unbuffer::{BytesMutExtras, ConstantBufferSize, UnbufferFrom},
-    data_types::cookie::{check_ver_file_compatible, check_ver_nonfile_compatible, CookieData},
+    data_types::{
+        cookie::{check_ver_file_compatible, check_ver_nonfile_compatible, CookieData},
+        LogMode,
+    },
     VrpnError,
 };
 use bytes::{Bytes, BytesMut};
@@ -21,25 +24,29 @@ where
 }
 
 /// Reads a cookie's worth of data into a temporary buffer.
-///
-/// Future resolves to (stream, buffer) on success.
 async fn read_cookie<T>(stream: &mut T) -> Result<Vec<u8>, VrpnError>
 where
     T: tokio::io::AsyncRead + Unpin,
 {
-    let mut buf = Vec::with_capacity(CookieData::constant_buffer_size());
+    let mut buf = vec![0u8; CookieData::constant_buffer_size()];
     stream.read_exact(&mut buf).await?;
     Ok(buf)
 }
 
-/// Writes the "non-file" magic cookie to the stream.
+/// Writes the "non-file" magic cookie to the stream,
+/// including the logging we would like the peer to do.
 ///
 /// Future resolves to the provided stream on success.
-pub(crate) async fn send_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+pub(crate) async fn send_nonfile_cookie<T>(
+    stream: &mut T,
+    remote_log_mode: LogMode,
+) -> Result<(), VrpnError>
 where
     T: tokio::io::AsyncWrite + Unpin,
 {
-    write_cookie(stream, CookieData::make_cookie()).await
+    let mut cookie = CookieData::make_cookie();
+    cookie.log_mode = Some(remote_log_mode);
+    write_cookie(stream, cookie).await
 }
 
 /// Writes the "file" magic cookie to the stream.
@@ -53,7 +60,7 @@ where
 }
 
 /// Reads a cookie's worth of data from the stream, and cheacks to make sure it is the right version.
-pub(crate) async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<(), VrpnError>
+pub(crate) async fn read_and_check_nonfile_cookie<T>(stream: &mut T) -> Result<LogMode, VrpnError>
 where
     T: tokio::io::AsyncRead + Unpin,
 {
@@ -61,7 +68,7 @@ where
     let mut buf = Bytes::from(read_buf);
     let msg = CookieData::unbuffer_from(&mut buf)?;
     check_ver_nonfile_compatible(msg.version)?;
-    Ok(())
+    Ok(msg.log_mode.unwrap_or(LogMode::NONE))
 }
 
 /// Reads a cookie's worth of data from the stream, and cheacks to make sure it is the right version.
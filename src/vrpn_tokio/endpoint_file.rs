@@ -5,34 +5,216 @@
 use crate::vrpn_tokio::codec::*;
 use crate::vrpn_tokio::cookie::*;
 use crate::{
-    data_types::{message::GenericMessage, ClassOfService},
-    Endpoint, Result, SystemCommand, TranslationTables,
+    buffer_unbuffer::ConstantBufferSize,
+    data_types::{message::GenericMessage, ClassOfService, CookieData, Message, TimeVal},
+    endpoint::{handle_system_command, parse_system_message},
+    log_file::record_size,
+    Endpoint, EndpointGeneric, Result, SystemCommand, TranslationTables, TypeDispatcher,
 };
-use futures::channel::mpsc;
-use std::fs;
-use tokio::fs::File;
+use futures::{channel::mpsc, ready, StreamExt};
+use std::{
+    fs,
+    io::SeekFrom,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs::File, io::AsyncSeek};
 use tokio_util::codec::{Decoder, Framed};
 
+/// Where a user message was found in the file, and when it was recorded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct IndexEntry {
+    time: TimeVal,
+    offset: u64,
+}
+
+/// A seek on the file that has been requested but not completed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum PendingSeek {
+    Requested(u64),
+    Started(u64),
+}
+
+/// An endpoint reading the messages recorded in a log file.
+#[derive(Debug)]
 pub struct EndpointFile {
     translation: TranslationTables,
-    file: Framed<File, FramedMessageCodec>,
+    file: Framed<File, LogRecordCodec>,
+    /// The next user message, read ahead and mapped to local IDs, waiting to be played.
+    next: Option<GenericMessage>,
+    /// Offset in the file of the next message to be decoded.
+    position: u64,
+    /// User messages seen so far, in file order, so jumps back need not re-read the file.
+    index: Vec<IndexEntry>,
+    seek: Option<PendingSeek>,
+    /// Drop user messages recorded before this long after the first one.
+    skip: Option<Duration>,
     system_rx: mpsc::UnboundedReceiver<SystemCommand>,
     system_tx: mpsc::UnboundedSender<SystemCommand>,
+    /// Messages sent on the connection, which are delivered back to its own handlers.
+    local_rx: mpsc::UnboundedReceiver<GenericMessage>,
+    local_tx: mpsc::UnboundedSender<GenericMessage>,
+}
+
+/// Offset of the first message, just past the file cookie.
+fn first_message_offset() -> u64 {
+    CookieData::constant_buffer_size() as u64
 }
 
 impl EndpointFile {
     pub async fn new(file: fs::File) -> Result<EndpointFile> {
         let (system_tx, system_rx) = mpsc::unbounded();
+        let (local_tx, local_rx) = mpsc::unbounded();
         let mut file = File::from_std(file);
         read_and_check_file_cookie(&mut file).await?;
         Ok(EndpointFile {
             translation: TranslationTables::new(),
-            file: FramedMessageCodec.framed(file),
+            file: LogRecordCodec.framed(file),
+            next: None,
+            position: first_message_offset(),
+            index: Vec::new(),
+            seek: None,
+            skip: None,
             system_tx,
             system_rx,
+            local_tx,
+            local_rx,
         })
     }
+
+    /// The time the first user message in the file was recorded, once it has been read.
+    pub(crate) fn start_time(&self) -> Option<TimeVal> {
+        self.index.first().map(|entry| entry.time)
+    }
+
+    /// Go back to the first message in the file.
+    pub(crate) fn rewind(&mut self) {
+        self.skip = None;
+        self.seek_to(first_message_offset());
+    }
+
+    /// Go to the first user message recorded at least `elapsed` after the first one,
+    /// without playing anything in between.
+    ///
+    /// Uses the index where the file has already been read, and reads forward
+    /// (extending the index) otherwise. Assumes messages were recorded in time order.
+    pub(crate) fn jump_to(&mut self, elapsed: Duration) {
+        let start = match self.start_time() {
+            Some(start) => start,
+            None => {
+                // Nothing read yet: just skip ahead from here.
+                self.skip = Some(elapsed);
+                return;
+            }
+        };
+        let target = TimeVal::from(SystemTime::from(start) + elapsed);
+        let found = self.index.partition_point(|entry| entry.time < target);
+        match self.index.get(found) {
+            Some(entry) => {
+                self.skip = None;
+                self.seek_to(entry.offset);
+            }
+            None => {
+                // Past the end of the index: carry on from the last message we know of.
+                let last = self.index[found - 1].offset;
+                self.skip = Some(elapsed);
+                self.seek_to(last);
+            }
+        }
+    }
+
+    fn seek_to(&mut self, offset: u64) {
+        self.next = None;
+        self.seek = Some(PendingSeek::Requested(offset));
+    }
+
+    fn poll_seek(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            match self.seek {
+                None => return Poll::Ready(Ok(())),
+                Some(PendingSeek::Requested(offset)) => {
+                    Pin::new(self.file.get_mut()).start_seek(SeekFrom::Start(offset))?;
+                    self.seek = Some(PendingSeek::Started(offset));
+                }
+                Some(PendingSeek::Started(offset)) => {
+                    let _ = ready!(Pin::new(self.file.get_mut()).poll_complete(cx))?;
+                    // Anything already buffered came from the old position.
+                    self.file.read_buffer_mut().clear();
+                    self.position = offset;
+                    self.seek = None;
+                }
+            }
+        }
+    }
+
+    /// Whether a user message recorded at `time` falls before a pending jump target.
+    fn skipping(&mut self, time: TimeVal) -> bool {
+        match (self.skip, self.start_time()) {
+            (Some(skip), Some(start)) => {
+                if SystemTime::from(time) < SystemTime::from(start) + skip {
+                    return true;
+                }
+                self.skip = None;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Poll for the next user message in the file, without taking it.
+    ///
+    /// Description messages read on the way are applied to the translation tables
+    /// and dispatcher. Resolves to `None` at the end of the file.
+    pub(crate) fn poll_peek(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<&GenericMessage>>> {
+        while let Poll::Ready(Some(cmd)) = self.system_rx.poll_next_unpin(cx) {
+            let _ = handle_system_command(dispatcher, &mut self.translation, cmd)?;
+        }
+        ready!(self.poll_seek(cx))?;
+        while self.next.is_none() {
+            match ready!(self.file.poll_next_unpin(cx)) {
+                Some(Ok(msg)) => {
+                    let offset = self.position;
+                    self.position += record_size(&msg) as u64;
+                    if msg.is_system_message() {
+                        // Extended commands (UDP and log descriptions) mean nothing in playback.
+                        let _ = handle_system_command(
+                            dispatcher,
+                            &mut self.translation,
+                            parse_system_message(msg)?,
+                        )?;
+                    } else {
+                        let time = msg.header.time;
+                        if !matches!(self.index.last(), Some(last) if last.offset >= offset) {
+                            self.index.push(IndexEntry { time, offset });
+                        }
+                        if !self.skipping(time) {
+                            self.next = Some(self.map_remote_message_to_local(msg)?);
+                        }
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(None)),
+            }
+        }
+        Poll::Ready(Ok(self.next.as_ref()))
+    }
+
+    /// Take the message last returned by `poll_peek`.
+    pub(crate) fn take_next(&mut self) -> Option<GenericMessage> {
+        self.next.take()
+    }
+
+    /// Poll for a message sent on the connection, to be dispatched locally.
+    pub(crate) fn poll_local(&mut self, cx: &mut Context<'_>) -> Poll<Option<GenericMessage>> {
+        self.local_rx.poll_next_unpin(cx)
+    }
 }
+
 impl Endpoint for EndpointFile {
     fn translation_tables(&self) -> &TranslationTables {
         &self.translation
@@ -41,15 +223,26 @@ impl Endpoint for EndpointFile {
         &mut self.translation
     }
 
-    fn send_system_change(&self, _message: SystemCommand) -> Result<()> {
-        unimplemented!()
+    fn send_system_change(&self, message: SystemCommand) -> Result<()> {
+        self.system_tx
+            .unbounded_send(message)
+            .map_err(crate::error::to_other_error)?;
+        Ok(())
     }
 
+    /// There is no peer to send to, so user messages sent on a file connection
+    /// (such as file controller messages) are delivered back to its own handlers,
+    /// and anything else is dropped.
     fn buffer_generic_message(
         &mut self,
-        _msg: GenericMessage,
+        msg: GenericMessage,
         _class: ClassOfService,
     ) -> Result<()> {
-        unimplemented!()
+        if !msg.is_system_message() {
+            self.local_tx
+                .unbounded_send(msg)
+                .map_err(crate::error::to_other_error)?;
+        }
+        Ok(())
     }
 }
@@ -3,138 +3,373 @@
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
 use crate::{
-    endpoint::*,
-    vrpn_tokio::{
-        codec::{self, FramedMessageCodec},
-        endpoint_channel::{poll_and_dispatch, EndpointChannel},
+    constants::UDP_BUFLEN,
+    data_types::{
+        ClassOfService, Disconnect, GenericMessage, LogFileNames, LogMode, TypedMessage,
+        UdpDescription,
     },
-    Result, TranslationTables, TypeDispatcher,
+    endpoint::*,
+    log_file::EndpointLog,
+    protocol::ProtocolEndpoint,
+    Result, TranslationTables, TypeDispatcher, VrpnError,
 };
-use futures::channel::mpsc;
-use std::task::Poll;
+use bytes::Bytes;
+use futures::ready;
 use std::{
-    ops::DerefMut,
-    sync::{Arc, Mutex},
+    convert::TryFrom,
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll, Waker},
 };
-use tokio::net::TcpStream;
-use tokio_util::udp::UdpFramed;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UdpSocket},
+};
+
+/// The reliable channel to the peer: a TCP stream, or an in-memory one for a loopback connection.
+pub(crate) trait ReliableStream: AsyncRead + AsyncWrite + Debug + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Debug + Send + Unpin> ReliableStream for T {}
+
+/// The outgoing low-latency channel: datagrams sent to the peer.
+#[derive(Debug)]
+struct UdpTx {
+    /// Connected to the peer.
+    socket: UdpSocket,
+    peer: SocketAddr,
+    /// A datagram the socket wasn't ready for yet.
+    pending: Option<Bytes>,
+}
 
-pub type MessageFramed = codec::MessageFramed<TcpStream>;
-pub type MessageFramedUdp = UdpFramed<FramedMessageCodec>;
+impl UdpTx {
+    /// Send the datagrams the protocol has packed.
+    ///
+    /// Datagrams are best-effort: one that fails to send is dropped.
+    fn poll_send(&mut self, protocol: &mut ProtocolEndpoint, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let datagram = match self.pending.take().or_else(|| protocol.take_datagram()) {
+                Some(datagram) => datagram,
+                None => return Poll::Ready(()),
+            };
+            match self.socket.poll_send(cx, &datagram) {
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => eprintln!("Dropping datagram to {}: {}", self.peer, e),
+                Poll::Pending => {
+                    self.pending = Some(datagram);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
 
+/// An endpoint over a TCP stream whose handshake is done,
+/// with an optional UDP socket for low-latency messages,
+/// or over any other reliable stream, without one.
+///
+/// The protocol itself is left to `ProtocolEndpoint`:
+/// this just moves bytes between it and the sockets.
 #[derive(Debug)]
 pub struct EndpointIp {
-    translation: TranslationTables,
-    reliable_channel: Arc<Mutex<EndpointChannel<MessageFramed>>>,
-    low_latency_channel: Option<MessageFramedUdp>,
-    system_rx: mpsc::UnboundedReceiver<SystemCommand>,
-    system_tx: mpsc::UnboundedSender<SystemCommand>,
+    protocol: ProtocolEndpoint,
+    stream: Box<dyn ReliableStream>,
+    /// Where the peer should send us datagrams, if we have an inbound UDP socket.
+    udp_description: Option<UdpDescription>,
+    low_latency_tx: Option<UdpTx>,
+    low_latency_rx: Option<UdpSocket>,
+    /// Records of the messages we receive and send, if requested.
+    log: EndpointLog,
+    /// The logging the peer asked us to do on its behalf, named in its log description.
+    peer_log_request: LogMode,
+    /// Where we keep logs for the peer, if we allow it to ask for them at all.
+    peer_log_dir: Option<PathBuf>,
+    /// Records of the messages we receive and send, kept for the peer.
+    peer_log: EndpointLog,
+    /// Wakes the task that last polled us, when something new is queued to send.
+    waker: Option<Waker>,
 }
+
 impl EndpointIp {
-    pub(crate) fn new(
-        reliable_stream: TcpStream,
-        low_latency_channel: Option<MessageFramedUdp>,
-    ) -> EndpointIp {
-        let framed = codec::apply_message_framing(reliable_stream);
-        let (system_tx, system_rx) = mpsc::unbounded();
+    pub(crate) fn new(reliable_stream: TcpStream, udp: Option<UdpSocket>) -> EndpointIp {
+        // Advertise the address the peer reaches us at over TCP, with our inbound UDP port.
+        let udp_description = match (&udp, reliable_stream.local_addr()) {
+            (Some(udp), Ok(tcp_addr)) => udp.local_addr().ok().map(|udp_addr| {
+                UdpDescription::new(SocketAddr::new(tcp_addr.ip(), udp_addr.port()))
+            }),
+            _ => None,
+        };
+        let mut endpoint = EndpointIp::from_stream(reliable_stream);
+        endpoint.udp_description = udp_description;
+        endpoint.low_latency_rx = udp;
+        endpoint
+    }
+
+    /// Wrap a reliable stream whose handshake is done, with no low-latency channel.
+    pub(crate) fn from_stream(stream: impl ReliableStream + 'static) -> EndpointIp {
+        // The cookies were exchanged while connecting:
+        // the logging the peer asked for in its cookie is noted by `set_peer_log_request`.
+        let mut protocol = ProtocolEndpoint::established(LogMode::NONE);
+        protocol.record_incoming();
         EndpointIp {
-            translation: TranslationTables::new(),
-            reliable_channel: EndpointChannel::new(framed),
-            low_latency_channel,
-            system_tx,
-            system_rx,
+            protocol,
+            stream: Box::new(stream),
+            udp_description: None,
+            low_latency_tx: None,
+            low_latency_rx: None,
+            log: EndpointLog::default(),
+            peer_log_request: LogMode::NONE,
+            peer_log_dir: None,
+            peer_log: EndpointLog::default(),
+            waker: None,
         }
     }
 
-    pub(crate) fn poll_endpoint(
-        &mut self,
-        mut dispatcher: &mut TypeDispatcher,
-    ) -> Poll<Result<()>> {
-        let channel_arc = Arc::clone(&self.reliable_channel);
-        let mut channel = channel_arc
-            .lock()
-            .map_err(|e| Error::OtherMessage(e.to_string()))?;
-        let _ = channel.poll_complete()?;
-        let mut closed = poll_and_dispatch(self, channel.deref_mut(), dispatcher)?.is_ready();
+    /// Start recording messages to the given logs.
+    ///
+    /// Set this before sending anything, so the logs include our descriptions.
+    pub(crate) fn set_log(&mut self, log: EndpointLog) {
+        self.log = log;
+    }
 
-        // todo UDP here.
+    /// Note the logging the peer asked for in its handshake cookie,
+    /// and the directory we keep such logs in, if we allow them.
+    ///
+    /// The logs are opened once the peer sends a log description naming the files.
+    pub(crate) fn set_peer_log_request(&mut self, mode: LogMode, dir: Option<PathBuf>) {
+        self.peer_log_request = mode;
+        self.peer_log_dir = dir;
+    }
+
+    /// Send the peer the names of the logs we asked it to keep in our handshake cookie, if any.
+    pub(crate) fn send_log_description(&mut self, names: &LogFileNames) -> Result<()> {
+        if !names.log_mode().is_empty() {
+            self.buffer_message(TypedMessage::from(names.clone()), ClassOfService::RELIABLE)?;
+        }
+        Ok(())
+    }
 
-        // Now, process the messages we sent ourself.
+    /// Open the logs the peer asked for, which start with everything described so far.
+    ///
+    /// A request we refuse is reported, but leaves the connection open.
+    fn open_peer_log(&mut self, names: &LogFileNames, dispatcher: &TypeDispatcher) -> Result<()> {
+        let dir = self.peer_log_dir.as_deref();
+        let mut log = match EndpointLog::open_requested(names, self.peer_log_request, dir) {
+            Ok(log) => log,
+            Err(e) => {
+                eprintln!(
+                    "Not keeping the logs {:?} requested by the peer: {}",
+                    names, e
+                );
+                return Ok(());
+            }
+        };
+        log.log_descriptions(dispatcher, self.protocol.translation_tables())?;
+        self.peer_log = log;
+        Ok(())
+    }
+
+    /// Tell the peer where to send us low-latency messages, if we have an inbound UDP socket.
+    ///
+    /// Clients send this right after the handshake.
+    pub(crate) fn send_udp_description(&mut self) -> Result<()> {
+        if let Some(desc) = self.udp_description.clone() {
+            self.buffer_message(TypedMessage::from(desc), ClassOfService::RELIABLE)?;
+        }
+        Ok(())
+    }
+
+    /// Start sending low-latency messages as datagrams to `peer`,
+    /// from a new UDP socket connected to it.
+    pub(crate) fn set_udp_destination(&mut self, peer: SocketAddr) -> Result<()> {
+        let any: IpAddr = if peer.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(any, 0))?;
+        socket.connect(peer)?;
+        socket.set_nonblocking(true)?;
+        self.low_latency_tx = Some(UdpTx {
+            socket: UdpSocket::from_std(socket)?,
+            peer,
+            pending: None,
+        });
+        self.protocol.enable_datagrams();
+        Ok(())
+    }
+
+    fn handle_extended_command(
+        &mut self,
+        cmd: ExtendedSystemCommand,
+        dispatcher: &TypeDispatcher,
+    ) -> Result<()> {
+        match cmd {
+            ExtendedSystemCommand::UdpDescription(desc) => {
+                self.set_udp_destination(desc.socket_address)?;
+            }
+            ExtendedSystemCommand::LogDescription(names) => {
+                self.open_peer_log(&names, dispatcher)?;
+            }
+            // The protocol handles this one itself.
+            ExtendedSystemCommand::DisconnectMessage => {}
+        }
+        Ok(())
+    }
+
+    /// Hand the protocol whatever the peer has sent over TCP so far.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        let mut buf = [0u8; 4096];
         loop {
-            let cmd_poll = self.system_rx.poll().map_err(|()| {
-                Error::OtherMessage(String::from(
-                    "error when polling system change message channel",
-                ))
-            })?;
-            match cmd_poll {
-                Poll::Ready(None) => {
-                    closed = true;
-                    break;
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                    self.protocol.handle_eof();
+                    return Ok(());
                 }
-                Poll::Ready(Some(cmd)) => {
-                    if let Some(cmd) = self.handle_system_command(&mut dispatcher, cmd)? {
-                        match cmd {
-                            ExtendedSystemCommand::UdpDescription(desc) => {
-                                eprintln!("UdpDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::LogDescription(desc) => {
-                                eprintln!("LogDescription: {:?}", desc);
-                            }
-                            ExtendedSystemCommand::DisconnectMessage => {
-                                eprintln!("DisconnectMessage");
-                            }
+                Poll::Ready(Ok(())) => self.protocol.handle_bytes(read_buf.filled()),
+                Poll::Ready(Err(e)) => return Err(e.into()),
+                Poll::Pending => return Ok(()),
+            }
+        }
+    }
+
+    /// Hand the protocol whatever datagrams the peer has sent us.
+    ///
+    /// Datagrams are best-effort: one that fails is dropped,
+    /// and losing the low-latency channel doesn't close us.
+    fn poll_read_datagrams(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        extended: &mut Vec<ExtendedSystemCommand>,
+        cx: &mut Context<'_>,
+    ) {
+        if let Some(socket) = self.low_latency_rx.take() {
+            let mut buf = [0u8; UDP_BUFLEN];
+            loop {
+                let mut read_buf = ReadBuf::new(&mut buf);
+                match socket.poll_recv_from(cx, &mut read_buf) {
+                    Poll::Ready(Ok(addr)) => {
+                        if let Err(e) = self.protocol.handle_datagram(
+                            read_buf.filled(),
+                            dispatcher,
+                            received,
+                            extended,
+                        ) {
+                            eprintln!("Dropping low-latency messages from {}: {}", addr, e);
                         }
                     }
+                    Poll::Ready(Err(e)) => {
+                        eprintln!("Low-latency channel failed: {}", e);
+                        break;
+                    }
+                    Poll::Pending => {
+                        self.low_latency_rx = Some(socket);
+                        break;
+                    }
                 }
-                Poll::NotReady => break,
             }
         }
+    }
+
+    /// Write as much of what the protocol has queued as the stream will take, and flush it.
+    ///
+    /// Ready once everything queued has been flushed.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if let Some(tx) = &mut self.low_latency_tx {
+            let _ = tx.poll_send(&mut self.protocol, cx);
+        }
+        while !self.protocol.output().is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, self.protocol.output()))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+            }
+            self.protocol.consume_output(n);
+        }
+        Pin::new(&mut self.stream)
+            .poll_flush(cx)
+            .map_err(VrpnError::from)
+    }
 
-        if closed {
-            Ok(Poll::Ready(()))
-        } else {
-            Ok(Poll::NotReady)
+    /// Poll the endpoint, handling system messages and appending other received messages
+    /// to `received` for the caller to dispatch.
+    ///
+    /// Ready when the endpoint has closed, or finished closing after a disconnect.
+    pub(crate) fn poll_endpoint(
+        &mut self,
+        dispatcher: &mut TypeDispatcher,
+        received: &mut Vec<GenericMessage>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        self.waker = Some(cx.waker().clone());
+        let mut extended = Vec::new();
+        self.poll_read(cx)?;
+        self.protocol.process(dispatcher, received, &mut extended)?;
+        self.poll_read_datagrams(dispatcher, received, &mut extended, cx);
+        for msg in self.protocol.take_recorded() {
+            self.log.log_incoming(&msg)?;
+            self.peer_log.log_incoming(&msg)?;
+        }
+        for cmd in extended {
+            self.handle_extended_command(cmd, dispatcher)?;
+        }
+
+        // Flush anything queued, including replies to what we just handled.
+        let sent = self.poll_write(cx)?;
+        self.log.flush()?;
+        self.peer_log.flush()?;
+
+        if self.protocol.is_closed() {
+            if sent.is_ready() {
+                let _ = ready!(Pin::new(&mut self.stream).poll_shutdown(cx));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    /// Wake the task polling us, to send what was just queued.
+    fn wake(&self) {
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
         }
     }
 }
 
 impl Endpoint for EndpointIp {
     fn translation_tables(&self) -> &TranslationTables {
-        &self.translation
+        self.protocol.translation_tables()
     }
 
     fn translation_tables_mut(&mut self) -> &mut TranslationTables {
-        &mut self.translation
+        self.protocol.translation_tables_mut()
     }
 
     fn send_system_change(&self, message: SystemCommand) -> Result<()> {
-        println!("send_system_change {:?}", message);
-        self.system_tx
-            .unbounded_send(message)
-            .map_err(|e| Error::OtherMessage(e.to_string()))?;
+        self.protocol.send_system_change(message)?;
+        self.wake();
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        // The protocol queues the disconnect message itself, bypassing our logging.
+        let msg = GenericMessage::try_from(TypedMessage::from(Disconnect))?;
+        self.log.log_outgoing(&msg)?;
+        self.peer_log.log_outgoing(&msg)?;
+        self.protocol.disconnect()?;
+        self.wake();
         Ok(())
     }
 
     fn buffer_generic_message(&mut self, msg: GenericMessage, class: ClassOfService) -> Result<()> {
-        if class.contains(ClassOfService::RELIABLE) || self.low_latency_channel.is_none() {
-            // We either need reliable, or don't have low-latency
-            let mut channel = self
-                .reliable_channel
-                .lock()
-                .map_err(|e| Error::OtherMessage(e.to_string()))?;
-            match channel
-                .start_send(msg)
-                .map_err(|e| Error::OtherMessage(e.to_string()))?
-            {
-                Poll::Ready(_) => Ok(()),
-                Poll::NotReady(_) => Err(Error::OtherMessage(String::from(
-                    "Didn't have room in send buffer",
-                ))),
-            }
-        } else {
-            // have and can use low-latency
-            unimplemented!()
-        }
+        self.log.log_outgoing(&msg)?;
+        self.peer_log.log_outgoing(&msg)?;
+        self.protocol.buffer_generic_message(msg, class)?;
+        self.wake();
+        Ok(())
     }
 }
 
@@ -142,47 +377,198 @@ impl Endpoint for EndpointIp {
 mod tests {
     use super::*;
     use crate::{
-        vrpn_tokio::connect::{Connect, ConnectResults},
+        codec::DatagramPacker,
+        data_types::{
+            id_types::LocalId, GenericBody, MessageHeader, StaticMessageTypeName, StaticSenderName,
+        },
+        vrpn_tokio::connect::connect,
         ServerInfo,
     };
+    use std::time::Duration;
 
-    #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn make_endpoint() {
-        let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>().unwrap();
-        let connector = Connect::new(server).expect("should be able to create connection future");
-
-        let _ = connector
-            .and_then(|ConnectResults { tcp, udp: _ }| {
-                let ep = EndpointIp::new(tcp.unwrap(), None);
-                for _i in 0..4 {
-                    let _ = ep.reliable_channel.lock()?.poll()?.map(|msg| {
-                        eprintln!("Received message {:?}", msg);
-                        msg
-                    });
-                }
-                Ok(())
+    #[tokio::test]
+    async fn receive_datagram() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _accepted = listener.accept().await.unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let mut ep = EndpointIp::new(tcp, Some(udp));
+
+        // The far side: its descriptions, then a message using them, all in one datagram.
+        let mut remote = TypeDispatcher::new();
+        let sender = remote
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap()
+            .into_inner();
+        let message_type = remote
+            .register_type(StaticMessageTypeName(b"Test Type"))
+            .unwrap()
+            .into_inner();
+        let mut packer = DatagramPacker::new();
+        for msg in remote.pack_all_descriptions().unwrap() {
+            packer.push(msg).unwrap();
+        }
+        packer
+            .push(GenericMessage {
+                header: MessageHeader::new(None, message_type, sender),
+                body: GenericBody::new(Bytes::from_static(b"12345678")),
             })
-            .wait()
             .unwrap();
+        packer.finish();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(&packer.pop().unwrap(), udp_addr)
+            .await
+            .unwrap();
+
+        let mut disp = TypeDispatcher::new();
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::poll_fn(|cx| {
+                let _ = ep.poll_endpoint(&mut disp, &mut received, cx);
+                if received.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            }),
+        )
+        .await
+        .expect("should receive the message");
+
+        assert_eq!(received.len(), 1);
+        let LocalId(local_type) = disp
+            .get_type_id(StaticMessageTypeName(b"Test Type"))
+            .unwrap();
+        let LocalId(local_sender) = disp.get_sender_id(StaticSenderName(b"Tracker0")).unwrap();
+        assert_eq!(received[0].header.message_type, local_type);
+        assert_eq!(received[0].header.sender, local_sender);
+        assert_eq!(&received[0].body.clone().into_inner()[..], &b"12345678"[..]);
+    }
+
+    #[tokio::test]
+    async fn udp_description_opens_low_latency_channel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let udp = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let mut client = EndpointIp::new(tcp, Some(udp));
+        let mut server = EndpointIp::new(accepted, None);
+
+        let mut server_disp = TypeDispatcher::new();
+        let sender = server_disp
+            .register_sender(StaticSenderName(b"Tracker0"))
+            .unwrap()
+            .into_inner();
+        let message_type = server_disp
+            .register_type(StaticMessageTypeName(b"Test Type"))
+            .unwrap()
+            .into_inner();
+        server.send_all_descriptions(&server_disp).unwrap();
+        client.send_udp_description().unwrap();
+
+        let mut client_disp = TypeDispatcher::new();
+        let mut client_received = Vec::new();
+        let mut server_received = Vec::new();
+        let mut poll_both =
+            |cx: &mut Context<'_>, client: &mut EndpointIp, server: &mut EndpointIp| {
+                let _ = client.poll_endpoint(&mut client_disp, &mut client_received, cx);
+                let _ = server.poll_endpoint(&mut server_disp, &mut server_received, cx);
+                (
+                    client_disp
+                        .get_type_id(StaticMessageTypeName(b"Test Type"))
+                        .is_some(),
+                    client_received.len(),
+                )
+            };
+
+        // Wait for the server to learn our UDP address, and us to learn its descriptions.
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::poll_fn(|cx| {
+                let (has_descriptions, _) = poll_both(cx, &mut client, &mut server);
+                if has_descriptions && server.low_latency_tx.is_some() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }),
+        )
+        .await
+        .expect("should exchange descriptions");
+
+        server
+            .buffer_generic_message(
+                GenericMessage {
+                    header: MessageHeader::new(None, message_type, sender),
+                    body: GenericBody::new(Bytes::from_static(b"12345678")),
+                },
+                ClassOfService::LOW_LATENCY,
+            )
+            .unwrap();
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            futures::future::poll_fn(|cx| {
+                let (_, received) = poll_both(cx, &mut client, &mut server);
+                if received == 0 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            }),
+        )
+        .await
+        .expect("should receive the message over UDP");
+        assert_eq!(
+            &client_received[0].body.clone().into_inner()[..],
+            &b"12345678"[..]
+        );
     }
 
     #[ignore] // because it requires an external server to be running.
-    #[test]
-    fn run_endpoint() {
+    #[tokio::test]
+    async fn make_endpoint() {
         let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>().unwrap();
-        let connector = Connect::new(server).expect("should be able to create connection future");
-
-        let _ = connector
-            .and_then(|ConnectResults { tcp, udp: _ }| {
-                let mut ep = EndpointIp::new(tcp.unwrap(), None);
-                let mut disp = TypeDispatcher::new();
-                for _i in 0..4 {
-                    let _ = ep.poll_endpoint(&mut disp).unwrap();
+        let results = connect(server, LogMode::NONE)
+            .await
+            .expect("should be able to connect");
+        let mut ep = EndpointIp::new(results.tcp, None);
+        let mut disp = TypeDispatcher::new();
+        let mut received = Vec::new();
+        while received.len() < 4 {
+            let num_received = received.len();
+            futures::future::poll_fn(|cx| {
+                let _ = ep.poll_endpoint(&mut disp, &mut received, cx);
+                if received.len() > num_received {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
                 }
-                Ok(())
             })
-            .wait()
-            .unwrap();
+            .await;
+        }
+        eprintln!("Received messages {:?}", received);
+    }
+
+    #[ignore] // because it requires an external server to be running.
+    #[tokio::test]
+    async fn run_endpoint() {
+        let server = "tcp://127.0.0.1:3883".parse::<ServerInfo>().unwrap();
+        let results = connect(server, LogMode::NONE)
+            .await
+            .expect("should be able to connect");
+        let mut ep = EndpointIp::new(results.tcp, None);
+        let mut disp = TypeDispatcher::new();
+        let mut received = Vec::new();
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        for _i in 0..4 {
+            let _ = ep.poll_endpoint(&mut disp, &mut received, &mut cx);
+        }
     }
 }
@@ -7,10 +7,14 @@ pub mod connect;
 pub mod connection_file;
 pub mod connection_ip;
 pub mod cookie;
-pub mod endpoint_channel;
 pub mod endpoint_file;
 pub mod endpoint_ip;
 pub mod ping;
-// pub mod util;
+pub mod util;
 
-pub use self::codec::apply_message_framing;
+pub use self::{
+    codec::apply_message_framing,
+    connection_file::{ConnectionFile, ConnectionFileStream, PlaybackMode},
+    connection_ip::{ConnectionIp, ConnectionIpStream},
+    util::{Drain, StreamExtras},
+};
@@ -5,7 +5,7 @@
 use crate::{
     data_types::{
         id_types::{LocalId, SenderId},
-        name_types::SenderName,
+        name_types::{NameIntoBytes, SenderName},
     },
     ping::Client as RawClient,
     Connection, Result,
@@ -32,7 +32,7 @@ impl<T: Connection + 'static> Client<T> {
     }
 
     pub fn new_from_name(
-        sender: impl Into<SenderName> + Clone,
+        sender: impl Into<SenderName> + NameIntoBytes + Clone,
         connection: Arc<T>,
     ) -> Result<Client<T>> {
         Client::new_impl(RawClient::new_from_name(sender, connection)?)
@@ -2,110 +2,102 @@
 // SPDX-License-Identifier: BSL-1.0
 // Author: Ryan A. Pavlik <ryan.pavlik@collabora.com>
 
-use std::task::Poll;
+use futures::{ready, Future, TryStream, TryStreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use futures::{Future, Stream};
-use tokio::prelude::*;
-
-/// Pull as many items from the stream as possible until an error, end of stream, or NotReady.
-pub fn drain_stream<T: Stream>(stream: &mut T) -> Poll<(), T::Error> {
-    drain_poll_fn(|| stream.poll())
+/// Pull as many items from the stream as possible until an error, end of stream, or Pending.
+pub fn drain_stream<T: TryStream + Unpin>(
+    stream: &mut T,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), T::Error>> {
+    drain_poll_fn(|cx| stream.try_poll_next_unpin(cx), cx)
 }
 
-/// Pull as many items from the poll function as possible until an error, end of stream, or NotReady.
-pub fn drain_poll_fn<F, T, E>(mut func: F) -> Poll<(), E>
+/// Pull as many items from the poll function as possible until an error, end of stream, or Pending.
+pub fn drain_poll_fn<F, T, E>(mut func: F, cx: &mut Context<'_>) -> Poll<Result<(), E>>
 where
-    F: FnMut() -> Poll<Option<T>, E>,
+    F: FnMut(&mut Context<'_>) -> Poll<Option<Result<T, E>>>,
 {
     loop {
-        match ready!(func()) {
-            Some(_) => {}
+        match ready!(func(cx)) {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                return Poll::Ready(Err(e));
+            }
             None => {
-                return Ok(Poll::Ready(()));
+                return Poll::Ready(Ok(()));
             }
         }
     }
 }
 
-pub trait StreamExtras: Stream + Sized {
-    //fn drain(self) -> Drain<Self>;
+pub trait StreamExtras: TryStream + Sized {
+    /// Turn this stream into a future that discards items, resolving at the end of the stream
+    /// or at the first error.
     fn drain(self) -> Drain<Self> {
         Drain::new(self)
     }
 }
-impl<S> StreamExtras for S where S: Stream + Sized {}
+impl<S> StreamExtras for S where S: TryStream + Sized {}
 
 #[derive(Debug)]
 pub struct Drain<S>
 where
-    S: Stream + Sized,
+    S: TryStream + Sized,
 {
-    inner: Option<S>,
+    inner: S,
 }
 
 impl<S> Drain<S>
 where
-    S: Stream + Sized,
+    S: TryStream + Sized,
 {
     pub fn new(stream: S) -> Drain<S> {
-        Drain {
-            inner: Some(stream),
-        }
+        Drain { inner: stream }
     }
 }
+
 impl<S> Future for Drain<S>
 where
-    S: Stream + Sized,
+    S: TryStream + Sized + Unpin,
 {
-    fn poll(&mut self) -> Poll<Result<(), S::Error>> {
-        let inner = self.inner.take();
-        if let Some(mut stream) = inner {
-            loop {
-                match stream.poll()? {
-                    Async::Ready(Some(_)) => {}
-                    Async::Ready(None) => {
-                        return Ok(Async::Ready(()));
-                    }
-                    Async::NotReady => {
-                        break;
-                    }
-                }
-            }
-            self.inner = Some(stream);
-        }
-        Ok(Async::NotReady)
-    }
-
     type Output = Result<(), S::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        drain_stream(&mut self.inner, cx)
+    }
 }
 
-/// Evaluates the expression (`returning Poll<Option<_>, _>`) in a loop,
-/// discarding values, until either `Ok(Async::Ready(None))` is returned
-/// (indicating end of stream, and making the whole expression evalute to `Async::Ready(())`),
-/// `Ok(Async::NotReady)` is returned (making the whole expression evalute to `Async::NotReady`),
+/// Evaluates the expression (returning `Poll<Option<Result<_, _>>>`) in a loop,
+/// discarding values, until either `Poll::Ready(None)` is returned
+/// (indicating end of stream, and making the whole expression evalute to `Poll::Ready(())`),
+/// `Poll::Pending` is returned (making the whole expression evalute to `Poll::Pending`),
 /// or an error is returned (causing a return statement to be executed).
 #[macro_export]
 macro_rules! try_drain {
     ($e: expr) => {
         loop {
             match $e {
-                Ok(Async::Ready(Some(_))) => {}
-                Ok(Async::Ready(None)) => {
-                    break Async::Ready(());
+                std::task::Poll::Ready(Some(Ok(_))) => {}
+                std::task::Poll::Ready(None) => {
+                    break std::task::Poll::Ready(());
                 }
-                Ok(Async::NotReady) => {
-                    break Async::NotReady;
+                std::task::Poll::Pending => {
+                    break std::task::Poll::Pending;
                 }
-                Err(e) => return Err(From::from(e)),
+                std::task::Poll::Ready(Some(Err(e))) => return Err(From::from(e)),
             }
         }
     };
 }
 
-/// Evaluates the expression (returning `Poll<Option<_>, _>`) in a loop,
-/// discarding values, until either `Ok(Async::Ready(None))` is returned
-/// (indicating end of stream, and returning `Ok(Async::Ready(Default::default()))`),
-/// `Ok(Async::NotReady)` is returned (making the whole expression evalute to `Async::NotReady`),
+/// Evaluates the expression (returning `Poll<Option<Result<_, _>>>`) in a loop,
+/// discarding values, until either `Poll::Ready(None)` is returned
+/// (indicating end of stream, and returning `Ok(Poll::Ready(Default::default()))`),
+/// `Poll::Pending` is returned (making the whole expression evalute to `Poll::Pending`),
 /// or an error is returned (causing a return statement to be executed).
 ///
 /// This is useful for things
@@ -114,15 +106,33 @@ macro_rules! try_drain_return_on_ready {
     ($e: expr) => {
         loop {
             match $e {
-                Ok(Async::Ready(Some(_))) => {}
-                Ok(Async::Ready(None)) => {
-                    return Ok(Async::Ready(Default::default()));
+                std::task::Poll::Ready(Some(Ok(_))) => {}
+                std::task::Poll::Ready(None) => {
+                    return Ok(std::task::Poll::Ready(Default::default()));
                 }
-                Ok(Async::NotReady) => {
-                    break Async::NotReady;
+                std::task::Poll::Pending => {
+                    break std::task::Poll::Pending;
                 }
-                Err(e) => return Err(From::from(e)),
+                std::task::Poll::Ready(Some(Err(e))) => return Err(From::from(e)),
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn drain_to_end() {
+        let s = stream::iter(vec![Ok::<i32, ()>(1), Ok(2), Ok(3)]);
+        assert_eq!(futures::executor::block_on(s.drain()), Ok(()));
+    }
+
+    #[test]
+    fn drain_stops_at_error() {
+        let s = stream::iter(vec![Ok(1), Err("oops"), Ok(3)]);
+        assert_eq!(futures::executor::block_on(s.drain()), Err("oops"));
+    }
+}